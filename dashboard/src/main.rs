@@ -6,13 +6,14 @@ extern crate time;
 #[macro_use]
 extern crate url;
 extern crate mqtt;
+extern crate openssl;
 
 mod printer_mgmt;
 mod ui;
 mod msgif;
 
 use printer_mgmt::core;
-use printer_mgmt::{Printer, Core};
+use printer_mgmt::{Printer, ApiToken, Core};
 use std::fs::File;
 use std::path::Path;
 use std::io::{BufReader, BufRead};
@@ -49,11 +50,17 @@ fn load_configured_printers(printers : Arc<Mutex<HashMap<usize, Printer>>>) {
         match conf.read_line(&mut conf_line) {
             Ok(0) => return,
             Ok(_) => {
-                let (fab, addr) = conf_line.split_at( conf_line.find("\t").expect("Invalid config file: Line without TAB!") );
+                //fab id, address and an optional API token, separated by TABs
+                let mut columns = conf_line.trim().split('\t');
+                let fab = columns.next().unwrap();
+                let addr = columns.next().expect("Invalid config file: Line without TAB!");
                 let printerid = get_new_printer_id();
-                printers.insert( printerid,
-                    Printer::new( fab.parse().expect("Invalid config file: Non-numeric fab id!"),
-                        printerid, addr.trim().to_string() ) );
+                let mut printer = Printer::new( fab.parse().expect("Invalid config file: Non-numeric fab id!"),
+                    printerid, addr.trim().to_string() );
+                if let Some(token) = columns.next() {
+                    printer.token = Some(ApiToken(token.trim().to_string()));
+                }
+                printers.insert( printerid, printer );
             }
             Err(e) => panic!("Error while reading configured printers: {}", e)
         }
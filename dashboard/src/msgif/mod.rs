@@ -1,8 +1,31 @@
 use std::sync::{Mutex, Arc};
 use std::collections::HashMap;
 use std::str::from_utf8;
+use std::ops::DerefMut;
 use printer_mgmt::{Printer, printbp};
+use printer_mgmt::metrics;
 use mqtt::async::{PersistenceType, Qos, AsyncClient, AsyncConnectOptions};
+use rustc_serialize::json;
+use super::get_new_printer_id;
+
+const PRESENCE_TOPIC : &'static str = "fab/+/printer/+/presence";
+const EVENTS_TOPIC : &'static str = "fab/+/printer/+/events";
+
+//Only the name of a panel event is needed, to count finished jobs
+#[derive(RustcDecodable)]
+struct PanelEvent {
+    event: String
+}
+
+//Retained announcement of a panel, sent with online: false as its last will
+#[derive(RustcDecodable, Debug)]
+struct Presence {
+    printer_id: String,
+    fab_id: usize,
+    online: bool,
+    address: String,
+    capabilities: Vec<String>
+}
 
 fn queue_job(printers: Arc<Mutex<HashMap<usize, Printer>>>,
     job_queue : Arc<Mutex<Vec<(usize, String, String)>>>,
@@ -17,6 +40,39 @@ fn queue_job(printers: Arc<Mutex<HashMap<usize, Printer>>>,
     return printbp( printers, job_queue, fab, v[1].to_string(), &v[2].to_string() );
 }
 
+//Adds announced panels, keeps their address up to date and marks them offline
+fn update_presence(printers: Arc<Mutex<HashMap<usize, Printer>>>, presence : Presence) {
+    let mut printers_lock = printers.lock().unwrap();
+    let mut printers = printers_lock.deref_mut();
+
+    //Panels are known by fab and printer id, their address only claims a configured printer without one,
+    //many panels announce the default address
+    let known = printers.values()
+        .find(|printer| printer.fabid == presence.fab_id && printer.name.as_ref() == Some(&presence.printer_id))
+        .or_else(|| printers.values().find(|printer| printer.name.is_none() && printer.address == presence.address))
+        .map(|printer| printer.id);
+    let id = match known {
+        Some(id) => id,
+        None if presence.online => {
+            let id = get_new_printer_id();
+            println!("Printer '{}' in fab {} announced itself at {}", presence.printer_id, presence.fab_id, presence.address);
+            printers.insert( id, Printer::new( presence.fab_id, id, presence.address.clone() ) );
+            id
+        },
+        None => return //Never seen online, nothing to mark
+    };
+
+    let printer = printers.get_mut(&id).unwrap();
+    if !presence.online {
+        println!("Printer {} ('{}') went offline", id, presence.printer_id);
+    }
+    printer.fabid = presence.fab_id;
+    printer.name = Some(presence.printer_id);
+    printer.address = presence.address;
+    printer.capabilities = presence.capabilities;
+    printer.reachable = Some(presence.online);
+}
+
 fn publish_error(msg : &str, client : &mut AsyncClient) {
     println!("MQTT task failed: {}", msg);
     let _ = client.send(format!("Failure: {}", msg).as_bytes(), "queueFeedback", Qos::OnceAndOneOnly, false);
@@ -31,6 +87,8 @@ pub fn work(printers: Arc<Mutex<HashMap<usize, Printer>>>,
     client.connect(&connection_options).expect("Cannot connect to MQTT broker!");
 
     client.subscribe("queueJob", Qos::OnceAndOneOnly).expect("Cannot subscribe to queueJob topic!");
+    client.subscribe(PRESENCE_TOPIC, Qos::OnceAndOneOnly).expect("Cannot subscribe to presence topic!");
+    client.subscribe(EVENTS_TOPIC, Qos::OnceAndOneOnly).expect("Cannot subscribe to events topic!");
     loop {
         for message in client.messages(None) {
             //println!("{:?}", message);
@@ -49,6 +107,25 @@ pub fn work(printers: Arc<Mutex<HashMap<usize, Printer>>>,
                         }
                     };
                 }
+                topic if topic.ends_with("/presence") => {
+                    let presence : Option<Presence> = message.payload.as_ref()
+                        .and_then(|payload| from_utf8(&payload[..]).ok())
+                        .and_then(|text| json::decode(text).ok());
+                    match presence {
+                        Some(presence) => update_presence(printers.clone(), presence),
+                        None => println!("Ignoring invalid presence message on {}", topic)
+                    }
+                }
+                topic if topic.ends_with("/events") => {
+                    let event : Option<PanelEvent> = message.payload.as_ref()
+                        .and_then(|payload| from_utf8(&payload[..]).ok())
+                        .and_then(|text| json::decode(text).ok());
+                    match event.as_ref().map(|event| &event.event[..]) {
+                        Some("job_done") => metrics::job_finished(false),
+                        Some("job_failed") => metrics::job_finished(true),
+                        _ => {}
+                    }
+                }
                 _ => {}
             }
         }
@@ -0,0 +1,100 @@
+use std::io::Read;
+
+//Same format the panels use: "RBAM" magic, followed by commands (1 byte id + little endian i32 params)
+
+#[derive(RustcDecodable, Debug, Copy, Clone)]
+pub struct BuildVolume {
+    pub min_x: i32,
+    pub min_y: i32,
+    pub min_z: i32,
+    pub max_x: i32,
+    pub max_y: i32,
+    pub max_z: i32
+}
+
+fn read_i32(buf : &[u8]) -> i32 {
+    (buf[0] as i32) | ((buf[1] as i32) << 8) | ((buf[2] as i32) << 16) | ((buf[3] as i32) << 24)
+}
+
+//Smallest and largest coordinate on each axis, None for an axis the blueprint never sets
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Extents {
+    pub x: Option<(i32, i32)>,
+    pub y: Option<(i32, i32)>,
+    pub z: Option<(i32, i32)>
+}
+
+fn range(coords : &[i32]) -> Option<(i32, i32)> {
+    match (coords.iter().min(), coords.iter().max()) {
+        (Some(&min), Some(&max)) => Some((min, max)),
+        _ => None
+    }
+}
+
+//Smallest volume containing every level, dot and line of the blueprint
+pub fn extents(bp : &mut Read) -> Result<Extents, String> {
+    let mut data = vec![0;0];
+    try!( bp.read_to_end(&mut data).map_err(|e| format!("Cannot read blueprint: {}", e)) );
+    if data.len() < 4 || &data[0..4] != b"RBAM" {
+        return Err("invalid blueprint".to_string());
+    }
+
+    let mut xs = Vec::new();
+    let mut ys = Vec::new();
+    let mut zs = Vec::new();
+    let mut pos = 4;
+    while pos < data.len() {
+        let len = match data[pos] {
+            1 => 5,
+            2 => 8,
+            3 => 16,
+            5 => 5, //Temperature, not a position
+            c => return Err(format!("unknown blueprint command {:#x}", c))
+        };
+        if pos + 1 + len > data.len() {
+            return Err("truncated blueprint".to_string());
+        }
+        let params = &data[pos + 1 .. pos + 1 + len];
+        match data[pos] {
+            1 => zs.push(read_i32(&params[0..4])),
+            5 => {},
+            _ => for coord in params.chunks(8) { //Dots have one, lines two x/y pairs
+                xs.push(read_i32(&coord[0..4]));
+                ys.push(read_i32(&coord[4..8]));
+            }
+        }
+        pos += 1 + len;
+    }
+
+    Ok(Extents { x: range(&xs), y: range(&ys), z: range(&zs) })
+}
+
+fn within(range : Option<(i32, i32)>, min : i32, max : i32) -> bool {
+    match range {
+        Some((low, high)) => low >= min && high <= max,
+        None => true //Axis is not used by the blueprint
+    }
+}
+
+impl BuildVolume {
+    pub fn contains(&self, extents : &Extents) -> bool {
+        within(extents.x, self.min_x, self.max_x) &&
+        within(extents.y, self.min_y, self.max_y) &&
+        within(extents.z, self.min_z, self.max_z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_is_not_a_coordinate() {
+        let mut bp : Vec<u8> = b"RBAM".to_vec();
+        bp.extend_from_slice(&[5, 0x58, 0x02, 0, 0, 1]); //Bed to 60.0 degrees
+        bp.extend_from_slice(&[1, 10, 0, 0, 0, 0]);
+        bp.extend_from_slice(&[2, 3, 0, 0, 0, 4, 0, 0, 0]);
+        let extents = extents(&mut &bp[..]).unwrap();
+        assert_eq!(extents, Extents { x: Some((3, 3)), y: Some((4, 4)), z: Some((10, 10)) });
+    }
+}
@@ -2,14 +2,16 @@ use std::sync::{Mutex, Arc};
 use std::collections::HashMap;
 use std::time::Duration;
 use mio::{Handler, EventLoop};
+use hyper::client::Client;
 use printer_mgmt::printbp;
-use super::Printer;
+use super::{Printer, StatusStream};
 use super::super::BenchWatchStopTime;
 use super::super::time;
 
 pub struct Core {
     printers : Arc<Mutex<HashMap<usize, Printer>>>,
-    job_queue : Arc<Mutex<Vec<(usize, String, String)>>>
+    job_queue : Arc<Mutex<Vec<(usize, String, String)>>>,
+    stream_client : Client<StatusStream> //Keeps the event streams of the panels open
 }
 
 pub enum TimeoutType {
@@ -21,7 +23,8 @@ impl Core {
             job_queue : Arc<Mutex<Vec<(usize, String, String)>>>) -> Self {
         Core {
             printers: printers,
-            job_queue: job_queue
+            job_queue: job_queue,
+            stream_client: super::tls::client().expect("Cannot instantiate new Client!")
         }
     }
 }
@@ -34,6 +37,7 @@ impl Handler for Core {
         match timeout_token {
             TimeoutType::PollStatus => {
                 super::update_status( self.printers.clone() );
+                super::open_streams( &self.stream_client, self.printers.clone() );
                 eventloop.timeout( TimeoutType::PollStatus, Duration::from_millis(super::super::POLL_TIME_MS) ).unwrap();
 
                 let queue_copy;
@@ -0,0 +1,153 @@
+use hyper;
+use hyper::{Decoder, Encoder, Next, StatusCode};
+use hyper::client::{Client, Request, Response, DefaultTransport as HttpStream};
+use std::io;
+use std::io::Read;
+use std::sync::{Mutex, Arc};
+use std::time::Duration;
+use std::collections::HashMap;
+use std::str::from_utf8;
+use hyper::Url;
+use rustc_serialize::json;
+use printer_mgmt::tls;
+use printer_mgmt::printer::{Status, Printer, StreamState, ApiToken, auth_header};
+
+//Panels send a keepalive at least every 10s
+const STREAM_TIMEOUT_MS : u64 = 30000;
+
+//Follows GET /events of a panel, the printer status is updated by its status events
+pub struct StatusStream {
+    printer_id : usize,
+    printers : Arc<Mutex<HashMap<usize, Printer>>>,
+    token : Option<ApiToken>,
+    buf : Vec<u8>,
+    pending : Vec<u8> //Received part of an incomplete event
+}
+
+fn read() -> Next {//Helper to generate a read-request with timeout
+    Next::read().timeout(Duration::from_millis(STREAM_TIMEOUT_MS))
+}
+
+impl StatusStream {
+    pub fn new(printer_id : usize, printers : Arc<Mutex<HashMap<usize, Printer>>>, token : Option<ApiToken>) -> Self {
+        StatusStream {
+            printer_id : printer_id,
+            printers : printers,
+            token : token,
+            buf : vec![0;4096],
+            pending : Vec::new()
+        }
+    }
+
+    fn set_state(&self, state : StreamState) {
+        if let Some(printer) = self.printers.lock().unwrap().get_mut(&self.printer_id) {
+            printer.stream = state;
+        }
+    }
+
+    fn closed(&self) -> Next { //Polling takes over, a new stream is opened with the next poll
+        self.set_state(StreamState::Closed);
+        Next::remove()
+    }
+
+    fn handle_event(&self, event : &str) {
+        let mut name = "message";
+        let mut data = String::new();
+        for line in event.lines() {
+            if line.starts_with("event: ") {
+                name = &line[7..];
+            } else if line.starts_with("data: ") {
+                data.push_str(&line[6..]);
+            }
+        }
+        if name != "status" {
+            return; //Every change is followed by a status event
+        }
+        let status : Status = match json::decode(&data) {
+            Ok(status) => status,
+            Err(e) => {
+                println!("Invalid status event from printer {}: {}", self.printer_id, e);
+                return;
+            }
+        };
+        if let Some(printer) = self.printers.lock().unwrap().get_mut(&self.printer_id) {
+            printer.status = status;
+            printer.reachable = Some(true);
+        }
+    }
+}
+
+impl hyper::client::Handler<HttpStream> for StatusStream {
+    fn on_request(&mut self, req: &mut Request) -> Next {
+        auth_header(&self.token, req.headers_mut());
+        read()
+    }
+
+    fn on_request_writable(&mut self, _encoder: &mut Encoder<HttpStream>) -> Next {
+        unimplemented!();
+    }
+
+    fn on_response(&mut self, res: Response) -> Next {
+        match *res.status() {
+            StatusCode::NotFound => {
+                self.set_state(StreamState::Unsupported);
+                return Next::remove();
+            }
+            StatusCode::Unauthorized | StatusCode::Forbidden => {//Fall back to polling, which reports the printer unreachable
+                println!("event stream of printer {} refused: {}", self.printer_id, res.status());
+                self.set_state(StreamState::Unsupported);
+                return Next::remove();
+            }
+            _ => {}
+        }
+        if !res.status().is_success() {
+            return self.closed();
+        }
+        read()
+    }
+
+    fn on_response_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+        match transport.read(&mut self.buf) {
+            Ok(0) => self.closed(),
+            Ok(n) => {
+                self.pending.extend_from_slice(&self.buf[0 .. n]);
+                while let Some(end) = self.pending.windows(2).position(|w| w == b"\n\n") {
+                    let event : Vec<u8> = self.pending.drain(0 .. end + 2).collect();
+                    if let Ok(event) = from_utf8(&event) {
+                        self.handle_event(event);
+                    }
+                }
+                read()
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock => read(),
+                _ => {
+                    println!("event stream of printer {} failed: {:?}", self.printer_id, e);
+                    self.closed()
+                }
+            }
+        }
+    }
+
+    fn on_error(&mut self, _err: hyper::Error) -> Next {
+        self.closed()
+    }
+}
+
+//Opens event streams to all reachable printers that are still polled
+pub fn open_streams(client : &Client<StatusStream>, printers : Arc<Mutex<HashMap<usize, Printer>>>) {
+    let mut printers_lock = printers.lock().unwrap();
+    for printer in printers_lock.values_mut() {
+        if printer.stream != StreamState::Closed || !printer.maybe_reachable() {
+            continue;
+        }
+        let url = match Url::parse( &*tls::panel_url(&printer.address, "/events") ) {
+            Ok(url) => url,
+            Err(_) => continue
+        };
+        printer.stream = StreamState::Open;
+        if client.request( url, StatusStream::new(printer.id, printers.clone(), printer.token.clone()) ).is_err() {
+            printer.stream = StreamState::Closed;
+        }
+    }
+}
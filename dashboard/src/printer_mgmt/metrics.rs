@@ -0,0 +1,95 @@
+use std::sync::Mutex;
+use std::collections::{HashMap, BTreeMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use super::Printer;
+
+//Prometheus text format, served on GET /metrics
+static JOBS_ACCEPTED : AtomicUsize = ATOMIC_USIZE_INIT;  //Sent to a panel
+static JOBS_REJECTED : AtomicUsize = ATOMIC_USIZE_INIT;  //Panel refused or could not be reached
+static JOBS_QUEUED : AtomicUsize = ATOMIC_USIZE_INIT;    //No printer free when submitted
+static JOBS_COMPLETED : AtomicUsize = ATOMIC_USIZE_INIT; //Reported by the panels over MQTT
+static JOBS_FAILED : AtomicUsize = ATOMIC_USIZE_INIT;
+static STATUS_POLLS : AtomicUsize = ATOMIC_USIZE_INIT;
+static STATUS_POLL_US_SUM : AtomicUsize = ATOMIC_USIZE_INIT;
+static STATUS_POLL_US_LAST : AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub fn job_sent(accepted : bool) {
+    if accepted {
+        JOBS_ACCEPTED.fetch_add(1, Ordering::SeqCst);
+    }
+    else {
+        JOBS_REJECTED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+pub fn job_queued() {
+    JOBS_QUEUED.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn job_finished(failed : bool) {
+    if failed {
+        JOBS_FAILED.fetch_add(1, Ordering::SeqCst);
+    }
+    else {
+        JOBS_COMPLETED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+pub fn status_polled(duration_us : u64) {
+    STATUS_POLLS.fetch_add(1, Ordering::SeqCst);
+    STATUS_POLL_US_SUM.fetch_add(duration_us as usize, Ordering::SeqCst);
+    STATUS_POLL_US_LAST.store(duration_us as usize, Ordering::SeqCst);
+}
+
+fn header(out : &mut String, name : &str, kind : &str, help : &str) {
+    let _ = write!(out, "# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+}
+
+fn seconds(us : usize) -> f64 {
+    (us as f64) / 1_000_000.0
+}
+
+pub fn render(printers : &Mutex<HashMap<usize, Printer>>, job_queue : &Mutex<Vec<(usize, String, String)>>) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "dashboard_jobs_total", "counter", "Print jobs by outcome");
+    for &(outcome, counter) in &[("accepted", &JOBS_ACCEPTED), ("rejected", &JOBS_REJECTED), ("queued", &JOBS_QUEUED),
+                                   ("completed", &JOBS_COMPLETED), ("failed", &JOBS_FAILED)] {
+        let _ = write!(out, "dashboard_jobs_total{{outcome=\"{}\"}} {}\n", outcome, counter.load(Ordering::SeqCst));
+    }
+
+    let mut queued = BTreeMap::new();
+    for &(fab, _, _) in job_queue.lock().unwrap().iter() {
+        *queued.entry(fab).or_insert(0) += 1;
+    }
+    let printers = printers.lock().unwrap();
+    for printer in printers.values() {
+        queued.entry(printer.fabid).or_insert(0); //Report empty queues of known fabs as 0
+    }
+    header(&mut out, "dashboard_queue_length", "gauge", "Jobs waiting for a free printer");
+    for (fab, length) in &queued {
+        let _ = write!(out, "dashboard_queue_length{{fab=\"{}\"}} {}\n", fab, length);
+    }
+
+    let mut ids : Vec<&usize> = printers.keys().collect();
+    ids.sort();
+    header(&mut out, "dashboard_printer_reachable", "gauge", "1 if the last status request or event stream reached the panel");
+    for id in &ids {
+        let printer = &printers[*id];
+        let _ = write!(out, "dashboard_printer_reachable{{printer=\"{}\",fab=\"{}\"}} {}\n", printer.id, printer.fabid, (printer.reachable == Some(true)) as u8);
+    }
+    header(&mut out, "dashboard_printer_busy", "gauge", "1 if the printer reported a job in progress");
+    for id in &ids {
+        let printer = &printers[*id];
+        let _ = write!(out, "dashboard_printer_busy{{printer=\"{}\",fab=\"{}\"}} {}\n", printer.id, printer.fabid,
+            (printer.reachable == Some(true) && printer.status.busy) as u8);
+    }
+
+    header(&mut out, "dashboard_status_poll_duration_seconds", "summary", "Time to poll the status of all printers");
+    let _ = write!(out, "dashboard_status_poll_duration_seconds_sum {}\n", seconds(STATUS_POLL_US_SUM.load(Ordering::SeqCst)));
+    let _ = write!(out, "dashboard_status_poll_duration_seconds_count {}\n", STATUS_POLLS.load(Ordering::SeqCst));
+    header(&mut out, "dashboard_status_poll_last_duration_seconds", "gauge", "Duration of the last status poll");
+    let _ = write!(out, "dashboard_status_poll_last_duration_seconds {}\n", seconds(STATUS_POLL_US_LAST.load(Ordering::SeqCst)));
+    out
+}
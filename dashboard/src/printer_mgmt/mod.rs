@@ -1,11 +1,18 @@
 mod printer;
 mod status_req;
 mod print_order;
+mod blueprint;
+mod event_stream;
+mod parts_req;
 pub mod core;
+pub mod tls;
+pub mod metrics;
 
 pub use self::core::Core;
-pub use self::printer::Printer;
+pub use self::printer::{Printer, ApiToken};
 pub use self::status_req::update_status;
+pub use self::event_stream::{StatusStream, open_streams};
+pub use self::parts_req::{PartInfo, get_parts};
 
 use self::printer::Status;
 use std::fs::File;
@@ -23,19 +30,34 @@ pub fn printbp(printers : Arc<Mutex<HashMap<usize, Printer>>>,
         return Err("blueprint not found".to_string());
     }
 
+    let extents = try!( blueprint::extents(&mut File::open(&filename).unwrap()) );
+
     let mut printers_lock = printers.lock().unwrap();
     let mut printers = printers_lock.deref_mut();
 
+    let large_enough = |printer : &Printer| match printer.status.volume {
+        Some(volume) => volume.contains(&extents),
+        None => true //Printers without a configured volume accept everything
+    };
+    if printers.values().any(|printer| printer.fabid == fab)
+            && !printers.values().any(|printer| printer.fabid == fab && large_enough(printer)) {
+        return Err("no printer in this fab is large enough for the blueprint".to_string());
+    }
+
     for (_id, printer) in printers.iter_mut() {
-        if printer.fabid != fab || printer.status.busy || printer.status.matempty {
+        if printer.fabid != fab || !printer.maybe_reachable() || printer.status.busy || printer.status.matempty || !large_enough(&*printer) {
             continue;
         }
         let mut bpfile = File::open(filename).unwrap();
-        printer.status = Status { busy: true, matempty: false, current_job: job_title.clone() };
+        printer.status = Status { busy: true, matempty: false, current_job: job_title.clone(),
+            volume: printer.status.volume, service_due: printer.status.service_due.take() };
 
-        return print_order::printbp(&printer.address, &mut bpfile, job_title).and(
+        let sent = print_order::printbp(&printer.address, &printer.token, &mut bpfile, job_title);
+        metrics::job_sent(sent.is_ok());
+        return sent.and(
             Ok(format!("Job '{}' printing on printer {}", job_title, printer.id)));
     }
     job_queue.lock().unwrap().deref_mut().push(( fab,bpname.to_string(),job_title.clone() ));
+    metrics::job_queued();
     Ok(format!("Job '{}' queued", job_title))
 }
@@ -0,0 +1,115 @@
+use hyper;
+use hyper::{Decoder, Encoder, Next};
+use hyper::client::{Request, Response, DefaultTransport as HttpStream};
+use hyper::header::Connection;
+use std::io;
+use std::io::Read;
+use std::sync::mpsc;
+use std::time::Duration;
+use hyper::Url;
+use rustc_serialize::json;
+use std::str::from_utf8;
+use printer_mgmt::tls;
+use printer_mgmt::printer::{ApiToken, auth_header};
+
+#[derive(RustcDecodable, Debug)]
+pub struct Commands {
+    pub sent: u64,
+    pub acked: u64,
+    pub failed: u64,
+    pub timeouts: u64
+}
+
+//One entry of GET /parts on a panel
+#[derive(RustcDecodable, Debug)]
+pub struct PartInfo {
+    pub id: usize,
+    pub serial: u32,
+    pub part_type: String,
+    pub material: i32,
+    pub material_empty: Option<bool>,
+    pub material_used: Option<u64>,
+    pub job_title: Option<String>,
+    pub paused: bool,
+    pub waiting_for_material: Option<i32>,
+    pub connected_at: i64,
+    pub last_activity: i64,
+    pub commands: Commands
+}
+
+struct PartsReq {
+    result_pipe: mpsc::Sender<Result<Vec<PartInfo>, String>>,
+    token : Option<ApiToken>,
+    buf : Vec<u8>,
+    read_pos : usize
+}
+
+fn read() -> Next {//Helper to generate a read-request with timeout
+    Next::read().timeout(Duration::from_millis(300))
+}
+
+impl hyper::client::Handler<HttpStream> for PartsReq {
+    fn on_request(&mut self, req: &mut Request) -> Next {
+        req.headers_mut().set(Connection::close());
+        auth_header(&self.token, req.headers_mut());
+        read()
+    }
+
+    fn on_request_writable(&mut self, _encoder: &mut Encoder<HttpStream>) -> Next {
+        unimplemented!();
+    }
+
+    fn on_response(&mut self, res: Response) -> Next {
+        if !res.status().is_success() {
+            self.result_pipe.send(Err(format!("panel answered {}", res.status()))).unwrap();
+            return Next::end();
+        }
+        read()
+    }
+
+    fn on_response_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+        if self.read_pos >= self.buf.len() {
+            let newsize = self.buf.len() + 1024;
+            self.buf.resize(newsize, 0); //If buffer is full, resize by 1KB
+        }
+        match transport.read(&mut self.buf[self.read_pos .. ]) {
+            Ok(0) => {
+                let res = from_utf8(&self.buf[0 .. self.read_pos]).map_err(|e| format!("{}", e))
+                    .and_then(|text| json::decode(text).map_err(|e| format!("{}", e)));
+                self.result_pipe.send(res).unwrap();
+                Next::end()
+            }
+            Ok(n) => {
+                self.read_pos += n;
+                read()
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock => read(),
+                _ => {
+                    self.result_pipe.send(Err(format!("read error {:?}", e))).unwrap();
+                    Next::end()
+                }
+            }
+        }
+    }
+
+    fn on_error(&mut self, err: hyper::Error) -> Next {
+        self.result_pipe.send(Err(format!("cannot reach printer: {}", err))).unwrap();
+        Next::remove()
+    }
+}
+
+pub fn get_parts(printer_addr : &str, token : &Option<ApiToken>) -> Result<Vec<PartInfo>, String> {
+    let client = tls::client().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    let url = Url::parse( &*tls::panel_url(printer_addr, "/parts") ).unwrap();
+
+    if client.request( url, PartsReq { result_pipe: tx, token: token.clone(), buf: vec![0;1024], read_pos: 0 } ).is_err() {
+        return Err( "Sending parts request failed!".to_string() );
+    }
+
+    let response = rx.recv().unwrap();
+    client.close();
+    response
+}
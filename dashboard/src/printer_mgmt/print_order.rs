@@ -1,6 +1,6 @@
 use hyper;
 use hyper::{Decoder, Encoder, Next};
-use hyper::client::{Client, Request, Response, DefaultTransport as HttpStream};
+use hyper::client::{Request, Response, DefaultTransport as HttpStream};
 use hyper::header::Connection;
 use std::io;
 use std::io::{Read, Write};
@@ -10,6 +10,8 @@ use hyper::Url;
 use rustc_serialize::json;
 use std::str::from_utf8;
 use rustc_serialize::base64::{ToBase64, STANDARD};
+use printer_mgmt::tls;
+use printer_mgmt::printer::{ApiToken, auth_header};
 
 #[derive(RustcEncodable)]
 struct PrintReq {
@@ -26,12 +28,13 @@ pub struct ReqRes {
 pub struct PrintOrder {
     result_pipe: mpsc::Sender<ReqRes>,
     req: PrintReq,
+    token : Option<ApiToken>,
     buf : Vec<u8>,
     read_pos : usize
 }
 
 impl PrintOrder {
-    pub fn new(result_pipe : mpsc::Sender<ReqRes>, bp : &mut Read, title:&String ) -> Self {
+    pub fn new(result_pipe : mpsc::Sender<ReqRes>, bp : &mut Read, title:&String, token : Option<ApiToken> ) -> Self {
         let mut bpdata = vec![0;0];
         bp.read_to_end(&mut bpdata).expect("Cannot read blueprint!");
         PrintOrder {
@@ -39,6 +42,7 @@ impl PrintOrder {
             req : PrintReq {
                 blueprint : bpdata.to_base64(STANDARD),
                 title: title.clone() },
+            token : token,
             buf : vec![0;64],
             read_pos : 0
         }
@@ -53,6 +57,7 @@ impl hyper::client::Handler<HttpStream> for PrintOrder {
     fn on_request(&mut self, req: &mut Request) -> Next {
         req.headers_mut().set(Connection::close());
         req.set_method(hyper::method::Method::Post);
+        auth_header(&self.token, req.headers_mut());
         Next::read_and_write()
     }
 
@@ -113,13 +118,13 @@ impl hyper::client::Handler<HttpStream> for PrintOrder {
     }
 }
 
-pub fn printbp(printer_addr : &String, blueprint : &mut Read, title : &String) -> Result<(), String> {
-    let client = Client::new().unwrap();
+pub fn printbp(printer_addr : &String, token : &Option<ApiToken>, blueprint : &mut Read, title : &String) -> Result<(), String> {
+    let client = tls::client().unwrap();
     let (tx, rx) = mpsc::channel();
 
-    let url = Url::parse( &*format!("http://{}/print", printer_addr) ).unwrap();
+    let url = Url::parse( &*tls::panel_url(printer_addr, "/print") ).unwrap();
 
-    if client.request( url, PrintOrder::new(tx, blueprint, title) ).is_err() {
+    if client.request( url, PrintOrder::new(tx, blueprint, title, token.clone()) ).is_err() {
         return Err( "Sending print request failed!".to_string() );
     }
 
@@ -1,17 +1,73 @@
 
+use std::env;
+use std::fmt;
+use hyper::header::{Headers, Authorization, Bearer};
+use super::blueprint::BuildVolume;
+
+//Token for panels that have none configured in printers.conf
+const TOKEN_VAR : &'static str = "PANEL_API_TOKEN";
+
+//Bearer token for the panel REST API, kept out of debug output
+#[derive(Clone)]
+pub struct ApiToken(pub String);
+
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ApiToken(..)")
+    }
+}
+
+//Sent with every request to the panel REST API
+pub fn auth_header(token : &Option<ApiToken>, headers : &mut Headers) {
+    if let Some(ApiToken(ref token)) = *token {
+        headers.set( Authorization( Bearer { token: token.clone() } ) );
+    }
+}
+
+//Part of a panel that has reached a service interval
+#[derive(RustcDecodable, Debug)]
+pub struct ServiceDue {
+    pub part: usize,
+    pub serial: u32,
+    pub counters: Vec<String>
+}
+
 #[derive(RustcDecodable, Debug)]
 pub struct Status {
     pub busy: bool,
     pub matempty: bool,
-    pub current_job: String
+    pub current_job: String,
+    pub volume: Option<BuildVolume>,
+    pub service_due: Option<Vec<ServiceDue>> //Missing for older panels
+}
+
+impl Status {
+    pub fn unreachable(volume : Option<BuildVolume>) -> Self {
+        Status { busy: true, matempty: false, current_job: "error: cannot reach printer!".to_string(), volume: volume, service_due: None }
+    }
+
+    pub fn needs_service(&self) -> bool {
+        self.service_due.as_ref().map(|due| !due.is_empty()).unwrap_or(false)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StreamState {
+    Closed,     //Status is polled
+    Open,       //Status is pushed by the panel
+    Unsupported //Panel has no event stream
 }
 
 #[derive(Debug)]
 pub struct Printer {
     pub id : usize,
     pub fabid : usize,
+    pub name : Option<String>, //Printer id the panel announced itself with
     pub address : String,
-    pub reachable : bool,
+    pub token : Option<ApiToken>,
+    pub reachable : Option<bool>, //None until the first status poll, event or presence message
+    pub capabilities : Vec<String>,
+    pub stream : StreamState,
     pub status : Status
 }
 
@@ -20,9 +76,18 @@ impl Printer {
         Printer {
             id: id,
             fabid: fabid,
+            name: None,
             address: address,
-            reachable: false,
-            status: Status { busy: false, matempty: false, current_job: "".to_string() }
+            token: env::var(TOKEN_VAR).ok().map(ApiToken),
+            reachable: None,
+            capabilities: Vec::new(),
+            stream: StreamState::Closed,
+            status: Status { busy: false, matempty: false, current_job: "".to_string(), volume: None, service_due: None }
         }
     }
+
+    //Printers not known to be offline get jobs, the first poll runs only after startup
+    pub fn maybe_reachable(&self) -> bool {
+        self.reachable != Some(false)
+    }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use hyper;
 use hyper::{Decoder, Encoder, Next};
-use hyper::client::{Client, Request, Response, DefaultTransport as HttpStream};
+use hyper::client::{Request, Response, DefaultTransport as HttpStream};
 use hyper::header::Connection;
 use std::io;
 use std::io::Read;
@@ -11,19 +11,24 @@ use std::time::Duration;
 use std::collections::HashMap;
 use hyper::Url;
 use rustc_serialize::json;
-use printer_mgmt::printer::{Status, Printer};
+use printer_mgmt::tls;
+use printer_mgmt::metrics;
+use time;
+use printer_mgmt::printer::{Status, Printer, StreamState, ApiToken, auth_header};
 use std::str::from_utf8;
 
 pub struct StatusReq {
-    result_pipe: mpsc::Sender<Status>,
+    result_pipe: mpsc::Sender<Option<Status>>, //None if the printer cannot be reached
+    token : Option<ApiToken>,
     buf : Vec<u8>,
     read_pos : usize
 }
 
 impl StatusReq {
-    pub fn new(result_pipe : mpsc::Sender<Status>) -> Self {
+    pub fn new(result_pipe : mpsc::Sender<Option<Status>>, token : Option<ApiToken>) -> Self {
         StatusReq {
             result_pipe : result_pipe,
+            token : token,
             buf : vec![0;64],
             read_pos : 0
         }
@@ -37,6 +42,7 @@ fn read() -> Next {//Helper to generate a read-request with timeout
 impl hyper::client::Handler<HttpStream> for StatusReq {
     fn on_request(&mut self, req: &mut Request) -> Next {
         req.headers_mut().set(Connection::close());
+        auth_header(&self.token, req.headers_mut());
         read()
     }
 
@@ -44,7 +50,12 @@ impl hyper::client::Handler<HttpStream> for StatusReq {
         unimplemented!();
     }
 
-    fn on_response(&mut self, _res: Response) -> Next {
+    fn on_response(&mut self, res: Response) -> Next {
+        if !res.status().is_success() {
+            println!("status request failed: {}", res.status());
+            self.result_pipe.send(None).unwrap();
+            return Next::end();
+        }
         read()
     }
 
@@ -58,7 +69,7 @@ impl hyper::client::Handler<HttpStream> for StatusReq {
                 let res_text = from_utf8(&self.buf[0 .. self.read_pos]).unwrap();
                 //println!("decoding '{}' / {:?}", &res_text, res_text.as_bytes() );
                 let res : Status = json::decode(res_text).unwrap();
-                self.result_pipe.send(res).unwrap();
+                self.result_pipe.send(Some(res)).unwrap();
                 Next::end()
             }
             Ok(n) => {
@@ -69,8 +80,7 @@ impl hyper::client::Handler<HttpStream> for StatusReq {
                 io::ErrorKind::WouldBlock => read(),
                 _ => {
                     println!("read error {:?}", e);
-                    self.result_pipe.send(Status{busy: true, matempty: false,
-                        current_job: "error: cannot reach printer!".to_string()}).unwrap();
+                    self.result_pipe.send(None).unwrap();
                     Next::end()
                 }
             }
@@ -79,27 +89,30 @@ impl hyper::client::Handler<HttpStream> for StatusReq {
 
     fn on_error(&mut self, _err: hyper::Error) -> Next {
         //println!("ERROR: {}", _err);
-        self.result_pipe.send(Status{busy: true, matempty: false,
-            current_job: "error: cannot reach printer!".to_string()}).unwrap();
+        self.result_pipe.send(None).unwrap();
         Next::remove()
     }
 }
 
 pub fn update_status(printers : Arc<Mutex<HashMap<usize, Printer>>>) {
-    let mut results = HashMap::<usize, mpsc::Receiver<Status>>::new();
-    let client = Client::new().expect("Cannot instantiate new Client!");
+    let started = time::precise_time_ns();
+    let mut results = HashMap::<usize, mpsc::Receiver<Option<Status>>>::new();
+    let client = tls::client().expect("Cannot instantiate new Client!");
 
     {
         let printers_lock = printers.lock().expect("Cannot lock printers!");
         let printers = printers_lock.deref();
 
         for (id, printer) in printers.iter() {
+            if printer.stream == StreamState::Open {
+                continue; //Kept up to date by the event stream
+            }
             let (tx, rx) = mpsc::channel();
 
-            let url = format!("http://{}/status", printer.address);
+            let url = tls::panel_url(&printer.address, "/status");
             let url = Url::parse( &*url ).expect("Cannot parse URL!");
 
-            if client.request( url, StatusReq::new(tx) ).is_err() {
+            if client.request( url, StatusReq::new(tx, printer.token.clone()) ).is_err() {
                 panic!("Sending status request failed!");
             }
 
@@ -112,13 +125,15 @@ pub fn update_status(printers : Arc<Mutex<HashMap<usize, Printer>>>) {
         {
             let mut printers_lock = printers.lock().unwrap();
             let printers = printers_lock.deref_mut();
-            let printer = printers.get_mut(id);
-            if printer.is_none() {
-                continue;
-            }
-            printer.unwrap().status = status;
+            let printer = match printers.get_mut(id) {
+                Some(printer) => printer,
+                None => continue
+            };
+            printer.reachable = Some(status.is_some());
+            printer.status = status.unwrap_or(Status::unreachable(printer.status.volume));
         }
     }
 
     client.close();
+    metrics::status_polled( (time::precise_time_ns() - started) / 1000 );
 }
@@ -0,0 +1,69 @@
+use std::env;
+use std::sync::Arc;
+use hyper;
+use hyper::client::{Client, Handler, DefaultTransport};
+use hyper::net::{Openssl, HttpsConnector};
+use openssl::ssl::{SslContext, SslMethod, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::x509::X509FileType;
+
+//Certificate and key of the dashboard (PEM), served by the web UI and shown to the panels
+const CERT_VAR : &'static str = "DASHBOARD_TLS_CERT";
+const KEY_VAR : &'static str = "DASHBOARD_TLS_KEY";
+//Local CA that signed the panel certificates, panels are reached over https if set
+const PANEL_CA_VAR : &'static str = "DASHBOARD_PANEL_CA";
+//Only browsers with a certificate signed by this CA may use the web UI
+const UI_CLIENT_CA_VAR : &'static str = "DASHBOARD_UI_CLIENT_CA";
+
+fn identity() -> Option<(String, String)> {
+    match (env::var(CERT_VAR), env::var(KEY_VAR)) {
+        (Ok(cert), Ok(key)) => Some((cert, key)),
+        (Err(_), Err(_)) => None,
+        _ => panic!("{} and {} have to be set together!", CERT_VAR, KEY_VAR)
+    }
+}
+
+fn load_identity(ctx : &mut SslContext, cert : &str, key : &str) {
+    ctx.set_certificate_chain_file(cert, X509FileType::PEM)
+        .expect(&format!("Cannot load TLS certificate {}!", cert));
+    ctx.set_private_key_file(key, X509FileType::PEM)
+        .expect(&format!("Cannot load TLS key {}!", key));
+    ctx.check_private_key().expect("TLS key does not match the certificate!");
+}
+
+//Base url of a panel's REST API
+pub fn panel_url(address : &str, path : &str) -> String {
+    let scheme = if env::var(PANEL_CA_VAR).is_ok() { "https" } else { "http" };
+    format!("{}://{}{}", scheme, address, path)
+}
+
+//Client for panel requests, verifies the panels against our CA and authenticates with our certificate
+pub fn client<H: Handler<DefaultTransport>>() -> hyper::Result<Client<H>> {
+    let ca = match env::var(PANEL_CA_VAR) {
+        Ok(ca) => ca,
+        Err(_) => return Client::new()
+    };
+    let mut ctx = SslContext::new(SslMethod::Sslv23).expect("Cannot create TLS context!");
+    ctx.set_CA_file(&ca).expect(&format!("Cannot load panel CA {}!", ca));
+    ctx.set_verify(SSL_VERIFY_PEER, None);
+    if let Some((cert, key)) = identity() {
+        load_identity(&mut ctx, &cert, &key);
+    }
+    Client::configure()
+        .connector( HttpsConnector::new( Openssl { context: Arc::new(ctx) } ) )
+        .build()
+}
+
+//Context for the web UI, None if it is served over plain http
+pub fn server_context() -> Option<Openssl> {
+    let (cert, key) = match identity() {
+        Some(identity) => identity,
+        None => return None
+    };
+    let mut ctx = SslContext::new(SslMethod::Sslv23).expect("Cannot create TLS context!");
+    load_identity(&mut ctx, &cert, &key);
+    if let Ok(ca) = env::var(UI_CLIENT_CA_VAR) {
+        ctx.set_CA_file(&ca).expect(&format!("Cannot load client CA {}!", ca));
+        ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+    }
+    Some( Openssl { context: Arc::new(ctx) } )
+}
@@ -1,6 +1,6 @@
-use hyper::{Get, Post, StatusCode, RequestUri, Decoder, Encoder, Next};
+use hyper::{Get, Post, StatusCode, RequestUri, Decoder, Encoder, Next, Control};
 use hyper::header::ContentType;
-use hyper::net::HttpStream;
+use hyper::net::Transport;
 use hyper::server::{Server, Handler, Request, Response};
 use hyper::mime;
 use std::sync::{Arc, Mutex};
@@ -9,13 +9,39 @@ use std::io;
 use std::io::{Write, Read};
 use std::fs::File;
 use std::ops::{Deref, DerefMut};
-use printer_mgmt::{Printer, printbp};
+use printer_mgmt::{Printer, ApiToken, PartInfo, printbp, get_parts};
+use printer_mgmt::tls;
+use printer_mgmt::metrics;
 use regex::Regex;
 use super::super::get_new_printer_id;
 use url::form_urlencoded;
 use super::super::BenchWatchStopTime;
 use super::super::time;
 
+fn ago(timestamp_ms : i64) -> String {
+    let now = time::get_time();
+    let now_ms = now.sec * 1000 + (now.nsec / 1_000_000) as i64;
+    format!("{}s ago", (now_ms - timestamp_ms) / 1000)
+}
+
+fn part_row(part : &PartInfo) -> String {
+    let material = match (part.material_empty, part.material_used) {
+        (Some(empty), Some(used)) => format!("{} ({} used{})", part.material, used, if empty { ", nearly empty" } else { "" }),
+        _ => match part.waiting_for_material {
+            Some(matid) => format!("{} (waiting for {})", part.material, matid),
+            None => part.material.to_string()
+        }
+    };
+    let job = match part.job_title {
+        Some(ref title) => format!("{}{}", title, if part.paused { " (paused)" } else { "" }),
+        None => "-".to_string()
+    };
+    format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{} / {} / {} / {}</td><td>{}</td><td>{}</td></tr>\n",
+        part.id, part.serial, part.part_type, material, job,
+        part.commands.sent, part.commands.acked, part.commands.failed, part.commands.timeouts,
+        ago(part.connected_at), ago(part.last_activity))
+}
+
 struct Templates {
     page_begin :  String,
     page_end :    String,
@@ -25,6 +51,7 @@ struct Templates {
     status_fab_end : String,
     status_printer : String,
     print :       String,
+    printer :     String,
     mgmt_begin :  String,
     mgmt_printer: String,
     mgmt_end :    String,
@@ -36,7 +63,8 @@ struct Templates {
     reg_queue:  Regex,
     reg_fab:  Regex,
     reg_printer: Regex,
-    reg_status: Regex
+    reg_status: Regex,
+    reg_parts: Regex
 }
 
 pub struct WebUi {
@@ -53,10 +81,12 @@ enum Action {
     GetStatus,
     GetPrint,
     GetMgmt,
+    GetPrinter(usize),
     Print,
     AddPrinter,
     DelPrinter,
-    Benchmark
+    Benchmark,
+    Metrics
 }
 
 impl WebUi {
@@ -82,10 +112,10 @@ impl WebUi {
         let mut count_matempty = 0;
         for printer in printers.values() {
             fabs.push(printer.fabid);
-            if !printer.status.busy && !printer.status.matempty {
+            if printer.maybe_reachable() && !printer.status.busy && !printer.status.matempty {
                 count_avail += 1;
             }
-            else if printer.status.matempty {
+            if printer.status.matempty || printer.status.needs_service() {
                 count_matempty += 1;
             }
         }
@@ -117,6 +147,28 @@ impl WebUi {
         let _ = outp.write_all( self.templates.print.as_bytes() );
     }
 
+    fn get_printer(&mut self, outp:&mut Write, id : usize) {
+        let (address, token, status) = {
+            let printers_lock = self.printers.lock().unwrap();
+            match printers_lock.deref().get(&id) {
+                Some(printer) => (printer.address.clone(), printer.token.clone(), format!("{:#?}", printer)),
+                None => {
+                    let _ = outp.write_all( b"<div class=\"alert alert-warn\">Printer not found!</div>" );
+                    return;
+                }
+            }
+        };
+
+        let parts = match get_parts(&address, &token) {
+            Ok(parts) => parts.iter().map(part_row).collect::<Vec<String>>().join(""),
+            Err(e) => format!("<tr><td colspan=\"8\">Cannot list parts: {}</td></tr>", e)
+        };
+        let result = self.templates.reg_printer.replace_all(&*self.templates.printer, &*id.to_string());
+        let result = self.templates.reg_status.replace_all(&*result, &*format!("<pre>{}</pre>", status));
+        let result = self.templates.reg_parts.replace_all(&*result, &*parts);
+        let _ = outp.write_all( result.as_bytes() );
+    }
+
     fn get_mgmt(&mut self, outp:&mut Write) {
         let printers_lock = self.printers.lock().unwrap();
         let printers = printers_lock.deref();
@@ -166,7 +218,14 @@ impl WebUi {
         let mut printers = printers_lock.deref_mut();
         let printerid = get_new_printer_id();
 
-        printers.insert( printerid, Printer::new( fab, printerid, ip ) );
+        let mut printer = Printer::new( fab, printerid, ip );
+        let token = form_urlencoded::parse(&self.buf[0 .. self.read_pos]).find(|&(ref key,_)| key=="token");
+        if let Some((_, token)) = token {
+            if !token.is_empty() {
+                printer.token = Some(ApiToken(token.into_owned()));
+            }
+        }
+        printers.insert( printerid, printer );
 
         let _ = outp.write_all( format!("<div class=\"alert alert-success\">Printer added - ID:{}</div>", printerid).as_bytes() );
     }
@@ -269,8 +328,8 @@ impl WebUi {
     }
 }
 
-impl Handler<HttpStream> for WebUi {
-    fn on_request(&mut self, req: Request<HttpStream>) -> Next{
+impl<T: Transport> Handler<T> for WebUi {
+    fn on_request(&mut self, req: Request<T>) -> Next{
         match *req.uri() {
             RequestUri::AbsolutePath(ref path) =>
             match (req.method(), &path[..]) {
@@ -282,6 +341,16 @@ impl Handler<HttpStream> for WebUi {
                     self.action = Action::GetMgmt;
                     Next::write()
                 },
+                (&Get, path) if path.starts_with("/printer/") => {
+                    if let Ok(id) = path["/printer/".len() ..].parse() {
+                        self.action = Action::GetPrinter(id);
+                    }
+                    Next::write()
+                },
+                (&Get, "/metrics") => {
+                    self.action = Action::Metrics;
+                    Next::write()
+                },
                 (&Get, "/bm") => {
                     self.action = Action::Benchmark;
                     Next::write()
@@ -308,7 +377,7 @@ impl Handler<HttpStream> for WebUi {
         }
     }
 
-    fn on_request_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+    fn on_request_readable(&mut self, transport: &mut Decoder<T>) -> Next {
         if self.read_pos >= self.buf.len() {
             let newsize = self.buf.len() + 2048;
             self.buf.resize(newsize, 0); //If buffer is full, resize by 2KB
@@ -338,13 +407,22 @@ impl Handler<HttpStream> for WebUi {
                 res.set_status(StatusCode::BadRequest); //Generic 400 failure
                 Next::write()
             },
+            Action::Metrics => { //Plain text for Prometheus
+                res.headers_mut().set( ContentType( mime::Mime( mime::TopLevel::Text, mime::SubLevel::Plain,
+                    vec![(mime::Attr::Ext("version".to_string()), mime::Value::Ext("0.0.4".to_string()))] ) ) );
+                Next::write()
+            },
             _ => {
                 Next::write()
             }
         }
     }
 
-    fn on_response_writable(&mut self, transport: &mut Encoder<HttpStream>) -> Next {
+    fn on_response_writable(&mut self, transport: &mut Encoder<T>) -> Next {
+        if let Action::Metrics = self.action {
+            let _ = transport.write_all( metrics::render(&self.printers, &self.job_queue).as_bytes() );
+            return Next::end();
+        }
         let _ = transport.write_all( self.templates.page_begin.as_bytes() );
         match self.action {
             Action::InvalidRequest => {
@@ -359,6 +437,9 @@ impl Handler<HttpStream> for WebUi {
             Action::GetMgmt => {
                 self.get_mgmt( transport );
             },
+            Action::GetPrinter(id) => {
+                self.get_printer( transport, id );
+            },
             Action::Benchmark => {
                 self.benchmark( transport );
             },
@@ -373,6 +454,7 @@ impl Handler<HttpStream> for WebUi {
                 self.del_printer( transport );
                 self.get_mgmt( transport );
             }
+            Action::Metrics => {}
             //_ => unimplemented!()
         };
         let _ = transport.write_all( self.templates.page_end.as_bytes() );
@@ -391,6 +473,7 @@ pub fn serve(printers: Arc<Mutex<HashMap<usize, Printer>>>,
         status_fab_end : String::new(),
         status_printer : String::new(),
         print :     String::new(),
+        printer :   String::new(),
         mgmt_begin : String::new(),
         mgmt_printer : String::new(),
         mgmt_end :  String::new(),
@@ -402,7 +485,8 @@ pub fn serve(printers: Arc<Mutex<HashMap<usize, Printer>>>,
         reg_queue :     Regex::new(r"\{queue\}").unwrap(),
         reg_fab :       Regex::new(r"\{fab\}").unwrap(),
         reg_printer :   Regex::new(r"\{printer\}").unwrap(),
-        reg_status :    Regex::new(r"\{status\}").unwrap()
+        reg_status :    Regex::new(r"\{status\}").unwrap(),
+        reg_parts :     Regex::new(r"\{parts\}").unwrap()
     };
     File::open("uitemplates/page_begin.html").expect("Cannot open template page_begin.html!")
         .read_to_string( &mut temps.page_begin ).unwrap();
@@ -420,6 +504,8 @@ pub fn serve(printers: Arc<Mutex<HashMap<usize, Printer>>>,
         .read_to_string( &mut temps.status_printer ).unwrap();
     File::open("uitemplates/print.html").expect("Cannot open template print.html!")
         .read_to_string( &mut temps.print ).unwrap();
+    File::open("uitemplates/printer.html").expect("Cannot open template printer.html!")
+        .read_to_string( &mut temps.printer ).unwrap();
     File::open("uitemplates/mgmt_begin.html").expect("Cannot open template mgmt_begin.html!")
         .read_to_string( &mut temps.mgmt_begin ).unwrap();
     File::open("uitemplates/mgmt_end.html").expect("Cannot open template mgmt_end.html!")
@@ -430,9 +516,18 @@ pub fn serve(printers: Arc<Mutex<HashMap<usize, Printer>>>,
 
     let temps = Arc::new(temps);
 
-    let server = Server::http(&"0.0.0.0:8080".parse().unwrap()).unwrap();
-    let (_, serverloop) = server.handle(|_| WebUi::new( printers.clone(),
-        job_queue.clone(), temps.clone() ) ).unwrap();
+    let addr = "0.0.0.0:8080".parse().unwrap();
+    let factory = |_ : Control| WebUi::new( printers.clone(), job_queue.clone(), temps.clone() );
 
-    serverloop.run();
+    match tls::server_context() {
+        Some(ssl) => {
+            println!("Serving web UI over https");
+            let (_, serverloop) = Server::https(&addr, ssl).unwrap().handle(factory).unwrap();
+            serverloop.run();
+        }
+        None => {
+            let (_, serverloop) = Server::http(&addr).unwrap().handle(factory).unwrap();
+            serverloop.run();
+        }
+    }
 }
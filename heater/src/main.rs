@@ -0,0 +1,73 @@
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const INTERVAL_MS : u64 = 500;
+const AMBIENT : f64 = 20.0;
+const HEATING_RATE : f64 = 1.5; //Celsius per interval
+const COOLING_RATE : f64 = 0.3;
+const SET_TARGET : u8 = 1;
+
+//Record: zone (quantity id of the panel), value in tenths as little endian i32
+fn send(stream : &Mutex<TcpStream>, zone : u8, value : f64) -> bool {
+    let tenths = (value * 10.0).round() as i32;
+    let record = [zone, tenths as u8, (tenths >> 8) as u8, (tenths >> 16) as u8, (tenths >> 24) as u8];
+    stream.lock().unwrap().write_all(&record).is_ok()
+}
+
+fn main() {
+    let stream = TcpStream::connect("127.0.0.1:18000").unwrap();
+    let serial : u32 = std::env::args().nth(1).map(|arg| arg.parse().expect("Serial must be numeric!")).unwrap_or(3000);
+    //1 heats the bed, 2 the chamber
+    let zone : u8 = std::env::args().nth(2).map(|arg| arg.parse().expect("Zone must be numeric!")).unwrap_or(1);
+    println!("Heater serial: {}, zone: {}", serial, zone);
+
+    let mut reader = stream.try_clone().unwrap();
+    let stream = Arc::new(Mutex::new(stream));
+    {
+        let mut stream = stream.lock().unwrap();
+        stream.write_all(&[0, 1, 3]).unwrap(); //Register as heater: extended handshake, version 1, kind heater
+        stream.write_all(&[serial as u8, (serial >> 8) as u8, (serial >> 16) as u8, (serial >> 24) as u8]).unwrap();
+    }
+
+    //Answers heartbeats with an empty record and takes new targets
+    let target = Arc::new(Mutex::new(None));
+    let command_stream = stream.clone();
+    let command_target = target.clone();
+    thread::spawn( move || {
+        let mut command = [0];
+        while reader.read_exact(&mut command).is_ok() {
+            match command[0] {
+                0 => if !send(&command_stream, 0, 0.0) { break },
+                SET_TARGET => {
+                    let mut param = [0; 4];
+                    if reader.read_exact(&mut param).is_err() {
+                        break;
+                    }
+                    let tenths = param[0] as i32 | (param[1] as i32) << 8 | (param[2] as i32) << 16 | (param[3] as i32) << 24;
+                    println!("Target: {}", tenths as f64 / 10.0);
+                    *command_target.lock().unwrap() = Some(tenths as f64 / 10.0);
+                },
+                other => println!("Unknown command {}", other)
+            }
+        }
+        println!("Connection closed, exiting");
+        std::process::exit(0);
+    } );
+
+    let mut temperature = AMBIENT;
+    loop {
+        temperature = match *target.lock().unwrap() {
+            Some(target) if temperature < target => (temperature + HEATING_RATE).min(target),
+            Some(target) => (temperature - COOLING_RATE).max(target.max(AMBIENT)),
+            None => (temperature - COOLING_RATE).max(AMBIENT)
+        };
+        if !send(&stream, zone, temperature) {
+            println!("Connection closed, exiting");
+            return;
+        }
+        thread::sleep(Duration::from_millis(INTERVAL_MS));
+    }
+}
@@ -0,0 +1,33 @@
+#!/bin/bash
+#
+# Creates a local CA plus certificates for the dashboard and panels in ./certs
+# usage: ./make_certs.sh <panel host> [<panel host> ...]
+#
+# panel.conf:  tls_cert certs/<host>.crt, tls_key certs/<host>.key, tls_client_ca certs/ca.crt
+# dashboard:   DASHBOARD_TLS_CERT=certs/dashboard.crt DASHBOARD_TLS_KEY=certs/dashboard.key DASHBOARD_PANEL_CA=certs/ca.crt
+
+set -e
+mkdir -p certs
+cd certs
+
+if [ ! -f ca.key ]; then
+    echo "Creating CA"
+    openssl req -x509 -newkey rsa:2048 -nodes -days 3650 -subj "/CN=VS local CA" -keyout ca.key -out ca.crt
+fi
+
+sign() {
+    echo "Creating certificate for $1"
+    openssl req -newkey rsa:2048 -nodes -subj "/CN=$1" -keyout $1.key -out $1.csr
+    echo "subjectAltName=$2" > $1.ext
+    openssl x509 -req -in $1.csr -CA ca.crt -CAkey ca.key -CAcreateserial -days 825 -extfile $1.ext -out $1.crt
+    rm $1.csr $1.ext
+}
+
+sign dashboard "DNS:localhost,IP:127.0.0.1"
+for host in "$@"; do
+    if [[ $host =~ ^[0-9.]+$ ]]; then
+        sign $host "IP:$host"
+    else
+        sign $host "DNS:$host"
+    fi
+done
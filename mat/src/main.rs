@@ -1,15 +1,36 @@
 use std::io::prelude::*;
 use std::net::TcpStream;
 use std::io::stdin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 const MATID : u8 = 0;
 
 fn main() {
-    let mut level = 10;
+    let mut level : u8 = 10;
     let mut stream = TcpStream::connect("127.0.0.1:18000").unwrap();
-    stream.write(&[(2+MATID)]).unwrap(); //Register as material
+    let serial : u32 = std::env::args().nth(1).map(|arg| arg.parse().expect("Serial must be numeric!")).unwrap_or(1000 + MATID as u32);
+    println!("Material container serial: {}", serial);
 
-    let mut input = String::new();
+    stream.write_all(&[(2+MATID)]).unwrap(); //Register as material
+    stream.write_all(&[serial as u8, (serial >> 8) as u8, (serial >> 16) as u8, (serial >> 24) as u8]).unwrap();
+
+    //Refills are entered on stdin, so heartbeats are still answered while waiting for them
+    let refilled = Arc::new(AtomicBool::new(false));
+    let mut refill_stream = stream.try_clone().unwrap();
+    let refill_flag = refilled.clone();
+    thread::spawn( move || {
+        let mut input = String::new();
+        loop {
+            stdin().read_line(&mut input).unwrap(); //wait till enter to reset
+            println!("Refilled");
+            refill_flag.store(true, Ordering::SeqCst);
+            let _ = refill_stream.write(&[1]); //notify refilled
+        }
+    } );
+
+    let mut empty = false;
     loop{
         let mut usebuf = [0];
         match stream.read_exact(&mut usebuf) {
@@ -19,18 +40,23 @@ fn main() {
                 return;
             }
         }
-        level -= usebuf[0]; //material abziehen
+        if usebuf[0] == 0 { //Heartbeat
+            let _ = stream.write(&[0]);
+            continue;
+        }
+        if refilled.swap(false, Ordering::SeqCst) {
+            level = 20;
+            empty = false;
+        }
+        level = level.saturating_sub(usebuf[0]); //material abziehen
         println!("Matlevel: {}", level);
-        if level > 2 {
+        if level > 2 || empty {
             continue;
         }
-        println!("Nearly empty, halting!", );
+        println!("Nearly empty, halting! Press enter once refilled");
 
         let _ = stream.write(&[255]); //notify nearly empty
-        stdin().read_line(&mut input).unwrap(); //wait till enter to reset
-        println!("Refilled");
-        level = 20;
-        let _ = stream.write(&[1]); //notify nearly empty
+        empty = true;
     }
 
 }
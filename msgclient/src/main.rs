@@ -1,14 +1,42 @@
 extern crate mqtt;
 extern crate time;
+extern crate rustc_serialize;
 
 use std::thread;
 use std::str::from_utf8;
 use std::io;
 use std::io::BufRead;
 use mqtt::async::{PersistenceType, Qos, AsyncClient, AsyncConnectOptions};
+use rustc_serialize::json::Json;
+
+const EVENTS_TOPIC : &'static str = "fab/+/printer/+/events";
 
 static mut BenchCounter : i64 = 0;
 static mut BenchWatchStopTime : u64 = 0;
+static mut JsonEventsSeen : bool = false;
+
+//Name of a JSON printer event, None for anything else
+fn event_name(payload : &str) -> Option<String> {
+    match Json::from_str(payload) {
+        Ok(event) => event.find("event").and_then(|name| name.as_string()).map(|name| name.to_string()),
+        Err(_) => None
+    }
+}
+
+//Whether a message reports a finished job. Older panels only send "Done" as free text on printInfo,
+//panels that publish JSON events (and printInfo in legacy mode) are counted by their events.
+fn job_done(topic : &str, payload : &str) -> bool {
+    if topic == "printInfo" {
+        return unsafe { !JsonEventsSeen } && payload.contains("Done");
+    }
+    match event_name(payload) {
+        Some(name) => {
+            unsafe { JsonEventsSeen = true; }
+            name == "job_done"
+        },
+        None => false
+    }
+}
 
 fn outputloop() {
     let connection_options = AsyncConnectOptions::new();
@@ -16,7 +44,8 @@ fn outputloop() {
     client.connect(&connection_options).expect("Cannot connect to MQTT broker!");
 
     client.subscribe("queueFeedback", Qos::OnceAndOneOnly).expect("Cannot subscribe to queueFeedback topic!");
-    client.subscribe("printInfo", Qos::OnceAndOneOnly).expect("Cannot subscribe to printInfo topic!");
+    client.subscribe(EVENTS_TOPIC, Qos::OnceAndOneOnly).expect("Cannot subscribe to printer events topic!");
+    client.subscribe("printInfo", Qos::OnceAndOneOnly).expect("Cannot subscribe to printInfo topic!"); //Panels in legacy mode
 
     loop {
         for message in client.messages(None) {
@@ -26,10 +55,9 @@ fn outputloop() {
                     Err(_) => println!("{}: Non-UTF8 payload", message.topic),
                     Ok(payload_msg) => {
                         println!("{}: {}", message.topic, payload_msg);
+                        let done = job_done(&*message.topic, payload_msg);
                         unsafe {
-                            if BenchCounter > 0 &&
-                                    &*message.topic == "printInfo" &&
-                                    payload_msg.contains("Done") {
+                            if BenchCounter > 0 && done {
                                 BenchCounter -= 1;
                                 if BenchCounter == 0 {
                                     println!("Benchmark: {}ms", (time::precise_time_ns() - BenchWatchStopTime) / 1_000_000);
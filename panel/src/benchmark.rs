@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use time;
+use internals::blueprint;
+use latency;
+use latency::{CommandLatencies, LatencySummary};
+
+const DEFAULT_COUNT : u32 = 10000;
+const MAX_REPORTS : usize = 20;
+
+static BENCHMARK_ID_COUNTER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+//How many commands to send and in which ratio, e.g. 1 level : 5 dots : 2 lines
+#[derive(Debug, Clone)]
+pub struct BenchmarkSpec {
+    pub count: u32,
+    pub mix: Vec<(u8, u32)> //Command id, weight
+}
+
+//Sent to POST /benchmark
+#[derive(RustcDecodable)]
+pub struct BenchmarkReq {
+    pub count: Option<u32>,
+    pub mix: Option<HashMap<String, u32>>,
+    pub printhead: Option<usize>
+}
+
+#[derive(RustcEncodable, Debug, Clone)]
+pub struct BenchmarkReport {
+    pub id: usize,
+    pub printhead: usize,
+    pub running: bool,
+    pub aborted: Option<String>,
+    pub count: u32,
+    pub mix: HashMap<String, u32>,
+    pub answered: u32,
+    pub failed: u32,
+    pub duration_ms: f64,
+    pub commands_per_sec: f64,
+    pub latency: HashMap<String, LatencySummary>
+}
+
+//Finished benchmarks by id, running ones are only known to their printhead
+pub type BenchmarkReports = HashMap<usize, BenchmarkReport>;
+
+impl BenchmarkSpec {
+    //The old benchmark: 10000 level commands
+    pub fn default() -> BenchmarkSpec {
+        BenchmarkSpec { count: DEFAULT_COUNT, mix: vec![(1, 1)] }
+    }
+
+    pub fn new(count : Option<u32>, mix : Option<&HashMap<String, u32>>) -> Result<BenchmarkSpec, String> {
+        let count = count.unwrap_or(DEFAULT_COUNT);
+        if count == 0 {
+            return Err("count has to be positive".to_string());
+        }
+        let mix = match mix {
+            None => vec![(1, 1)],
+            Some(mix) => {
+                let mut parsed = Vec::new();
+                for (name, weight) in mix {
+                    let commandid = try!( blueprint::command_id(name).ok_or(format!("unknown command '{}'", name)) );
+                    if *weight > 0 {
+                        parsed.push((commandid, *weight));
+                    }
+                }
+                parsed.sort();
+                parsed
+            }
+        };
+        if mix.is_empty() {
+            return Err("empty command mix".to_string());
+        }
+        Ok( BenchmarkSpec { count: count, mix: mix } )
+    }
+
+    //From the command line: "[count] [level=1,dot=5,line=2]"
+    pub fn parse(args : &[&str]) -> Result<BenchmarkSpec, String> {
+        let count = match args.get(0) {
+            Some(count) => Some( try!( count.parse().map_err(|_| format!("invalid count '{}'", count)) ) ),
+            None => None
+        };
+        let mix = match args.get(1) {
+            Some(mix) => {
+                let mut parsed = HashMap::new();
+                for entry in mix.split(',') {
+                    let mut fields = entry.split('=');
+                    let name = fields.next().unwrap_or("");
+                    let weight = try!( fields.next().and_then(|weight| weight.parse().ok())
+                        .ok_or(format!("invalid mix entry '{}'", entry)) );
+                    parsed.insert(name.to_string(), weight);
+                }
+                Some(parsed)
+            },
+            None => None
+        };
+        BenchmarkSpec::new(count, mix.as_ref())
+    }
+
+    //Command ids in the order they are sent, repeated until count is reached
+    fn pattern(&self) -> Vec<u8> {
+        let mut pattern = Vec::new();
+        for &(commandid, weight) in &self.mix {
+            for _ in 0 .. weight {
+                pattern.push(commandid);
+            }
+        }
+        pattern
+    }
+}
+
+//Arbitrary commands of each type, the printhead does not know it is benchmarked
+fn command_bytes(commandid : u8, index : u32) -> Vec<u8> {
+    let pos = (index % 100) as u8;
+    match commandid {
+        1 => vec![1, 57, 5, 0, 0, 0],
+        2 => vec![2, pos, 0, 0, 0, pos, 0, 0, 0],
+        _ => vec![3, pos, 0, 0, 0, pos, 0, 0, 0, 100, 0, 0, 0, pos, 0, 0, 0]
+    }
+}
+
+//State of a benchmark on one printhead
+pub struct BenchmarkRun {
+    pub id: usize,
+    spec: BenchmarkSpec,
+    pattern: Vec<u8>,
+    sent: u32,
+    answered: u32,
+    failed: u32,
+    started_ns: u64,
+    latency: CommandLatencies
+}
+
+impl BenchmarkRun {
+    pub fn new(spec : BenchmarkSpec) -> BenchmarkRun {
+        BenchmarkRun {
+            id: BENCHMARK_ID_COUNTER.fetch_add(1, Ordering::SeqCst),
+            pattern: spec.pattern(),
+            spec: spec,
+            sent: 0,
+            answered: 0,
+            failed: 0,
+            started_ns: time::precise_time_ns(),
+            latency: CommandLatencies::new()
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.sent >= self.spec.count
+    }
+
+    //None once all commands have been sent
+    pub fn next_command(&mut self) -> Option<(u8, Vec<u8>)> {
+        if self.finished() {
+            return None;
+        }
+        let commandid = self.pattern[(self.sent as usize) % self.pattern.len()];
+        let command = command_bytes(commandid, self.sent);
+        self.sent += 1;
+        Some((commandid, command))
+    }
+
+    pub fn answered(&mut self, commandid : u8, latency_us : u64, ok : bool) {
+        self.answered += 1;
+        if !ok {
+            self.failed += 1;
+        }
+        latency::record(&mut self.latency, commandid, latency_us);
+    }
+
+    pub fn report(&self, printhead : usize, running : bool, aborted : Option<&str>) -> BenchmarkReport {
+        let duration_ms = ( (time::precise_time_ns() - self.started_ns) as f64 ) / 1_000_000.0;
+        BenchmarkReport {
+            id: self.id,
+            printhead: printhead,
+            running: running,
+            aborted: aborted.map(|reason| reason.to_string()),
+            count: self.spec.count,
+            mix: self.spec.mix.iter().map(|&(commandid, weight)| (blueprint::command_name(commandid).to_string(), weight)).collect(),
+            answered: self.answered,
+            failed: self.failed,
+            duration_ms: duration_ms,
+            commands_per_sec: if duration_ms > 0.0 { (self.answered as f64) * 1000.0 / duration_ms } else { 0.0 },
+            latency: latency::summarize(&self.latency)
+        }
+    }
+}
+
+pub fn store(reports : &mut BenchmarkReports, report : BenchmarkReport) {
+    if reports.len() >= MAX_REPORTS {
+        let oldest = *reports.keys().min().unwrap();
+        reports.remove(&oldest);
+    }
+    reports.insert(report.id, report);
+}
@@ -0,0 +1,280 @@
+use std::fs::File;
+use std::path::Path;
+use std::io::{BufReader, BufRead};
+use std::collections::HashMap;
+use internals::blueprint::BuildVolume;
+use telemetry::{Quantity, SensorLimit};
+use maintenance::{Counter, ServiceInterval};
+use sim::SIM_SERIALS;
+
+const CONFIG_FILE : &'static str = "panel.conf";
+
+//What a REST client may do, control includes read
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Scope {
+    Read,
+    Control
+}
+
+#[derive(Debug, Clone)]
+pub struct HmacKey {
+    pub secret: String,
+    pub scopes: Vec<Scope>
+}
+
+//Virtual material container of the simulation mode
+#[derive(Debug, Clone, Copy)]
+pub struct SimContainer {
+    pub matid: u8,
+    pub capacity: u32 //Material units until it is refilled
+}
+
+//Panel settings, read from panel.conf ("<key>TAB<value>" per line, # starts a comment)
+pub struct Config {
+    pub fab_id: usize,
+    pub printer_id: String, //Used in MQTT topics, has to be unique within the fab
+    pub legacy_printinfo: bool, //Additionally publish the old free text on printInfo
+    pub rest_address: String, //Announced to the dashboard, has to be reachable from there
+    pub build_volume: Option<BuildVolume>,
+    pub voxel_size: i32, //Edge length of a voxel in blueprint units, for virtual bed exports
+    pub max_retries: u32, //How often a command failed by the printhead is sent again
+    pub pipeline_depth: usize, //Commands kept in flight per printhead that advertises a command buffer
+    pub heartbeat_interval_ms: u64, //Idle parts are pinged this often, 0 disables heartbeats
+    pub heartbeat_missed: u32, //Unanswered heartbeats after which a part is unhealthy
+    pub mat_substitutes: HashMap<i32, i32>, //Material to use if the requested one is not available
+    pub qc_max_deviation: f64, //Highest deviation score a print passes the quality check with
+    pub history_limit: usize, //Jobs kept in the job history, older ones are dropped
+    pub purge_amount: u8, //Material units drawn from the new container on every tool change
+    pub purge_command: bool, //Let the printhead purge and wipe after a tool change
+    pub toolchange_confirm: bool, //Tool changes wait until the operator has confirmed them
+    pub sensor_limits: Vec<SensorLimit>, //Printheads hold while a sensor reads outside of them
+    pub heater_tolerance: f64, //Jobs stay heating until the heater is this close to the target, in celsius
+    pub heater_timeout_ms: u64, //Jobs fail if no heater of their zone connects within this time
+    pub service_intervals: Vec<ServiceInterval>, //Parts are due for service once a counter reaches its limit
+    pub api_tokens: HashMap<String, Vec<Scope>>, //Bearer tokens, REST is open if neither tokens nor keys are configured
+    pub hmac_keys: HashMap<String, HmacKey>, //Key id -> secret for signed requests
+    pub max_body_bytes: usize, //Larger REST request bodies are rejected with 413
+    pub tls_cert: Option<String>, //PEM files, REST is served over https if both are set
+    pub tls_key: Option<String>,
+    pub tls_client_ca: Option<String>, //Only clients with a certificate signed by this CA are accepted
+    pub sim_printheads: u32, //Virtual parts started inside the panel, see sim.rs
+    pub sim_containers: Vec<SimContainer>,
+    pub sim_delay_ms: u64, //Time a virtual printhead takes per command
+    pub sim_failure_rate: f64, //Share of commands a virtual printhead fails
+    pub sim_buffer_depth: u8, //Command buffer of a virtual printhead, 1 registers without one
+    pub sim_refill_ms: u64 //Time until an empty virtual container is refilled
+}
+
+impl Config {
+    pub fn auth_required(&self) -> bool {
+        !self.api_tokens.is_empty() || !self.hmac_keys.is_empty()
+    }
+
+    pub fn simulation(&self) -> bool {
+        self.sim_printheads > 0 || !self.sim_containers.is_empty()
+    }
+}
+
+fn parse_scopes(value : &str) -> Vec<Scope> {
+    value.split(',').map(|scope| match scope.trim() {
+        "read" => Scope::Read,
+        "control" => Scope::Control,
+        other => panic!("Invalid config file: Unknown scope '{}'!", other)
+    }).collect()
+}
+
+fn parse_coords(key : &str, value : &str) -> [i32; 3] {
+    let coords : Vec<i32> = value.split_whitespace()
+        .map(|c| c.parse().expect(&format!("Invalid config file: Non-numeric coordinate for {}!", key)))
+        .collect();
+    if coords.len() != 3 {
+        panic!("Invalid config file: {} needs x, y and z!", key);
+    }
+    [coords[0], coords[1], coords[2]]
+}
+
+pub fn load() -> Config {
+    let mut config = Config {
+        fab_id: 0,
+        printer_id: "0".to_string(),
+        legacy_printinfo: false,
+        rest_address: "127.0.0.1:18080".to_string(),
+        build_volume: None,
+        voxel_size: 1,
+        max_retries: 0,
+        pipeline_depth: 8,
+        heartbeat_interval_ms: 2000,
+        heartbeat_missed: 3,
+        mat_substitutes: HashMap::new(),
+        qc_max_deviation: 0.0,
+        history_limit: 10000,
+        purge_amount: 0,
+        purge_command: false,
+        toolchange_confirm: false,
+        sensor_limits: Vec::new(),
+        heater_tolerance: 2.0,
+        heater_timeout_ms: 300000,
+        service_intervals: Vec::new(),
+        api_tokens: HashMap::new(),
+        hmac_keys: HashMap::new(),
+        max_body_bytes: 16 * 1024 * 1024,
+        tls_cert: None,
+        tls_key: None,
+        tls_client_ca: None,
+        sim_printheads: 0,
+        sim_containers: Vec::new(),
+        sim_delay_ms: 10,
+        sim_failure_rate: 0.0,
+        sim_buffer_depth: 16,
+        sim_refill_ms: 5000
+    };
+    if ! Path::new(CONFIG_FILE).exists() {
+        return config;
+    }
+
+    let mut volume_min = None;
+    let mut volume_max = None;
+
+    let conf = BufReader::new( File::open(CONFIG_FILE).unwrap() );
+    for line in conf.lines() {
+        let line = line.expect("Error while reading panel config");
+        if line.trim().is_empty() || line.starts_with("#") {
+            continue;
+        }
+        let (key, value) = line.split_at( line.find("\t").expect("Invalid config file: Line without TAB!") );
+        let value = value.trim();
+        match key {
+            "fab_id" => config.fab_id = value.parse().expect("Invalid config file: Non-numeric fab_id!"),
+            "printer_id" => config.printer_id = value.to_string(),
+            "legacy_printinfo" => config.legacy_printinfo = value.parse().expect("Invalid config file: legacy_printinfo has to be true or false!"),
+            "rest_address" => config.rest_address = value.to_string(),
+            "volume_min" => volume_min = Some(parse_coords(key, value)),
+            "volume_max" => volume_max = Some(parse_coords(key, value)),
+            "voxel_size" => {
+                config.voxel_size = value.parse().expect("Invalid config file: Non-numeric voxel_size!");
+                if config.voxel_size < 1 {
+                    panic!("Invalid config file: voxel_size has to be at least 1!");
+                }
+            },
+            "max_retries" => config.max_retries = value.parse().expect("Invalid config file: Non-numeric max_retries!"),
+            "pipeline_depth" => {
+                config.pipeline_depth = value.parse().expect("Invalid config file: Non-numeric pipeline_depth!");
+                if config.pipeline_depth < 1 || config.pipeline_depth > 128 {
+                    panic!("Invalid config file: pipeline_depth has to be 1-128!"); //Sequence numbers are a single byte
+                }
+            },
+            "heartbeat_interval_ms" => config.heartbeat_interval_ms = value.parse().expect("Invalid config file: Non-numeric heartbeat_interval_ms!"),
+            "heartbeat_missed" => config.heartbeat_missed = value.parse().expect("Invalid config file: Non-numeric heartbeat_missed!"),
+            "mat_substitute" => {
+                let ids : Vec<i32> = value.split_whitespace()
+                    .map(|id| id.parse().expect("Invalid config file: Non-numeric material id!"))
+                    .collect();
+                if ids.len() != 2 {
+                    panic!("Invalid config file: mat_substitute needs material and substitute!");
+                }
+                config.mat_substitutes.insert(ids[0], ids[1]);
+            },
+            "qc_max_deviation" => config.qc_max_deviation = value.parse().expect("Invalid config file: Non-numeric qc_max_deviation!"),
+            "history_limit" => {
+                config.history_limit = value.parse().expect("Invalid config file: Non-numeric history_limit!");
+                if config.history_limit < 1 {
+                    panic!("Invalid config file: history_limit has to be at least 1!");
+                }
+            },
+            "purge_amount" => config.purge_amount = value.parse().expect("Invalid config file: purge_amount has to be 0-255!"),
+            "purge_command" => config.purge_command = value.parse().expect("Invalid config file: purge_command has to be true or false!"),
+            "toolchange_confirm" => config.toolchange_confirm = value.parse().expect("Invalid config file: toolchange_confirm has to be true or false!"),
+            "sensor_limit" => {
+                let fields : Vec<&str> = value.split_whitespace().collect();
+                if fields.len() != 3 {
+                    panic!("Invalid config file: sensor_limit needs quantity, min and max!");
+                }
+                config.sensor_limits.push( SensorLimit {
+                    quantity: Quantity::parse(fields[0]).unwrap_or_else(|e| panic!("Invalid config file: {}!", e)),
+                    min: fields[1].parse().expect("Invalid config file: Non-numeric sensor_limit!"),
+                    max: fields[2].parse().expect("Invalid config file: Non-numeric sensor_limit!")
+                } );
+            },
+            "heater_tolerance" => config.heater_tolerance = value.parse().expect("Invalid config file: Non-numeric heater_tolerance!"),
+            "heater_timeout_ms" => config.heater_timeout_ms = value.parse().expect("Invalid config file: Non-numeric heater_timeout_ms!"),
+            "service_interval" => {
+                let fields : Vec<&str> = value.split_whitespace().collect();
+                if fields.len() != 2 {
+                    panic!("Invalid config file: service_interval needs counter and limit!");
+                }
+                config.service_intervals.push( ServiceInterval {
+                    counter: Counter::parse(fields[0]).unwrap_or_else(|e| panic!("Invalid config file: {}!", e)),
+                    limit: fields[1].parse().expect("Invalid config file: Non-numeric service_interval!")
+                } );
+            },
+            "api_token" => {
+                let fields : Vec<&str> = value.split_whitespace().collect();
+                if fields.len() != 2 {
+                    panic!("Invalid config file: api_token needs token and scopes!");
+                }
+                config.api_tokens.insert(fields[0].to_string(), parse_scopes(fields[1]));
+            },
+            "api_hmac_key" => {
+                let fields : Vec<&str> = value.split_whitespace().collect();
+                if fields.len() != 3 {
+                    panic!("Invalid config file: api_hmac_key needs key id, secret and scopes!");
+                }
+                config.hmac_keys.insert(fields[0].to_string(),
+                    HmacKey { secret: fields[1].to_string(), scopes: parse_scopes(fields[2]) });
+            },
+            "max_body_bytes" => config.max_body_bytes = value.parse().expect("Invalid config file: Non-numeric max_body_bytes!"),
+            "tls_cert" => config.tls_cert = Some(value.to_string()),
+            "tls_key" => config.tls_key = Some(value.to_string()),
+            "tls_client_ca" => config.tls_client_ca = Some(value.to_string()),
+            "sim_printheads" => {
+                config.sim_printheads = value.parse().expect("Invalid config file: Non-numeric sim_printheads!");
+                if config.sim_printheads > SIM_SERIALS {
+                    panic!("Invalid config file: sim_printheads has to be at most {}!", SIM_SERIALS);
+                }
+            },
+            "sim_container" => {
+                let fields : Vec<u32> = value.split_whitespace()
+                    .map(|field| field.parse().expect("Invalid config file: Non-numeric sim_container!"))
+                    .collect();
+                if fields.len() != 2 || fields[0] > 253 {
+                    panic!("Invalid config file: sim_container needs material id (0-253) and capacity!");
+                }
+                if config.sim_containers.len() as u32 >= SIM_SERIALS {
+                    panic!("Invalid config file: At most {} sim_container lines!", SIM_SERIALS);
+                }
+                config.sim_containers.push( SimContainer { matid: fields[0] as u8, capacity: fields[1] } );
+            },
+            "sim_delay_ms" => config.sim_delay_ms = value.parse().expect("Invalid config file: Non-numeric sim_delay_ms!"),
+            "sim_failure_rate" => {
+                config.sim_failure_rate = value.parse().expect("Invalid config file: Non-numeric sim_failure_rate!");
+                if !(config.sim_failure_rate >= 0.0 && config.sim_failure_rate <= 1.0) {
+                    panic!("Invalid config file: sim_failure_rate has to be between 0 and 1!");
+                }
+            },
+            "sim_buffer_depth" => {
+                config.sim_buffer_depth = value.parse().expect("Invalid config file: sim_buffer_depth has to be 1-255!");
+                if config.sim_buffer_depth == 0 {
+                    panic!("Invalid config file: sim_buffer_depth has to be 1-255!");
+                }
+            },
+            "sim_refill_ms" => config.sim_refill_ms = value.parse().expect("Invalid config file: Non-numeric sim_refill_ms!"),
+            _ => println!("Ignoring unknown config key '{}'", key)
+        }
+    }
+
+    config.build_volume = match (volume_min, volume_max) {
+        (None, None) => None,
+        (Some(min), Some(max)) => Some( BuildVolume {
+            min_x: min[0], min_y: min[1], min_z: min[2],
+            max_x: max[0], max_y: max[1], max_z: max[2]
+        } ),
+        _ => panic!("Invalid config file: volume_min and volume_max have to be set together!")
+    };
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        panic!("Invalid config file: tls_cert and tls_key have to be set together!");
+    }
+    if config.tls_client_ca.is_some() && config.tls_cert.is_none() {
+        panic!("Invalid config file: tls_client_ca needs tls_cert and tls_key!");
+    }
+    config
+}
@@ -0,0 +1,61 @@
+use std::io::{Read, Write};
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use rustc_serialize::json;
+use events::now_ms;
+use internals::journal::SPOOL_DIR;
+
+//Exists while an emergency stop is active, so a restarted panel still refuses jobs
+const ESTOP_FILE : &'static str = "spool/emergency_stop.json";
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct EmergencyStop {
+    pub triggered_by: String, //e.g. "console", "rest key dashboard (alice)" or "remote (alice)"
+    pub triggered_at: i64     //Milliseconds since the epoch
+}
+
+//Set by the console, REST and remote commands, the event loop owns the active stop
+pub fn load() -> Option<EmergencyStop> {
+    let mut stop = None;
+    if Path::new(ESTOP_FILE).exists() {
+        let mut text = String::new();
+        File::open(ESTOP_FILE).expect("Cannot open emergency stop state!")
+            .read_to_string(&mut text).expect("Cannot read emergency stop state!");
+        let active : EmergencyStop = json::decode(&text).expect("Invalid emergency stop state!");
+        println!("EMERGENCY STOP by {} is still active, no jobs are accepted until it is reset", active.triggered_by);
+        stop = Some(active);
+    }
+    stop
+}
+
+//Returns false if a stop was already active
+pub fn trigger(stop : &mut Option<EmergencyStop>, by : &str) -> bool {
+    if stop.is_some() {
+        return false;
+    }
+    println!("EMERGENCY STOP triggered by {}", by);
+    let active = EmergencyStop { triggered_by: by.to_string(), triggered_at: now_ms() };
+    let written = fs::create_dir_all(SPOOL_DIR)
+        .and_then(|_| File::create(ESTOP_FILE))
+        .and_then(|mut file| file.write_all(json::encode(&active).unwrap().as_bytes()));
+    if let Err(e) = written {
+        println!("Cannot write emergency stop state: {}", e);
+    }
+    *stop = Some(active);
+    true
+}
+
+//Returns false if no stop was active
+pub fn reset(stop : &mut Option<EmergencyStop>, by : &str) -> bool {
+    match stop.take() {
+        Some(active) => {
+            println!("Emergency stop by {} reset by {}", active.triggered_by, by);
+            if let Err(e) = fs::remove_file(ESTOP_FILE) {
+                println!("Cannot remove emergency stop state: {}", e);
+            }
+            true
+        },
+        None => false
+    }
+}
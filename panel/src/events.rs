@@ -0,0 +1,247 @@
+use time;
+use mqtt::async::{PersistenceType, Qos, AsyncClient, AsyncConnectOptions};
+use rustc_serialize::json;
+use config::Config;
+use vbed::quality::QualityReport;
+use remote::RemoteReply;
+use rest::SharedEventStreams;
+use status::Status;
+use internals::MaterialUsage;
+use telemetry::Sample;
+
+//Progress events are only sent when the job advanced by this many percent
+pub const PROGRESS_STEP_PERCENT : u8 = 10;
+
+//Published as JSON on fab/<fab>/printer/<id>/events, unused fields are null
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Event {
+    pub event: &'static str,
+    pub timestamp: i64, //Milliseconds since the epoch
+    pub part: Option<usize>,
+    pub part_type: Option<String>,
+    pub serial: Option<u32>,
+    pub job_id: Option<usize>,
+    pub title: Option<String>,
+    pub progress: Option<u8>,
+    pub layer: Option<i32>,
+    pub material: Option<i32>,
+    pub previous_material: Option<i32>,
+    pub quantity: Option<&'static str>, //Sensor reading, heater zone or maintenance counter
+    pub value: Option<f64>,
+    pub materials: Option<Vec<MaterialUsage>>, //Used by a finished job
+    pub reason: Option<String>,
+    pub quality: Option<QualityReport>
+}
+
+//What the panel offers, announced in its presence message
+const CAPABILITIES : [&'static str; 13] = ["print", "benchmark", "events", "remote", "bed_export", "quality", "latency", "split", "telemetry", "heater", "maintenance", "history", "emergency_stop"];
+
+//Retained on fab/<fab>/printer/<id>/presence, replaced by the last will (online: false) if the panel dies
+#[derive(RustcEncodable)]
+struct Presence {
+    printer_id: String,
+    fab_id: usize,
+    online: bool,
+    address: String,
+    capabilities: Vec<&'static str>,
+    timestamp: i64
+}
+
+fn presence(config : &Config, online : bool) -> String {
+    json::encode(&Presence {
+        printer_id: config.printer_id.clone(),
+        fab_id: config.fab_id,
+        online: online,
+        address: config.rest_address.clone(),
+        capabilities: CAPABILITIES.to_vec(),
+        timestamp: now_ms()
+    }).unwrap()
+}
+
+//fab/<fab>/printer/<id>/<leaf>
+pub fn printer_topic(config : &Config, leaf : &str) -> String {
+    format!("fab/{}/printer/{}/{}", config.fab_id, config.printer_id, leaf)
+}
+
+pub fn now_ms() -> i64 {
+    let now = time::get_time();
+    now.sec * 1000 + (now.nsec / 1_000_000) as i64
+}
+
+impl Event {
+    fn new(event : &'static str, part : usize) -> Event {
+        Event {
+            event: event,
+            timestamp: now_ms(),
+            part: Some(part),
+            part_type: None,
+            serial: None,
+            job_id: None,
+            title: None,
+            progress: None,
+            layer: None,
+            material: None,
+            previous_material: None,
+            quantity: None,
+            value: None,
+            materials: None,
+            reason: None,
+            quality: None
+        }
+    }
+
+    pub fn job_started(part : usize, job_id : usize, title : &str) -> Event {
+        Event { job_id: Some(job_id), title: Some(title.to_string()), ..Event::new("job_started", part) }
+    }
+
+    pub fn progress(part : usize, job_id : usize, percent : u8) -> Event {
+        Event { job_id: Some(job_id), progress: Some(percent), ..Event::new("progress", part) }
+    }
+
+    pub fn layer_changed(part : usize, job_id : usize, layer : i32, material : i32) -> Event {
+        Event { job_id: Some(job_id), layer: Some(layer), material: Some(material), ..Event::new("layer_changed", part) }
+    }
+
+    pub fn tool_change(part : usize, job_id : usize, from : i32, to : i32) -> Event {
+        Event { job_id: Some(job_id), material: Some(to), previous_material: Some(from), ..Event::new("tool_change", part) }
+    }
+
+    pub fn heating(part : usize, job_id : usize, zone : &'static str, target : f64, reached : bool) -> Event {
+        let event = if reached { "heating_done" } else { "heating" };
+        Event { job_id: Some(job_id), quantity: Some(zone), value: Some(target), ..Event::new(event, part) }
+    }
+
+    pub fn heater_missing(part : usize, job_id : usize, zone : &'static str) -> Event {
+        Event { job_id: Some(job_id), quantity: Some(zone), ..Event::new("heater_missing", part) }
+    }
+
+    pub fn job_done(part : usize, job_id : usize, title : &str, materials : Vec<MaterialUsage>) -> Event {
+        Event { job_id: Some(job_id), title: Some(title.to_string()), materials: Some(materials), ..Event::new("job_done", part) }
+    }
+
+    pub fn job_failed(part : usize, job_id : usize, title : &str, reason : &str, materials : Vec<MaterialUsage>) -> Event {
+        Event { job_id: Some(job_id), title: Some(title.to_string()), reason: Some(reason.to_string()),
+            materials: Some(materials), ..Event::new("job_failed", part) }
+    }
+
+    pub fn quality_report(part : usize, report : &QualityReport) -> Event {
+        Event { job_id: Some(report.job_id), quality: Some(report.clone()), ..Event::new("quality_report", part) }
+    }
+
+    pub fn material_low(part : usize, material : i32) -> Event {
+        Event { material: Some(material), ..Event::new("material_low", part) }
+    }
+
+    pub fn material_refilled(part : usize, material : i32) -> Event {
+        Event { material: Some(material), ..Event::new("material_refilled", part) }
+    }
+
+    pub fn part_connected(part : usize, part_type : &str) -> Event {
+        Event { part_type: Some(part_type.to_string()), ..Event::new("part_connected", part) }
+    }
+
+    pub fn part_health(part : usize, part_type : &str, healthy : bool) -> Event {
+        let event = if healthy { "part_healthy" } else { "part_unhealthy" };
+        Event { part_type: Some(part_type.to_string()), ..Event::new(event, part) }
+    }
+
+    pub fn sensor_alarm(part : usize, quantity : &'static str, value : f64, outside : bool) -> Event {
+        let event = if outside { "sensor_alarm" } else { "sensor_ok" };
+        Event { quantity: Some(quantity), value: Some(value), ..Event::new(event, part) }
+    }
+
+    //A counter of the part has reached its service interval
+    pub fn service_due(part : usize, serial : u32, counter : &'static str, value : f64) -> Event {
+        Event { serial: Some(serial), quantity: Some(counter), value: Some(value), ..Event::new("service_due", part) }
+    }
+
+    //Affects the whole panel, the reason is who triggered it
+    pub fn emergency_stop(triggered_by : Option<&str>, active : bool) -> Event {
+        let event = if active { "emergency_stop" } else { "emergency_stop_reset" };
+        Event { part: None, reason: triggered_by.map(|by| by.to_string()), ..Event::new(event, 0) }
+    }
+
+    pub fn part_disconnected(part : usize, part_type : &str, job_id : Option<usize>) -> Event {
+        Event { part_type: Some(part_type.to_string()), job_id: job_id, ..Event::new("part_disconnected", part) }
+    }
+
+    //Free text the panel used to publish on printInfo
+    fn legacy_text(&self) -> Option<String> {
+        let title = self.title.clone().unwrap_or("-".to_string());
+        match self.event {
+            "job_started" => Some(format!("Started printing {}", title)),
+            "job_done" => Some(format!("Done [last: {}]", title)),
+            "job_failed" => Some(title),
+            _ => None
+        }
+    }
+}
+
+pub struct Events {
+    client: AsyncClient,
+    topic: String,
+    reply_topic: String,
+    telemetry_topic: String,
+    presence_topic: String,
+    offline: String,
+    streams: SharedEventStreams, //Clients of the REST event stream
+    legacy_printinfo: bool
+}
+
+impl Events {
+    //Connects with a last will marking the panel offline, then announces it as online
+    pub fn connect(broker_addr : &str, config : &Config, streams : SharedEventStreams) -> Events {
+        let presence_topic = printer_topic(config, "presence");
+        let offline = presence(config, false);
+
+        let mut connection_options = AsyncConnectOptions::new();
+        connection_options.set_will(&presence_topic, offline.as_bytes(), Qos::OnceAndOneOnly, true);
+        let mut client = AsyncClient::new(broker_addr, &format!("printer_{}", config.printer_id), PersistenceType::Nothing, None)
+            .expect("Cannot create MQTT client!");
+        client.connect(&connection_options).expect("Cannot connect to MQTT broker!");
+        if client.send(presence(config, true).as_bytes(), &presence_topic, Qos::OnceAndOneOnly, true).is_err() {
+            println!("Cannot announce panel on {}", presence_topic);
+        }
+
+        Events {
+            client: client,
+            topic: printer_topic(config, "events"),
+            reply_topic: printer_topic(config, "responses"),
+            telemetry_topic: printer_topic(config, "telemetry"),
+            presence_topic: presence_topic,
+            offline: offline,
+            streams: streams,
+            legacy_printinfo: config.legacy_printinfo
+        }
+    }
+
+    //Regular shutdown, the last will is only sent if the connection is lost
+    pub fn go_offline(&mut self) {
+        let _ = self.client.send(self.offline.as_bytes(), &self.presence_topic, Qos::OnceAndOneOnly, true);
+    }
+
+    pub fn publish(&mut self, event : Event) {
+        let data = json::encode(&event).unwrap();
+        let _ = self.client.send(data.as_bytes(), &self.topic, Qos::OnceAndOneOnly, false);
+        self.streams.broadcast(event.event, &data);
+        if !self.legacy_printinfo {
+            return;
+        }
+        if let Some(text) = event.legacy_text() {
+            let _ = self.client.send(text.as_bytes(), "printInfo", Qos::OnceAndOneOnly, false);
+        }
+    }
+
+    //Only on the event stream, MQTT clients can ask with a status command
+    pub fn stream_status(&mut self, status : &Status) {
+        self.streams.broadcast("status", &json::encode(status).unwrap());
+    }
+
+    pub fn telemetry(&mut self, sample : &Sample) {
+        let _ = self.client.send(json::encode(sample).unwrap().as_bytes(), &self.telemetry_topic, Qos::OnceAndOneOnly, false);
+    }
+
+    pub fn reply(&mut self, reply : &RemoteReply) {
+        let _ = self.client.send(json::encode(reply).unwrap().as_bytes(), &self.reply_topic, Qos::OnceAndOneOnly, false);
+    }
+}
@@ -0,0 +1,160 @@
+//Blueprint format: "RBAM" magic, followed by commands (1 byte id + little endian i32 params)
+
+pub const MAGIC : &'static [u8; 4] = b"RBAM";
+
+//Heartbeat on part connections, printheads and material containers answer with the same byte
+pub const HEARTBEAT : u8 = 0;
+
+//Sent by the panel on tool changes, never part of a blueprint: material id and purge amount
+pub const PURGE : u8 = 4;
+
+//Sent by the panel on an emergency stop, the printhead stops moving and does not answer
+pub const STOP : u8 = 6;
+
+//Sent by the panel to pipelined printheads: drop all buffered commands, answered with the same byte.
+//After a failed command a pipelined printhead drops every command until it is flushed.
+pub const FLUSH : u8 = 7;
+
+//Registration of parts: 1 for printheads, 2 + material id (0-253) for material containers.
+//Other parts send EXTENDED_HANDSHAKE, the handshake version and their kind, followed by the serial like all parts.
+//Printheads registering this way send a byte of capability flags after their kind.
+pub const EXTENDED_HANDSHAKE : u8 = 0;
+pub const HANDSHAKE_VERSION : u8 = 1;
+pub const KIND_PRINTHEAD : u8 = 1;
+pub const KIND_SENSOR : u8 = 2;
+pub const KIND_HEATER : u8 = 3;
+
+//Capability of printheads with a command buffer, they send its depth after the serial.
+//Their commands carry a sequence number after the id and are answered with result and sequence number.
+pub const CAPABILITY_PIPELINED : u8 = 1;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Command {
+    Level { z: i32, matid: u8 },
+    Dot { x: i32, y: i32 },
+    Line { x1: i32, y1: i32, x2: i32, y2: i32 },
+    Temperature { target: i32, zone: u8 } //Tenths of a degree, zone 1 is the bed, 2 the chamber
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Copy, Clone)]
+pub struct BuildVolume {
+    pub min_x: i32,
+    pub min_y: i32,
+    pub min_z: i32,
+    pub max_x: i32,
+    pub max_y: i32,
+    pub max_z: i32
+}
+
+pub fn read_i32(buf : &[u8]) -> i32 {
+    (buf[0] as i32) | ((buf[1] as i32) << 8) | ((buf[2] as i32) << 16) | ((buf[3] as i32) << 24)
+}
+
+//Number of parameter bytes following the command id
+pub fn param_len(commandid : u8) -> Option<usize> {
+    match commandid {
+        1 => Some(5),  //Choose level & mat, 4+1=5byte params
+        2 => Some(8),  //Print dot, 2*4=8 byte params
+        3 => Some(16), //Print line, 4*4=16byte params
+        5 => Some(5),  //Set temperature & wait, 4+1=5byte params
+        _ => None
+    }
+}
+
+//Number of parameter bytes of all commands a printhead understands
+pub fn printhead_param_len(commandid : u8) -> Option<usize> {
+    match commandid {
+        PURGE => Some(2),
+        5 => None, //Temperatures are set by the panel
+        _ => param_len(commandid)
+    }
+}
+
+pub fn command_name(commandid : u8) -> &'static str {
+    match commandid {
+        1 => "level",
+        2 => "dot",
+        3 => "line",
+        PURGE => "purge",
+        STOP => "stop",
+        5 => "temperature",
+        _ => "unknown"
+    }
+}
+
+pub fn command_id(name : &str) -> Option<u8> {
+    match name {
+        "level" => Some(1),
+        "dot" => Some(2),
+        "line" => Some(3),
+        _ => None
+    }
+}
+
+impl Command {
+    //Executed by the panel itself instead of being sent to the printhead
+    pub fn panel_only(&self) -> bool {
+        match *self {
+            Command::Temperature { .. } => true,
+            _ => false
+        }
+    }
+
+    pub fn decode(commandid : u8, params : &[u8]) -> Command {
+        match commandid {
+            1 => Command::Level { z: read_i32(&params[0..4]), matid: params[4] },
+            2 => Command::Dot { x: read_i32(&params[0..4]), y: read_i32(&params[4..8]) },
+            3 => Command::Line { x1: read_i32(&params[0..4]), y1: read_i32(&params[4..8]),
+                                 x2: read_i32(&params[8..12]), y2: read_i32(&params[12..16]) },
+            5 => Command::Temperature { target: read_i32(&params[0..4]), zone: params[4] },
+            c => panic!("Unknown blueprint command {:#x}", c)
+        }
+    }
+}
+
+//Splits a complete blueprint into its commands, together with the offset each command starts at
+pub fn parse(data : &[u8]) -> Result<Vec<(usize, Command)>, String> {
+    if data.len() < MAGIC.len() || &data[0..MAGIC.len()] != MAGIC {
+        return Err("invalid blueprint".to_string());
+    }
+    let mut result = Vec::new();
+    let mut pos = MAGIC.len();
+    while pos < data.len() {
+        let len = match param_len(data[pos]) {
+            Some(len) => len,
+            None => return Err(format!("unknown command {:#x} at offset {}", data[pos], pos))
+        };
+        if pos + 1 + len > data.len() {
+            return Err(format!("truncated command at offset {}", pos));
+        }
+        result.push( (pos, Command::decode(data[pos], &data[pos + 1 .. pos + 1 + len])) );
+        pos += 1 + len;
+    }
+    Ok(result)
+}
+
+impl BuildVolume {
+    fn contains_xy(&self, x : i32, y : i32) -> bool {
+        x >= self.min_x && x <= self.max_x && y >= self.min_y && y <= self.max_y
+    }
+
+    pub fn contains(&self, command : &Command) -> bool {
+        match *command {
+            Command::Level { z, .. } => z >= self.min_z && z <= self.max_z,
+            Command::Dot { x, y } => self.contains_xy(x, y),
+            //The volume is a box, so a line is inside if both ends are
+            Command::Line { x1, y1, x2, y2 } => self.contains_xy(x1, y1) && self.contains_xy(x2, y2),
+            Command::Temperature { .. } => true
+        }
+    }
+
+    //Returns a description of the first command outside the volume
+    pub fn check_blueprint(&self, data : &[u8]) -> Result<(), String> {
+        for (index, &(offset, command)) in try!(parse(data)).iter().enumerate() {
+            if !self.contains(&command) {
+                return Err(format!("command {} at offset {} outside build volume: {:?}", index, offset, command));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use mio::tcp::TcpStream;
+use mio::TryRead;
+
+use super::PrinterPartType;
+use super::blueprint;
+use events::now_ms;
+
+//A connected part that has not completely registered yet, read on every readable event
+pub struct Handshake {
+    pub socket: TcpStream,
+    pub connected_at: i64,
+    buf: Vec<u8>
+}
+
+pub struct Registration {
+    pub parttype: PrinterPartType,
+    pub serial: u32,
+    pub matid: i32, //-1 for parts other than material containers
+    pub pipelined: bool,
+    pub window: usize //Buffer depth of pipelined printheads, 1 for the others
+}
+
+impl Handshake {
+    pub fn new(socket : TcpStream) -> Handshake {
+        Handshake { socket: socket, connected_at: now_ms(), buf: Vec::new() }
+    }
+
+    //Ok(None) until the registration is complete, Err if the part has to be disconnected.
+    //Reads byte by byte, so everything the part sends after registering is left to its notify.
+    pub fn read(self : &mut Self) -> Result<Option<Registration>, String> {
+        loop {
+            let mut byte = [0];
+            match self.socket.try_read(&mut byte) {
+                Err(e) => return Err(format!("read failed: {}", e)),
+                Ok(Some(0)) => return Err("connection closed".to_string()),
+                Ok(None) => return Ok(None), //Rest arrives with the next readable event
+                Ok(Some(_)) => self.buf.push(byte[0])
+            }
+            if let Some(registration) = try!(parse(&self.buf)) {
+                return Ok(Some(registration));
+            }
+        }
+    }
+}
+
+//Ok(None) while bytes are missing
+fn parse(buf : &[u8]) -> Result<Option<Registration>, String> {
+    let (parttype, capabilities, header) = match buf[0] {
+        blueprint::EXTENDED_HANDSHAKE => {
+            if buf.len() < 3 {
+                return Ok(None);
+            }
+            if buf[1] != blueprint::HANDSHAKE_VERSION {
+                return Err(format!("unsupported handshake version {}", buf[1]));
+            }
+            match buf[2] {
+                blueprint::KIND_PRINTHEAD => {
+                    if buf.len() < 4 {
+                        return Ok(None);
+                    }
+                    (PrinterPartType::Printhead, buf[3], 4)
+                },
+                blueprint::KIND_SENSOR => (PrinterPartType::Sensor, 0, 3),
+                blueprint::KIND_HEATER => (PrinterPartType::Heater, 0, 3),
+                kind => return Err(format!("unknown part kind {}", kind))
+            }
+        },
+        1 => (PrinterPartType::Printhead, 0, 1),
+        _ => (PrinterPartType::Material, 0, 1)
+    };
+    if capabilities & !blueprint::CAPABILITY_PIPELINED != 0 {
+        return Err(format!("unknown printhead capabilities {:#x}", capabilities));
+    }
+    let pipelined = capabilities & blueprint::CAPABILITY_PIPELINED != 0;
+    //Type is followed by the stable 4 byte serial of the part, printheads with a command buffer add its depth
+    let len = header + 4 + if pipelined { 1 } else { 0 };
+    if buf.len() < len {
+        return Ok(None);
+    }
+    if pipelined && buf[header + 4] == 0 {
+        return Err("pipelined printhead without a command buffer".to_string());
+    }
+    let serial = (buf[header] as u32) | ((buf[header + 1] as u32) << 8) |
+        ((buf[header + 2] as u32) << 16) | ((buf[header + 3] as u32) << 24);
+    Ok(Some(Registration {
+        parttype: parttype,
+        serial: serial,
+        matid: if parttype == PrinterPartType::Material { (buf[0] as i32) - 2 } else { -1 },
+        pipelined: pipelined,
+        window: if pipelined { buf[header + 4] as usize } else { 1 }
+    }))
+}
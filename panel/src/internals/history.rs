@@ -0,0 +1,146 @@
+use std::io::{BufRead, BufReader, Write};
+use std::cmp;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use rustc_serialize::json;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use super::MaterialUsage;
+use super::journal::SPOOL_DIR;
+use super::skip_job_ids;
+
+//One JSON entry per line, appended whenever a job has finished, was interrupted or discarded.
+//Once it has twice as many lines as entries are kept, it is rewritten with the kept ones.
+const HISTORY_FILE : &'static str = "spool/history.jsonl";
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct HistoryEntry {
+    pub job_id: usize,
+    pub title: String,
+    pub blueprint_hash: Option<String>, //Hex SHA-256, None if the spooled blueprint was missing
+    pub printhead: Option<usize>, //None if the job was discarded while its printhead was disconnected
+    pub serial: u32, //Of a split job the printhead that finished last
+    pub shards: Option<usize>, //Printheads a split job was printed on
+    pub started_at: i64, //Milliseconds since the epoch
+    pub finished_at: i64,
+    pub outcome: String, //done, cancelled, failed, interrupted or discarded
+    pub reason: Option<String>, //Why the job did not finish
+    pub materials: Vec<MaterialUsage>,
+    pub commands: usize //Acknowledged by the printhead
+}
+
+pub struct History {
+    pub entries: Vec<HistoryEntry>, //Oldest first
+    lines: usize, //Written to the file
+    limit: usize
+}
+
+//Filter of GET /jobs, all optional
+#[derive(Clone)]
+pub struct HistoryQuery {
+    pub from: Option<i64>, //Finished at or after, milliseconds since the epoch
+    pub to: Option<i64>,   //Finished before
+    pub outcome: Option<String>
+}
+
+impl HistoryQuery {
+    //Query string of the request, e.g. from=1480000000000&outcome=failed
+    pub fn parse(query : &str) -> Result<HistoryQuery, String> {
+        let mut result = HistoryQuery { from: None, to: None, outcome: None };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(pos) => (&pair[.. pos], &pair[pos + 1 ..]),
+                None => return Err(format!("missing value for '{}'", pair))
+            };
+            match key {
+                "from" => result.from = Some( try!( value.parse().map_err(|_| "from has to be milliseconds since the epoch".to_string()) ) ),
+                "to" => result.to = Some( try!( value.parse().map_err(|_| "to has to be milliseconds since the epoch".to_string()) ) ),
+                "outcome" => match value {
+                    "done" | "cancelled" | "failed" | "interrupted" | "discarded" => result.outcome = Some(value.to_string()),
+                    other => return Err(format!("unknown outcome '{}'", other))
+                },
+                other => return Err(format!("unknown parameter '{}'", other))
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn matches(&self, entry : &HistoryEntry) -> bool {
+        self.from.map(|from| entry.finished_at >= from).unwrap_or(true)
+            && self.to.map(|to| entry.finished_at < to).unwrap_or(true)
+            && self.outcome.as_ref().map(|outcome| *outcome == entry.outcome).unwrap_or(true)
+    }
+}
+
+pub fn outcome(failure : Option<&str>) -> &'static str {
+    match failure {
+        None => "done",
+        Some("cancelled") => "cancelled",
+        Some(_) => "failed"
+    }
+}
+
+pub fn blueprint_hash(blueprint : &[u8]) -> String {
+    let mut sha = Sha256::new();
+    sha.input(blueprint);
+    sha.result_str()
+}
+
+//Reads the history written by earlier panel runs, keeps the latest limit entries
+pub fn load(limit : usize) -> History {
+    let mut entries = Vec::new();
+    if Path::new(HISTORY_FILE).exists() {
+        let file = BufReader::new( File::open(HISTORY_FILE).expect("Cannot open job history!") );
+        for line in file.lines() {
+            let line = line.expect("Cannot read job history!");
+            match json::decode::<HistoryEntry>(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => println!("Skipping invalid job history entry: {}", e) //A crash may leave a half written line
+            }
+        }
+    }
+    //Job ids stay unique across restarts
+    if let Some(last) = entries.iter().map(|entry| entry.job_id).max() {
+        skip_job_ids(last + 1);
+    }
+    let lines = entries.len();
+    let mut history = History { entries: entries, lines: lines, limit: limit };
+    history.trim();
+    history
+}
+
+impl History {
+    pub fn record(&mut self, entry : HistoryEntry) {
+        let line = json::encode(&entry).unwrap() + "\n";
+        let written = fs::create_dir_all(SPOOL_DIR)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(HISTORY_FILE))
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        match written {
+            Ok(()) => self.lines += 1,
+            Err(e) => println!("Cannot write job history: {}", e)
+        }
+        self.entries.push(entry);
+        self.trim();
+    }
+
+    //Drops the oldest entries beyond the limit, the file is rewritten once it has grown to twice the limit
+    fn trim(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.limit);
+        self.entries.drain(.. excess);
+        if self.lines < cmp::max(self.limit, 1) * 2 {
+            return;
+        }
+        //Same as the job journal, a crash never leaves a half written file
+        let text : String = self.entries.iter().map(|entry| json::encode(entry).unwrap() + "\n").collect();
+        let tmp_path = format!("{}.tmp", HISTORY_FILE);
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .and_then(|_| fs::rename(&tmp_path, HISTORY_FILE));
+        match result {
+            Ok(()) => self.lines = self.entries.len(),
+            Err(e) => println!("Cannot rewrite job history: {}", e)
+        }
+    }
+}
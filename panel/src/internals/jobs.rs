@@ -0,0 +1,202 @@
+use std::mem;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use super::{Printerpart, MaterialUsage};
+use super::Journal;
+use super::journal;
+use super::history;
+use super::history::{HistoryEntry, History};
+use config::Config;
+use vbed::VirtualBed;
+use vbed::quality;
+use vbed::quality::QualityReport;
+use events::{Event, now_ms};
+use metrics;
+
+const MAX_FINISHED_JOBS : usize = 50;
+
+pub struct FinishedJob {
+    pub job_id: usize,
+    pub title: String,
+    pub printhead: usize,
+    pub bed: VirtualBed,
+    pub materials: Vec<MaterialUsage>,
+    pub quality: Option<QualityReport>
+}
+
+pub type FinishedJobs = HashMap<usize, FinishedJob>;
+
+fn spooled_hash(job_id : usize) -> Option<String> {
+    let mut bp = vec![0;0];
+    File::open(journal::spool_path(job_id)).and_then(|mut file| file.read_to_end(&mut bp)).ok()
+        .map(|_| history::blueprint_hash(&bp))
+}
+
+//Keeps track of running jobs (journal) and the results of finished ones
+pub struct Jobs {
+    pub journal: Journal,
+    pub finished: FinishedJobs,
+    pub history: History,
+    config: Arc<Config>
+}
+
+impl Jobs {
+    pub fn new(journal : Journal, history : History, config : Arc<Config>) -> Jobs {
+        Jobs {
+            journal: journal,
+            finished: HashMap::new(),
+            history: history,
+            config: config
+        }
+    }
+
+    //Has to be called whenever a printhead made progress on its job
+    pub fn update(&mut self, part : &mut Printerpart) {
+        self.journal.sync(part);
+        if part.blueprint.is_some() {
+            return;
+        }
+        let job_id = match part.job_id.take() {
+            Some(job_id) => job_id,
+            None => return //No job or already finished
+        };
+
+        part.wear.jobs += 1;
+        if part.job_failure.is_some() {
+            part.wear.failures += 1;
+        }
+        let title = part.job_title.clone().unwrap_or("--".to_string());
+        let mut bp = vec![0;0];
+        let spooled = match File::open(journal::spool_path(job_id)).and_then(|mut file| file.read_to_end(&mut bp)) {
+            Ok(_) => Some(&bp[..]),
+            Err(e) => {
+                println!("Blueprint of job #{} not available: {}", job_id, e);
+                None
+            }
+        };
+
+        let shard = part.shard.take();
+        let entry = match shard {
+            //Shards of a split job are reported together, once the last one has finished
+            Some(ref shard) => shard.finished(part.job_failure.as_ref().map(|reason| &reason[..]), &part.mat_usage, part.stats.acked_commands)
+                .map(|report| HistoryEntry {
+                    job_id: report.job_id,
+                    title: report.title,
+                    blueprint_hash: Some(report.blueprint_hash),
+                    printhead: Some(part.id),
+                    serial: part.serial,
+                    shards: Some(report.shards),
+                    started_at: report.started_at,
+                    finished_at: now_ms(),
+                    outcome: history::outcome(report.failure.as_ref().map(|reason| &reason[..])).to_string(),
+                    reason: report.failure,
+                    materials: report.materials,
+                    commands: report.commands
+                }),
+            None => Some( HistoryEntry {
+                job_id: job_id,
+                title: title.clone(),
+                blueprint_hash: spooled.map(history::blueprint_hash),
+                printhead: Some(part.id),
+                serial: part.serial,
+                shards: None,
+                started_at: part.job_started_at,
+                finished_at: now_ms(),
+                outcome: history::outcome(part.job_failure.as_ref().map(|reason| &reason[..])).to_string(),
+                reason: part.job_failure.clone(),
+                materials: part.mat_usage.clone(),
+                commands: part.stats.acked_commands
+            } )
+        };
+        if let Some(entry) = entry {
+            let event = match entry.reason {
+                Some(ref reason) => Event::job_failed(part.id, entry.job_id, &entry.title, reason, entry.materials.clone()),
+                None => Event::job_done(part.id, entry.job_id, &entry.title, entry.materials.clone())
+            };
+            part.events.push(event);
+            metrics::job_finished(entry.reason.is_some());
+            self.history.record(entry);
+        }
+
+        let quality = spooled.and_then(|bp| self.inspect(job_id, bp, part));
+        if let Some(ref report) = quality {
+            part.events.push( Event::quality_report(part.id, report) );
+        }
+        let _ = fs::remove_file(journal::spool_path(job_id));
+
+        if self.finished.len() >= MAX_FINISHED_JOBS {
+            let oldest = *self.finished.keys().min().unwrap();
+            self.finished.remove(&oldest);
+        }
+        self.finished.insert(job_id, FinishedJob {
+            job_id: job_id,
+            title: title,
+            printhead: part.id,
+            bed: mem::replace(&mut part.bed, VirtualBed::new()),
+            materials: part.mat_usage.clone(),
+            quality: quality
+        });
+    }
+
+    //The printhead has disconnected, its job is journaled to be resumed and recorded as interrupted
+    pub fn interrupt(&mut self, part : &Printerpart) {
+        let entry = match self.journal.interrupt(part) {
+            Some(entry) => entry,
+            None => return
+        };
+        self.history.record(HistoryEntry {
+            job_id: entry.job_id,
+            title: entry.title,
+            blueprint_hash: spooled_hash(entry.job_id),
+            printhead: Some(part.id),
+            serial: part.serial,
+            shards: None,
+            started_at: part.job_started_at,
+            finished_at: now_ms(),
+            outcome: "interrupted".to_string(),
+            reason: Some("printhead disconnected".to_string()),
+            materials: part.mat_usage.clone(),
+            commands: part.stats.acked_commands
+        });
+    }
+
+    //Interrupted jobs that will not be resumed, recorded as discarded
+    pub fn discard_interrupted(&mut self) {
+        for entry in self.journal.discard_interrupted() {
+            self.history.record(HistoryEntry {
+                job_id: entry.job_id,
+                title: entry.title,
+                blueprint_hash: spooled_hash(entry.job_id),
+                printhead: None,
+                serial: entry.serial,
+                shards: None,
+                started_at: entry.started_at.unwrap_or(0),
+                finished_at: now_ms(),
+                outcome: "discarded".to_string(),
+                reason: Some("discarded".to_string()),
+                materials: Vec::new(),
+                commands: 0 //Not known across panel runs
+            });
+            let _ = fs::remove_file(journal::spool_path(entry.job_id));
+        }
+    }
+
+    //Post-print inspection against the spooled blueprint
+    fn inspect(&self, job_id : usize, bp : &[u8], part : &Printerpart) -> Option<QualityReport> {
+        match quality::inspect(job_id, bp, &part.bed, &part.stats, self.config.voxel_size, self.config.qc_max_deviation) {
+            Ok(report) => {
+                println!("Quality check of job #{}: {} (deviation {:.3}, {} skipped commands)", job_id,
+                    if report.passed { "passed" } else { "FAILED" }, report.deviation, report.skipped_commands);
+                Some(report)
+            },
+            Err(e) => {
+                println!("Quality check of job #{} failed: {}", job_id, e);
+                None
+            }
+        }
+    }
+}
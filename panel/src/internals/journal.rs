@@ -0,0 +1,180 @@
+use std::io::{Read, Write};
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::collections::HashMap;
+use rustc_serialize::json;
+
+use super::Printerpart;
+use super::skip_job_ids;
+use events::now_ms;
+
+pub const SPOOL_DIR : &'static str = "spool";
+const JOURNAL_FILE : &'static str = "spool/journal.json";
+const SAVE_INTERVAL_MS : i64 = 1000; //Progress lost on a crash is at most this old, those commands are printed again on resume
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct JournalEntry {
+    pub job_id: usize,
+    pub title: String,
+    pub serial: u32,     //Printhead the job was running on
+    pub offset: u64,     //Blueprint offset after the last acknowledged command
+    pub matid: i32,
+    pub started_at: Option<i64>, //Missing in journals of older panels
+    pub level: Option<Vec<u8>> //Last level command, has to be repeated before resuming
+}
+
+pub struct Journal {
+    active: HashMap<usize, JournalEntry>,
+    pub interrupted: Vec<JournalEntry>,
+    dirty: bool,
+    saved_at: i64
+}
+
+pub fn spool_path(job_id : usize) -> String {
+    format!("{}/{}.3dbp", SPOOL_DIR, job_id)
+}
+
+//Writes the blueprint to disk, so the job survives a panel restart
+pub fn spool_blueprint(job_id : usize, blueprint : &[u8]) -> Result<File, String> {
+    try!( fs::create_dir_all(SPOOL_DIR).map_err(|e| format!("Cannot create spool dir: {}", e)) );
+    let path = spool_path(job_id);
+    {
+        let mut file = try!( File::create(&path).map_err(|e| format!("Cannot spool blueprint: {}", e)) );
+        try!( file.write_all(blueprint).map_err(|e| format!("Cannot spool blueprint: {}", e)) );
+    }
+    File::open(&path).map_err(|e| format!("Cannot open spooled blueprint: {}", e))
+}
+
+impl Journal {
+    pub fn load() -> Journal {
+        let mut journal = Journal {
+            active: HashMap::new(),
+            interrupted: Vec::new(),
+            dirty: false,
+            saved_at: now_ms()
+        };
+        if ! Path::new(JOURNAL_FILE).exists() {
+            return journal;
+        }
+
+        let mut text = String::new();
+        File::open(JOURNAL_FILE).expect("Cannot open job journal!")
+            .read_to_string(&mut text).expect("Cannot read job journal!");
+        journal.interrupted = json::decode(&text).expect("Invalid job journal!");
+
+        //Don't reuse ids of jobs that may still be resumed
+        let next_id = journal.interrupted.iter().map(|entry| entry.job_id + 1).max().unwrap_or(0);
+        skip_job_ids(next_id);
+
+        for entry in journal.interrupted.iter() {
+            println!("Interrupted job #{} '{}' on printhead serial {} at offset {}",
+                entry.job_id, entry.title, entry.serial, entry.offset);
+        }
+        journal
+    }
+
+    fn save(&mut self) {
+        self.saved_at = now_ms();
+        self.dirty = false;
+        let mut entries : Vec<&JournalEntry> = self.active.values().collect();
+        entries.extend(self.interrupted.iter());
+
+        if let Err(e) = fs::create_dir_all(SPOOL_DIR) {
+            println!("Cannot create spool dir: {}", e);
+            return;
+        }
+        //Write to a temporary file first, so a crash never leaves a half written journal
+        let tmp_path = format!("{}.tmp", JOURNAL_FILE);
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(json::encode(&entries).unwrap().as_bytes()))
+            .and_then(|_| fs::rename(&tmp_path, JOURNAL_FILE));
+        if let Err(e) = result {
+            println!("Cannot write job journal: {}", e);
+        }
+    }
+
+    //Writes the progress of the running jobs if anything has changed
+    pub fn flush(&mut self) {
+        if self.dirty {
+            self.save();
+        }
+    }
+
+    //Records the current state of the printheads job, must be called after every acknowledged command.
+    //Progress is written at most every SAVE_INTERVAL_MS, starting and ending jobs right away.
+    //Shards of split jobs are left out, they cannot be resumed without the other printheads.
+    pub fn sync(&mut self, part : &Printerpart) {
+        let job_id = match part.job_id {
+            Some(job_id) if part.shard.is_none() => job_id,
+            _ => return
+        };
+        if part.blueprint.is_none() { //Job has finished or was aborted, spooled blueprint is removed by the caller
+            if self.active.remove(&job_id).is_some() {
+                self.save();
+            }
+            return;
+        }
+
+        let started = match self.active.get(&job_id) {
+            Some(entry) if entry.offset == part.acked_offset && entry.matid == part.acked_matid() => return, //Nothing new to write
+            Some(_) => false,
+            None => true
+        };
+        self.active.insert(job_id, JournalEntry {
+            job_id: job_id,
+            title: part.job_title.clone().unwrap_or("--".to_string()),
+            serial: part.serial,
+            offset: part.acked_offset,
+            matid: part.acked_matid(),
+            started_at: Some(part.job_started_at),
+            level: part.last_level.clone()
+        });
+        self.dirty = true;
+        if started || now_ms() - self.saved_at >= SAVE_INTERVAL_MS {
+            self.save();
+        }
+    }
+
+    //The printhead has disconnected, its job can be resumed once it is back
+    pub fn interrupt(&mut self, part : &Printerpart) -> Option<JournalEntry> {
+        let job_id = match part.job_id {
+            Some(job_id) => job_id,
+            None => return None
+        };
+        let entry = match self.active.remove(&job_id) {
+            Some(entry) => entry,
+            None => return None
+        };
+        println!("Job #{} '{}' interrupted at offset {}", entry.job_id, entry.title, entry.offset);
+        self.interrupted.push(entry.clone());
+        self.save();
+        Some(entry)
+    }
+
+    pub fn has_interrupted(&self, serial : u32) -> bool {
+        self.interrupted.iter().any(|entry| entry.serial == serial)
+    }
+
+    pub fn take_interrupted(&mut self, serial : u32) -> Option<JournalEntry> {
+        match self.interrupted.iter().position(|entry| entry.serial == serial) {
+            Some(pos) => Some(self.interrupted.remove(pos)), //Will be journaled as active by the next sync
+            None => None
+        }
+    }
+
+    //The job could not be resumed, it stays in the journal to be retried or discarded
+    pub fn restore_interrupted(&mut self, entry : JournalEntry) {
+        self.interrupted.push(entry);
+    }
+
+    //Their spooled blueprints have to be removed by the caller
+    pub fn discard_interrupted(&mut self) -> Vec<JournalEntry> {
+        let discarded : Vec<JournalEntry> = self.interrupted.drain(..).collect();
+        for entry in &discarded {
+            println!("Discarding interrupted job #{} '{}'", entry.job_id, entry.title);
+        }
+        self.save();
+        discarded
+    }
+}
@@ -1,8 +1,45 @@
 mod server;
 mod printerpart;
+mod handshake;
+pub mod journal;
+pub mod history;
+pub mod blueprint;
+pub mod jobs;
+pub mod split;
 
 pub use self::server::Server;
 pub use self::printerpart::PrinterPartType;
 pub use self::printerpart::Printerpart;
+pub use self::printerpart::JobStats;
+pub use self::printerpart::PartCounters;
+pub use self::printerpart::MaterialUsage;
+pub use self::journal::Journal;
+pub use self::jobs::Jobs;
 
-static mut BenchWatchStopTime : u64 = 0;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use mio::Token;
+
+//Only the event loop has access to the parts, other threads send it a messages::Message
+pub type PartCell = Rc<RefCell<Printerpart>>;
+pub type Parts = HashMap<Token, PartCell>;
+
+static JOB_ID_COUNTER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub fn get_new_job_id() -> usize {
+    JOB_ID_COUNTER.fetch_add(1, Ordering::SeqCst)
+}
+
+//Ids below next are used by jobs of earlier panel runs
+pub fn skip_job_ids(next : usize) {
+    let mut current = JOB_ID_COUNTER.load(Ordering::SeqCst);
+    while current < next {
+        let previous = JOB_ID_COUNTER.compare_and_swap(current, next, Ordering::SeqCst);
+        if previous == current {
+            break;
+        }
+        current = previous;
+    }
+}
@@ -1,142 +1,654 @@
 use super::super::time;
 
-use std::io::{Read, Write};
+use std::io::{Read, Write, Seek, SeekFrom, Cursor};
+use std::collections::VecDeque;
+use std::cmp;
 use std::fs::File;
 use std::time::Duration;
 use mio::tcp::TcpStream;
 use mio::{Timeout, TryRead, EventLoop};
 
 use super::Server;
+use super::journal;
+use super::journal::JournalEntry;
+use super::blueprint;
+use super::blueprint::{Command, BuildVolume};
+use super::split::Shard;
+use super::handshake::Registration;
+use vbed::VirtualBed;
+use events::{Event, PROGRESS_STEP_PERCENT, now_ms};
+use latency;
+use metrics;
+use latency::CommandLatencies;
+use telemetry::{Telemetry, SensorLimit, Sample, Quantity, SET_TARGET};
+use maintenance::Wear;
+use benchmark::{BenchmarkSpec, BenchmarkRun, BenchmarkReport};
+use super::get_new_job_id;
 use super::super::PRINT_TIMEOUT_MS;
 use super::super::CONTINUE_DELAY_MS;
-use super::BenchWatchStopTime;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum PrinterPartType {
     Printhead,
-    Material
+    Material,
+    Sensor,
+    Heater
+}
+
+//What happened while printing the current job, used for the quality check
+#[derive(Debug, Clone, Copy)]
+pub struct JobStats {
+    pub from_offset: u64, //Commands before this offset were not printed by this panel run
+    pub acked_commands: usize,
+    pub retries: u32,
+    pub substitutions: u32,
+    pub tool_changes: u32
+}
+
+impl JobStats {
+    pub fn new(from_offset : u64) -> JobStats {
+        JobStats { from_offset: from_offset, acked_commands: 0, retries: 0, substitutions: 0, tool_changes: 0 }
+    }
+}
+
+//Material a job has drawn from the containers, by material id
+#[derive(Debug, Clone, Copy, RustcEncodable, RustcDecodable)]
+pub struct MaterialUsage {
+    pub material: i32,
+    pub printed: u64,
+    pub purged: u64 //Wasted on tool changes
+}
+
+pub fn add_usage(total : &mut Vec<MaterialUsage>, usage : MaterialUsage) {
+    match total.iter().position(|entry| entry.material == usage.material) {
+        Some(index) => {
+            total[index].printed += usage.printed;
+            total[index].purged += usage.purged;
+        },
+        None => {
+            total.push(usage);
+            total.sort_by_key(|entry| entry.material);
+        }
+    }
+}
+
+//Job waits until the heater of the zone is close enough to the target
+#[derive(Debug, Clone, Copy)]
+pub struct Heating {
+    pub zone: Quantity,
+    pub target: f64, //Celsius
+    pub missing_since: Option<i64> //No heater of the zone has been connected since then
+}
+
+//Switch to another material in the middle of a job, the level command selecting it is held back
+//until the operator has confirmed the change and the printhead has purged
+pub struct ToolChange {
+    pub to: i32,
+    pub level: Vec<u8>,
+    pub confirmed: bool,
+    pub purged: bool
+}
+
+//Command sent to a printhead and not yet answered
+pub struct InFlight {
+    pub seq: u8,
+    pub raw: Vec<u8>, //Without the sequence number
+    pub command: Option<(Command, i32)>, //Blueprint command with the material actually used, None for purges and benchmarks
+    pub offset: u64, //Blueprint offset after the command
+    pub usage: Option<MaterialUsage>, //Drawn from the container, counted for the job once printed
+    pub started_ns: u64, //Written, or when the command before it was answered, the printhead works on one at a time
+    pub retries: u32
+}
+
+//Counted over the whole connection, benchmarks included
+#[derive(Debug, Clone, Copy, RustcEncodable)]
+pub struct PartCounters {
+    pub sent: u64,
+    pub acked: u64,
+    pub failed: u64,
+    pub timeouts: u64
 }
 
 pub struct Printerpart {
     pub id: usize,
+    pub serial: u32,
     pub socket: TcpStream,
     pub parttype: PrinterPartType,
     pub blueprint: Option<Box<Read>>,
     pub job_title: Option<String>,
-    pub timeoutid: Option<Timeout>,
+    pub job_id: Option<usize>,
+    pub job_failure: Option<String>, //Why the last job was aborted
+    pub job_started_at: i64, //Milliseconds since the epoch
+    pub bp_size: u64,
+    pub bp_offset: u64,    //Bytes of the blueprint sent to the printhead so far
+    pub acked_offset: u64, //Blueprint offset after the last acknowledged command
+    pub last_level: Option<Vec<u8>>, //Last acknowledged level command
+    pub unsent: Option<Vec<u8>>, //Read from the blueprint, waits for a container or for the commands in flight
+    pub bed: VirtualBed,
+    pub stats: JobStats,
+    pub layer_z: Option<i32>,
+    pub progress_reported: u8,
+    pub events: Vec<Event>, //Not yet published events
+    pub timeoutid: Option<Timeout>, //Set while commands are in flight or a flush is answered
+    pub in_flight: VecDeque<InFlight>, //Oldest first
+    pub window: usize, //Commands kept in flight, 1 for printheads without a command buffer
+    pub pipelined: bool, //Commands and answers carry a sequence number
+    pub next_seq: u8,
+    pub pending_status: Option<u8>, //Answer of a pipelined printhead whose sequence number has not arrived yet
+    pub flushing: bool, //Waiting for the printhead to confirm it has dropped its buffered commands
+    pub latency: CommandLatencies,
     pub matempty: bool,
     pub matid: i32,
-    pub benchmarkcnt: i32
+    pub matwait: Option<i32>,
+    pub paused: bool, //No further commands are sent until resumed
+    pub halted: bool, //Emergency stop, no jobs are accepted until it is reset
+    pub toolchange: Option<ToolChange>,
+    pub purge_due: u8, //Purge of the last tool change, drawn together with the next material
+    pub mat_usage: Vec<MaterialUsage>, //Of the current job
+    pub shard: Option<Shard>, //Part of a blueprint split across printheads
+    pub layer_wait: bool, //Shard waits for the other printheads to finish the previous layers
+    pub mat_used: u64, //Material units drawn from the container since it was last refilled
+    pub env_hold: bool, //A sensor reads outside its limits, no commands are sent
+    pub telemetry: Telemetry, //Readings of a sensor
+    pub samples: Vec<Sample>, //Not yet published readings
+    pub sensor_limits: Vec<SensorLimit>,
+    pub heating: Option<Heating>,
+    pub heater_zone: Option<Quantity>, //Reported by a heater with its temperature
+    pub heater_target: Option<f64>,
+    pub counters: PartCounters,
+    pub wear: Wear, //Not yet collected into the maintenance counters
+    pub service_due: Vec<String>, //Counters that have reached their service interval
+    pub connected_at: i64, //Milliseconds since the epoch
+    pub last_activity: i64,
+    pub healthy: bool, //False after too many missed heartbeats, until the part is heard from again
+    pub heartbeat_pending: bool,
+    pub heartbeats_missed: u32,
+    pub volume: Option<BuildVolume>,
+    pub max_retries: u32,
+    pub purge_amount: u8,
+    pub purge_command: bool,
+    pub toolchange_confirm: bool,
+    pub benchmark: Option<BenchmarkRun>,
+    pub finished_benchmarks: Vec<BenchmarkReport> //Not yet stored reports
 }
 
-unsafe impl Send for Printerpart {}
-unsafe impl Sync for Printerpart {}
-
 impl Printerpart {
-    pub fn new(mut socket: TcpStream, id : usize) -> Printerpart{
-        let mut buf = [0];
-        let ptype;
-        loop {
-            ptype = match socket.try_read(&mut buf) {
-                Err(_) => panic!("Error while handshaking with new client"),
-                Ok(None) => continue,
-                Ok(Some(_)) => {
-                    match buf[0] {
-                        0 => panic!("Something tried to register as an invalid printerpart!"),
-                        1 => PrinterPartType::Printhead,
-                        _ => PrinterPartType::Material
-                    }
-                }
-            };
-            break;
-        };
-        println!("{:?}", ptype);
+    pub fn new(socket: TcpStream, id : usize, registration : Registration) -> Printerpart{
+        let ptype = registration.parttype;
+        let serial = registration.serial;
+        let pipelined = registration.pipelined;
+        let window = registration.window;
+        if pipelined {
+            println!("{:?} (serial {}, buffer depth {})", ptype, serial, window);
+        }
+        else {
+            println!("{:?} (serial {})", ptype, serial);
+        }
         Printerpart {
             id: id,
+            serial: serial,
             socket: socket,
             parttype: ptype,
             blueprint: None,
             job_title: None,
+            job_id: None,
+            job_failure: None,
+            job_started_at: 0,
+            bp_size: 0,
+            bp_offset: 0,
+            acked_offset: 0,
+            last_level: None,
+            unsent: None,
+            bed: VirtualBed::new(),
+            stats: JobStats::new(0),
+            layer_z: None,
+            progress_reported: 0,
+            events: Vec::new(),
             timeoutid: None,
+            in_flight: VecDeque::new(),
+            window: window,
+            pipelined: pipelined,
+            next_seq: 0,
+            pending_status: None,
+            flushing: false,
+            latency: CommandLatencies::new(),
             matempty: false,
-            matid: (buf[0] as i32) - 2,
-            benchmarkcnt: 0
+            matid: registration.matid,
+            matwait: None,
+            paused: false,
+            halted: false,
+            toolchange: None,
+            purge_due: 0,
+            mat_usage: Vec::new(),
+            shard: None,
+            layer_wait: false,
+            mat_used: 0,
+            env_hold: false,
+            telemetry: Telemetry::new(),
+            samples: Vec::new(),
+            sensor_limits: Vec::new(),
+            heating: None,
+            heater_zone: None,
+            heater_target: None,
+            counters: PartCounters { sent: 0, acked: 0, failed: 0, timeouts: 0 },
+            wear: Wear::new(),
+            service_due: Vec::new(),
+            connected_at: now_ms(),
+            last_activity: now_ms(),
+            healthy: true,
+            heartbeat_pending: false,
+            heartbeats_missed: 0,
+            volume: None,
+            max_retries: 0,
+            purge_amount: 0,
+            purge_command: false,
+            toolchange_confirm: false,
+            benchmark: None,
+            finished_benchmarks: Vec::new()
         }
     }
 
     pub fn set_blueprint(self : &mut Self, blueprint : Option<Box<Read>>) {
         self.blueprint = blueprint;
+        self.unsent = None;
+        self.matwait = None;
+        self.paused = false;
+        self.toolchange = None;
+        self.heating = None;
+    }
+
+    //Material the next commands need, the new one as soon as a tool change has begun
+    pub fn required_matid(&self) -> i32 {
+        match self.toolchange {
+            Some(ref toolchange) => toolchange.to,
+            None => self.matid
+        }
+    }
+
+    //Returns the id of the benchmark, the first command is sent by continue_benchmark
+    pub fn start_benchmark(self : &mut Self, spec : BenchmarkSpec) -> usize {
+        let run = BenchmarkRun::new(spec);
+        let id = run.id;
+        self.benchmark = Some(run);
+        id
+    }
+
+    pub fn abort_benchmark(self : &mut Self, reason : &str) {
+        if let Some(run) = self.benchmark.take() {
+            println!("Printhead({}): Aborting benchmark #{}: {}", self.id, run.id, reason);
+            let report = run.report(self.id, false, Some(reason));
+            self.finished_benchmarks.push(report);
+        }
+    }
+
+    pub fn abort_job(self : &mut Self, reason : &str) {
+        if self.blueprint.is_none() {
+            return;
+        }
+        println!("Printhead({}): Aborting job '{}': {}", self.id, self.job_title.as_ref().unwrap(), reason);
+        self.job_failure = Some(reason.to_string());
+        self.set_blueprint(None);
+        if self.pipelined && !self.in_flight.is_empty() && !self.flushing {
+            self.flush(); //The buffered commands of the job are not printed anymore
+        }
     }
 
-    pub fn abort_benchmark(self : &mut Self) {
-        self.benchmarkcnt = 0;
+    //The job is failed and cannot be resumed, an answer to the command in flight is still expected.
+    //Pipelined printheads drop their buffered commands and answer like a flush.
+    pub fn emergency_stop(self : &mut Self) {
+        self.halted = true;
+        let _ = self.socket.write(&[blueprint::STOP]);
+        if self.pipelined && !self.in_flight.is_empty() {
+            self.flushing = true;
+        }
+        self.abort_job("emergency stop");
+        self.abort_benchmark("emergency stop");
     }
 
-    pub fn load_blueprint(self : &mut Self) {
-        self.blueprint = Some( Box::new( File::open("modell.3dbp").unwrap() ) );
-        self.job_title = Some( "local job".to_string() );
+    //The oldest command in flight took too long, the printhead is given up on
+    pub fn command_timeout(self : &mut Self) {
+        self.timeoutid = None;
+        self.counters.timeouts += 1;
+        self.in_flight.clear();
+        self.flushing = false;
+        if self.pipelined {
+            let _ = self.socket.write(&[blueprint::FLUSH]); //A late answer is ignored
+        }
+        self.abort_job("timeout"); //Abort print process
+        self.abort_benchmark("timeout"); //Abort benchmark process
+    }
 
+    //Material of the last acknowledged level command, a job is resumed with it
+    pub fn acked_matid(&self) -> i32 {
+        match self.last_level {
+            Some(ref level) => level[5] as i32,
+            None => self.matid
+        }
+    }
+
+    fn reset_job_state(self : &mut Self, job_id : usize, title : String, size : u64) {
+        self.job_title = Some(title);
+        self.job_id = Some(job_id);
+        self.job_failure = None;
+        self.shard = None;
+        self.layer_wait = false;
+        self.bp_size = size;
+        self.bed = VirtualBed::new(); //Only what is printed by this panel run is known
+        self.layer_z = None;
+        self.purge_due = 0;
+        self.mat_usage = Vec::new();
+    }
+
+    //Loads a new job, the blueprint has to start with its magic number
+    pub fn start_job(self : &mut Self, job_id : usize, title : String, mut bp : Box<Read>, size : u64) -> Result<(), String> {
+        if self.halted {
+            return Err("emergency stop active".to_string());
+        }
         //Read & check Magic number
         let mut magic = [0;4];
-        self.blueprint.as_mut().unwrap().read_exact(&mut magic).unwrap();
-        for i in 0..4 {
-            if magic[i] != b"RBAM"[i] {
-                panic!("Invalid blueprint magic");
+        if bp.read_exact(&mut magic).is_err() || &magic != blueprint::MAGIC {
+            return Err("invalid blueprint".to_string());
+        }
+
+        self.set_blueprint( Some(bp) );
+        self.reset_job_state(job_id, title, size);
+        self.bp_offset = magic.len() as u64;
+        self.acked_offset = self.bp_offset;
+        self.last_level = None;
+        self.stats = JobStats::new(self.bp_offset);
+        self.progress_reported = 0;
+        self.job_started_at = now_ms();
+        metrics::job_accepted();
+        Ok(())
+    }
+
+    //Continues an interrupted job from the spooled blueprint
+    pub fn resume_job(self : &mut Self, entry : &JournalEntry) -> Result<(), String> {
+        if self.halted {
+            return Err("emergency stop active".to_string());
+        }
+        let mut file = try!( File::open(journal::spool_path(entry.job_id))
+            .map_err(|e| format!("Cannot open spooled blueprint: {}", e)) );
+        try!( file.seek(SeekFrom::Start(entry.offset)).map_err(|e| format!("Cannot seek in blueprint: {}", e)) );
+        let size = try!( file.metadata().map_err(|e| format!("Cannot read blueprint size: {}", e)) ).len();
+
+        let blueprint : Box<Read> = match entry.level {
+            //Repeat the last level command first, so the printhead continues on the right layer and material
+            Some(ref level) => Box::new( Cursor::new(level.clone()).chain(file) ),
+            None => Box::new(file)
+        };
+        let prefix_len = entry.level.as_ref().map(|level| level.len() as u64).unwrap_or(0);
+
+        self.set_blueprint( Some(blueprint) );
+        self.reset_job_state(entry.job_id, entry.title.clone(), size);
+        self.matid = entry.matid;
+        self.bp_offset = entry.offset - prefix_len;
+        self.acked_offset = entry.offset;
+        self.last_level = entry.level.clone();
+        self.stats = JobStats::new(entry.offset);
+        self.job_started_at = entry.started_at.unwrap_or(now_ms());
+        self.progress_reported = (entry.offset * 100 / ::std::cmp::max(size, 1)) as u8;
+        Ok(())
+    }
+
+    pub fn load_blueprint(self : &mut Self) -> Result<(), String> {
+        let mut bp = vec![0;0];
+        File::open("modell.3dbp").unwrap().read_to_end(&mut bp).unwrap();
+        if let Some(volume) = self.volume {
+            try!( volume.check_blueprint(&bp) );
+        }
+
+        let job_id = get_new_job_id();
+        let file = try!( journal::spool_blueprint(job_id, &bp) );
+        self.start_job(job_id, "local job".to_string(), Box::new(file), bp.len() as u64)
+    }
+
+    //Keeps up to window commands in flight, matsrc is the container of the required material
+    pub fn exec_instr(self : &mut Self, eventloop: &mut EventLoop<Server>, mut matsrc: Option<&mut Printerpart>) {
+        let wanted = self.required_matid();
+        while self.blueprint.is_some() && self.in_flight.len() < self.window && !self.flushing && !self.paused {
+            if !self.send_next(eventloop, wanted, &mut matsrc) {
+                break;
             }
         }
-        //b vor String-Literal erzeugt ein ASCII-byte array literal
     }
 
-    pub fn exec_instr(self : &mut Self, eventloop: &mut EventLoop<Server>, matsrc: Option<&mut Printerpart>) {
-        let mut commandid = [0];
+    //Sends the next blueprint command, false if the job has to wait or has finished
+    fn send_next(self : &mut Self, eventloop: &mut EventLoop<Server>, wanted : i32, matsrc: &mut Option<&mut Printerpart>) -> bool {
+        let blocked = match self.shard {
+            Some(ref shard) => self.bp_offset < self.bp_size && !shard.may_print(self.bp_offset),
+            None => false
+        };
+        self.layer_wait = blocked;
+        if blocked {
+            return false; //Continued by the server once the other shards have caught up
+        }
+        if self.env_hold {
+            return false; //Continued by the server once all sensors read within their limits
+        }
+        if self.heating.is_some() {
+            return false; //Continued by the server once the heater has reached the target
+        }
+        let held = match self.toolchange {
+            Some(_) if !self.in_flight.is_empty() => return false, //The old material is printed first
+            Some(_) => match self.toolchange_step(eventloop) {
+                Some(level) => Some(level),
+                None => return false //Waiting for the operator or for the purge to finish
+            },
+            None => None
+        };
 
         let job_title = match self.job_title.as_ref() {
                 Some(title)=>title.clone(),
                 None => "--".to_string()
         };
 
-        match self.blueprint.as_mut().expect("No blueprint in progess!").read_exact(&mut commandid) {
-            Err(_) => {
-                println!("Blueprint finished! Job: {}", job_title);
-                self.blueprint = None;
-                self.job_title = Some(format!("Done [last: {}]", self.job_title.as_ref().unwrap()));
-                return
-            },
-            _ => {}
-        }
-        let mut bp = self.blueprint.as_mut().unwrap();
-        self.socket.write(&commandid).unwrap();
-
-        let matreq = match commandid[0] {
-            1 => { //Choose level & mat, 4+1=5byte params
-                 let mut params = [0;5];
-                 bp.read_exact(&mut params).unwrap();
-                 self.matid = params[4] as i32; //New material will be taken from container with id
-                 self.socket.write(&params).unwrap();
-                 0
-            },
-            2 => { //Print dot, 2*4=8 byte params
-                 let mut params = [0;8];
-                 bp.read_exact(&mut params).unwrap();
-                 self.socket.write(&params).unwrap();
-                 1//A dot takes 1 material unit
-            },
-            3 => { //Print line, 4*4=16byte params
-                let mut params = [0;16];
-                bp.read_exact(&mut params).unwrap();
-                self.socket.write(&params).unwrap();
-                2//A line takes 2 material units
+        let raw = match (held.clone(), self.unsent.take()) {
+            (Some(level), _) => level,
+            (None, Some(raw)) => raw,
+            (None, None) => {
+                let mut commandid = [0];
+                match self.blueprint.as_mut().expect("No blueprint in progess!").read_exact(&mut commandid) {
+                    Err(_) => {
+                        if self.in_flight.is_empty() {
+                            println!("Blueprint finished! Job: {}", job_title);
+                            self.blueprint = None;
+                        }
+                        return false //Otherwise finished once the commands in flight are answered
+                    },
+                    _ => {}
+                }
+                let paramlen = match blueprint::param_len(commandid[0]) {
+                    Some(len) => len,
+                    None => panic!("Unknown blueprint command {:#x}", commandid[0])
+                };
+                let mut raw = vec![0; 1 + paramlen];
+                raw[0] = commandid[0];
+                self.blueprint.as_mut().unwrap().read_exact(&mut raw[1 ..]).unwrap();
+                raw
+            }
+        };
+        let command = Command::decode(raw[0], &raw[1 ..]);
+
+        if let Some(volume) = self.volume {
+            if !volume.contains(&command) {
+                let reason = format!("{:?} at offset {} outside build volume", command, self.bp_offset);
+                self.abort_job(&reason);
+                return false;
+            }
+        }
+
+        if let Command::Temperature { target, zone } = command {
+            if !self.in_flight.is_empty() {
+                self.unsent = Some(raw); //Heats once the commands before it are printed
+                return false;
             }
-            c => {
-                panic!("Unknown blueprint command {:#x}", c);
+            //Done by the panel, acknowledged once the target is reached so a resumed job heats again
+            self.bp_offset += raw.len() as u64;
+            self.begin_heating(zone, target);
+            return false;
+        }
+
+        if let Command::Level { matid, .. } = command {
+            if held.is_none() && self.matid >= 0 && self.matid != matid as i32 {
+                self.begin_toolchange(matid as i32, raw);
+                return true;
             }
+        }
+
+        let matreq = match command {
+            Command::Level { .. } => 0,
+            Command::Dot { .. } => 1, //A dot takes 1 material unit
+            Command::Line { .. } => 2 //A line takes 2 material units
         };
 
+        let mut usage = None;
         if matreq > 0 {
-            matsrc.expect("No matching material source available!").sim_mat_usage(matreq);
+            if self.required_matid() != wanted {
+                self.unsent = Some(raw); //Material changed, the server looks up the new container on the next answer
+                return false;
+            }
+            let matsrc = match *matsrc {
+                Some(ref mut matsrc) => matsrc,
+                None => {
+                    self.unsent = Some(raw);
+                    if self.in_flight.is_empty() {
+                        println!("Printhead({}): Pausing print until material {} is refilled", self.id, wanted);
+                        self.matwait = Some(wanted);
+                    }
+                    return false;
+                }
+            };
+            let mut used_matid = self.matid;
+            if matsrc.matid != self.matid {
+                println!("Printhead({}): Substituting material {} with {}", self.id, self.matid, matsrc.matid);
+                self.stats.substitutions += 1;
+                used_matid = matsrc.matid;
+            }
+            matsrc.sim_mat_usage(self.purge_due);
+            matsrc.sim_mat_usage(matreq);
+            usage = Some( MaterialUsage { material: used_matid, printed: matreq as u64, purged: self.purge_due as u64 } );
+            self.purge_due = 0;
+        }
+
+        self.bp_offset += raw.len() as u64;
+        if let Command::Level { matid, .. } = command {
+            self.matid = matid as i32; //New material will be taken from container with id
+        }
+        let used_matid = usage.map(|usage| usage.material).unwrap_or(self.matid);
+        self.send_command(eventloop, raw, Some((command, used_matid)), usage);
+        true
+    }
+
+    //Pipelined printheads get the sequence number after the command id
+    fn write_command(self : &mut Self, raw : &[u8], seq : u8) {
+        if self.pipelined {
+            let mut framed = Vec::with_capacity(raw.len() + 1);
+            framed.push(raw[0]);
+            framed.push(seq);
+            framed.extend_from_slice(&raw[1 ..]);
+            self.socket.write(&framed).unwrap();
+        }
+        else {
+            self.socket.write(raw).unwrap();
+        }
+        self.counters.sent += 1;
+    }
+
+    fn send_command(self : &mut Self, eventloop: &mut EventLoop<Server>, raw : Vec<u8>, command : Option<(Command, i32)>, usage : Option<MaterialUsage>) {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.write_command(&raw, seq);
+        self.in_flight.push_back( InFlight { seq: seq, raw: raw, command: command, offset: self.bp_offset,
+            usage: usage, started_ns: time::precise_time_ns(), retries: 0 } );
+        if self.timeoutid.is_none() {
+            self.rearm_timeout(eventloop);
+        }
+    }
+
+    //Every command has PRINT_TIMEOUT_MS from when the printhead could start it, so only the oldest one is watched
+    fn rearm_timeout(self : &mut Self, eventloop: &mut EventLoop<Server>) {
+        if let Some(timeoutid) = self.timeoutid.take() {
+            eventloop.clear_timeout(&timeoutid);
+        }
+        if !self.in_flight.is_empty() || self.flushing {
+            self.timeoutid = Some( eventloop.timeout(self.id, Duration::from_millis(PRINT_TIMEOUT_MS)).unwrap() );
+        }
+    }
+
+    //The printhead drops all buffered commands and answers with FLUSH once it has
+    fn flush(self : &mut Self) {
+        let _ = self.socket.write(&[blueprint::FLUSH]);
+        self.flushing = true;
+    }
+
+    fn begin_heating(self : &mut Self, zone : u8, target : i32) {
+        let zone = match Quantity::from_id(zone) {
+            Some(zone @ Quantity::BedTemperature) | Some(zone @ Quantity::ChamberTemperature) => zone,
+            _ => {
+                self.abort_job(&format!("unknown heater zone {}", zone));
+                return;
+            }
+        };
+        let target = target as f64 / 10.0;
+        println!("Printhead({}): Heating {} to {}", self.id, zone.name(), target);
+        if let Some(job_id) = self.job_id {
+            self.events.push( Event::heating(self.id, job_id, zone.name(), target, false) );
+        }
+        self.heating = Some( Heating { zone: zone, target: target, missing_since: None } );
+    }
+
+    //The heater has reached the target, the temperature command counts as acknowledged
+    pub fn heating_done(self : &mut Self) {
+        let heating = match self.heating.take() {
+            Some(heating) => heating,
+            None => return
+        };
+        println!("Printhead({}): {} reached {}, continuing", self.id, heating.zone.name(), heating.target);
+        self.acked_offset = self.bp_offset;
+        if let Some(ref shard) = self.shard {
+            shard.acked(self.acked_offset);
         }
+        if let Some(job_id) = self.job_id {
+            self.events.push( Event::heating(self.id, job_id, heating.zone.name(), heating.target, true) );
+        }
+    }
 
-        self.timeoutid = Some( eventloop.timeout(self.id, Duration::from_millis(PRINT_TIMEOUT_MS)).unwrap() );
+    pub fn set_heater_target(self : &mut Self, target : f64) {
+        let tenths = (target * 10.0).round() as i32;
+        let _ = self.socket.write(&[SET_TARGET, tenths as u8, (tenths >> 8) as u8, (tenths >> 16) as u8, (tenths >> 24) as u8]);
+        self.heater_target = Some(target);
+    }
+
+    fn begin_toolchange(self : &mut Self, to : i32, level : Vec<u8>) {
+        println!("Printhead({}): Tool change from material {} to {}", self.id, self.matid, to);
+        self.stats.tool_changes += 1;
+        self.purge_due = self.purge_amount;
+        let job_id = self.job_id.unwrap_or(0);
+        self.events.push( Event::tool_change(self.id, job_id, self.matid, to) );
+        if self.toolchange_confirm {
+            println!("Printhead({}): Load material {}, then enter 't' to continue", self.id, to);
+        }
+        self.toolchange = Some( ToolChange { to: to, level: level, confirmed: !self.toolchange_confirm, purged: !self.purge_command } );
+    }
+
+    //Returns the held back level command once the tool change is complete
+    fn toolchange_step(self : &mut Self, eventloop: &mut EventLoop<Server>) -> Option<Vec<u8>> {
+        let (to, confirmed, purged) = {
+            let toolchange = self.toolchange.as_ref().unwrap();
+            (toolchange.to, toolchange.confirmed, toolchange.purged)
+        };
+        if !confirmed {
+            return None; //Continued by the server when the operator confirms
+        }
+        if !purged {
+            println!("Printhead({}): Purging {} units of material {}", self.id, self.purge_amount, to);
+            self.toolchange.as_mut().unwrap().purged = true;
+            let purge = vec![blueprint::PURGE, to as u8, self.purge_amount];
+            self.send_command(eventloop, purge, None, None);
+            return None;
+        }
+        self.toolchange.take().map(|toolchange| toolchange.level)
     }
 
     fn sim_mat_usage(self : &mut Self, amount : u8) {
@@ -145,73 +657,321 @@ impl Printerpart {
         }
         assert!(self.parttype == PrinterPartType::Material, "sim_mat_usage on non-Material!");
         self.socket.write(&[amount]).unwrap();
+        self.mat_used += amount as u64;
+        self.wear.material += amount as u64;
     }
 
-    fn read_result(self : &mut Self) -> u8 {
+    //Ok(None) once everything received has been read, Err if the part has closed the connection
+    fn try_result(self : &mut Self) -> Result<Option<u8>, ()> {
         let mut buf = [0];
+        match self.socket.try_read(&mut buf) {
+            Err(_) | Ok(Some(0)) => Err(()),
+            Ok(None) => Ok(None),
+            Ok(Some(_)) => {
+                self.last_activity = now_ms();
+                self.alive();
+                Ok(Some(buf[0]))
+            }
+        }
+    }
+
+    //Anything the part sends answers a pending heartbeat
+    fn alive(self : &mut Self) {
+        self.heartbeat_pending = false;
+        self.heartbeats_missed = 0;
+        if !self.healthy {
+            println!("{:?}({}) is responding again", self.parttype, self.id);
+            self.healthy = true;
+            let event = Event::part_health(self.id, &format!("{:?}", self.parttype).to_lowercase(), true);
+            self.events.push(event);
+        }
+    }
+
+    //Called every heartbeat interval, a printhead working on a command is watched by the command timeout instead
+    pub fn heartbeat(self : &mut Self, max_missed : u32) {
+        if self.timeoutid.is_some() {
+            return;
+        }
+        if self.heartbeat_pending {
+            self.heartbeats_missed += 1;
+            if self.heartbeats_missed >= max_missed && self.healthy {
+                println!("{:?}({}) missed {} heartbeats, marking it unhealthy", self.parttype, self.id, self.heartbeats_missed);
+                self.healthy = false;
+                let event = Event::part_health(self.id, &format!("{:?}", self.parttype).to_lowercase(), false);
+                self.events.push(event);
+            }
+        }
+        let _ = self.socket.write(&[blueprint::HEARTBEAT]); //A failed write is noticed as missed heartbeat
+        self.heartbeat_pending = true;
+    }
+
+    fn report_progress(self : &mut Self, command : &Command) {
+        let job_id = match self.job_id {
+            Some(job_id) => job_id,
+            None => return
+        };
+        if let Command::Level { z, .. } = *command {
+            if self.layer_z != Some(z) {
+                self.layer_z = Some(z);
+                self.events.push( Event::layer_changed(self.id, job_id, z, self.matid) );
+            }
+        }
+        if self.bp_size > 0 {
+            let percent = (self.acked_offset * 100 / self.bp_size) as u8;
+            if percent >= self.progress_reported + PROGRESS_STEP_PERCENT {
+                self.progress_reported = percent - percent % PROGRESS_STEP_PERCENT;
+                self.events.push( Event::progress(self.id, job_id, self.progress_reported) );
+            }
+        }
+    }
+
+    //Handles every answer the printhead has sent, returns false if it has disconnected
+    pub fn notify_printhead(self : &mut Self, eventloop : &mut EventLoop<Server>, mut matcontainer : Option<&mut Printerpart>) -> bool {
         loop {
-            return match self.socket.try_read(&mut buf) {
-                Err(_) => panic!("Error while receiving client data"),
-                Ok(None) => continue,
-                Ok(Some(_)) => buf[0]
+            let result = match self.try_result() {
+                Err(()) => return false,
+                Ok(None) => return true, //Everything read
+                Ok(Some(result)) => result
             };
-        };
+            let matsrc = matcontainer.as_mut().map(|matsrc| &mut **matsrc);
+            let answered = match self.pending_status.take() {
+                Some(status) => self.answer(eventloop, status, Some(result), matsrc),
+                None => self.answer(eventloop, result, None, matsrc)
+            };
+            if !answered {
+                return false;
+            }
+        }
     }
 
-    pub fn notify_printhead(self : &mut Self, eventloop : &mut EventLoop<Server>, matcontainer : Option<&mut Printerpart>) {
-        eventloop.clear_timeout(& self.timeoutid.as_mut().expect("Unexpected printhead message!"));
-        self.timeoutid = None;
+    //Answers of pipelined printheads carry the sequence number of the command, unless it is still to be read
+    fn answer(self : &mut Self, eventloop : &mut EventLoop<Server>, result : u8, seq : Option<u8>, matcontainer : Option<&mut Printerpart>) -> bool {
+        match result {
+            blueprint::HEARTBEAT => return true,
+            blueprint::FLUSH => {
+                self.flushed(eventloop);
+                return true;
+            },
+            1 | 255 => {},
+            _ => panic!("Unknown printhead status!")
+        }
+        let index = if self.pipelined {
+            let seq = match seq {
+                Some(seq) => seq,
+                None => match self.try_result() {
+                    Err(()) => return false,
+                    Ok(None) => {
+                        //Split across segments, finished on the next readable event
+                        self.pending_status = Some(result);
+                        return true;
+                    },
+                    Ok(Some(seq)) => seq
+                }
+            };
+            self.in_flight.iter().position(|entry| entry.seq == seq)
+        }
+        else if self.in_flight.is_empty() {
+            None
+        }
+        else {
+            Some(0)
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                println!("Printhead({}): Ignoring answer to a command that is not in flight", self.id);
+                return true;
+            }
+        };
+        let ok = result == 1;
+        if ok {
+            self.counters.acked += 1;
+        }
+        else {
+            self.counters.failed += 1;
+        }
+        let now = time::precise_time_ns();
+        let mut entry = self.in_flight.remove(index).unwrap();
+        let latency_us = (now - entry.started_ns) / 1000;
+        latency::record(&mut self.latency, entry.raw[0], latency_us);
+        if index == 0 {
+            if let Some(next) = self.in_flight.front_mut() {
+                next.started_ns = cmp::max(next.started_ns, now); //The printhead starts on it now
+            }
+        }
 
-        if self.benchmarkcnt > 0 {
+        if self.benchmark.is_some() {
+            self.benchmark.as_mut().unwrap().answered(entry.raw[0], latency_us, ok);
+            if !ok && self.pipelined && !self.flushing {
+                self.flush(); //The commands after it are dropped by the printhead and sent again
+            }
+            self.rearm_timeout(eventloop);
             self.continue_benchmark(eventloop);
-            return;
+            return true;
         }
-        match self.read_result() {
-            1 => {
-                if matcontainer.is_some() {
-                    self.exec_instr(eventloop, matcontainer)
-                }
-                else {
-                    println!("Printhead({}): Pausing print until material is refilled", self.id);
+
+        if !ok {
+            if self.pipelined && !self.flushing {
+                self.flush();
+            }
+            if entry.retries < self.max_retries && self.blueprint.is_some() {
+                entry.retries += 1;
+                self.stats.retries += 1;
+                println!("Printhead({}) problem, retrying command ({}/{})", self.id, entry.retries, self.max_retries);
+                entry.started_ns = now;
+                if !self.pipelined {
+                    self.write_command(&entry.raw, entry.seq);
                 }
-            },
-            255 => {
+                self.in_flight.insert(index, entry); //Pipelined printheads get it again with the commands after it once flushed
+            }
+            else {
                 println!("Printhead problem, aborting print");
-                self.blueprint = None;
-            },
-            _ => panic!("Unknown printhead status!")
-        };
+                self.abort_job("printhead failure");
+            }
+            self.rearm_timeout(eventloop);
+            return true;
+        }
+
+        self.wear.commands += 1;
+        self.rearm_timeout(eventloop);
+        if let Some(usage) = entry.usage {
+            add_usage(&mut self.mat_usage, usage);
+            self.wear.material += usage.printed + usage.purged;
+        }
+        if let Some((command, used_matid)) = entry.command {
+            self.acked_offset = entry.offset;
+            match command {
+                Command::Level { .. } => self.last_level = Some(entry.raw.clone()),
+                Command::Line { x1, y1, x2, y2 } => self.wear.line_length += ((x2 - x1) as f64).hypot((y2 - y1) as f64),
+                _ => {}
+            }
+            self.bed.record(&command, used_matid);
+            if entry.offset > self.stats.from_offset {
+                self.stats.acked_commands += 1; //The level repeated on resume is not part of the blueprint rest
+            }
+            self.report_progress(&command);
+            if let Some(ref shard) = self.shard {
+                shard.acked(self.acked_offset);
+            }
+        }
+        if self.blueprint.is_none() {
+            return true; //Job was cancelled while the command was printed
+        }
+        if self.paused {
+            println!("Printhead({}): Paused", self.id);
+            return true;
+        }
+        if matcontainer.is_some() {
+            self.matwait = None;
+        }
+        self.exec_instr(eventloop, matcontainer); //Waits for material once nothing is in flight anymore
+        true
     }
 
-    pub fn notify_material(self : &mut Self, eventloop : &mut EventLoop<Server>, continuedelay : &mut Option<Timeout>) {
-        match self.read_result() {
-            255 => {
-                println!("Material container {} is nearly empty, pausing...", self.matid);
-                self.matempty = true;
-            },
-            1 => {
-                println!("Material container {} refilled", self.matid);
-                self.matempty = false;
-                if continuedelay.is_some() {
-                    eventloop.clear_timeout(continuedelay.as_mut().expect(""));
+    //The printhead has dropped its buffered commands, they are sent again unless the job or benchmark has ended
+    fn flushed(self : &mut Self, eventloop : &mut EventLoop<Server>) {
+        if !self.flushing {
+            return; //Late answer after a timeout
+        }
+        self.flushing = false;
+        if self.blueprint.is_some() || self.benchmark.is_some() {
+            let now = time::precise_time_ns();
+            let resend : Vec<(Vec<u8>, u8)> = self.in_flight.iter().map(|entry| (entry.raw.clone(), entry.seq)).collect();
+            for (raw, seq) in resend {
+                self.write_command(&raw, seq);
+            }
+            for entry in self.in_flight.iter_mut() {
+                entry.started_ns = now;
+            }
+        }
+        else {
+            self.in_flight.clear();
+        }
+        self.rearm_timeout(eventloop);
+        if self.benchmark.is_some() {
+            self.continue_benchmark(eventloop);
+        }
+    }
+
+    //Handles everything the container has sent, returns false if it has disconnected
+    pub fn notify_material(self : &mut Self, eventloop : &mut EventLoop<Server>, continuedelay : &mut Option<Timeout>) -> bool {
+        loop {
+            match self.try_result() {
+                Err(()) => return false,
+                Ok(None) => return true, //Everything read
+                Ok(Some(blueprint::HEARTBEAT)) => {},
+                Ok(Some(255)) => {
+                    println!("Material container {} is nearly empty, pausing printheads using it...", self.matid);
+                    self.matempty = true;
+                    self.events.push( Event::material_low(self.id, self.matid) );
+                },
+                Ok(Some(1)) => {
+                    println!("Material container {} refilled", self.matid);
+                    self.matempty = false;
+                    self.mat_used = 0;
+                    self.events.push( Event::material_refilled(self.id, self.matid) );
+                    if continuedelay.is_some() {
+                        eventloop.clear_timeout(continuedelay.as_mut().expect(""));
+                    }
+                    *continuedelay = Some(eventloop.timeout( 0, Duration::from_millis(CONTINUE_DELAY_MS)).unwrap() );
+                },
+                Ok(Some(_)) => panic!("Unknown material status!")
+            }
+        }
+    }
+
+    //Readings of sensors and heaters, returns false if the part has disconnected
+    pub fn notify_sensor(self : &mut Self) -> bool {
+        let mut buf = [0; 64];
+        loop {
+            let received = match self.socket.try_read(&mut buf) {
+                Err(_) | Ok(Some(0)) => return false,
+                Ok(None) => return true, //Everything read
+                Ok(Some(n)) => n
+            };
+            self.last_activity = now_ms();
+            self.alive();
+            for (quantity, reading) in self.telemetry.receive(&buf[.. received]) {
+                if self.parttype == PrinterPartType::Heater {
+                    self.heater_zone = Some(quantity);
                 }
-                *continuedelay = Some(eventloop.timeout( 0, Duration::from_millis(CONTINUE_DELAY_MS)).unwrap() );
-            },
-            _ => panic!("Unknown material status!")
+                if let Some(outside) = self.telemetry.record(quantity, reading, &self.sensor_limits) {
+                    println!("Sensor({}): {} {} {}", self.id, quantity.name(), reading.value,
+                        if outside { "is out of range, holding printheads" } else { "is back in range" });
+                    self.events.push( Event::sensor_alarm(self.id, quantity.name(), reading.value, outside) );
+                }
+                self.samples.push( Sample { part: self.id, quantity: quantity.name(), unit: quantity.unit(),
+                    value: reading.value, timestamp: reading.timestamp } );
+            }
         }
     }
 
-    fn continue_benchmark(self : &mut Self, eventloop : &mut EventLoop<Server>) {
-        self.benchmarkcnt -= 1;
-        if self.benchmarkcnt == 0 {
-            unsafe {
-                println!("Benchmark finished, time: {}ms",
-                    ( (time::precise_time_ns() - BenchWatchStopTime) as f32) / 1_000_000.0);
+    //Keeps the window filled with benchmark commands, or finishes the benchmark once all are answered
+    pub fn continue_benchmark(self : &mut Self, eventloop : &mut EventLoop<Server>) {
+        loop {
+            let finished = match self.benchmark {
+                Some(ref run) => run.finished(),
+                None => return
+            };
+            if finished {
+                break;
             }
+            if self.in_flight.len() >= self.window || self.flushing {
+                return;
+            }
+            let (_, command) = self.benchmark.as_mut().unwrap().next_command().unwrap();
+            self.send_command(eventloop, command, None, None);
+        }
+        if !self.in_flight.is_empty() {
             return;
         }
-        self.socket.write(&[1, 57, 5, 0, 0, 0]).unwrap();//Arbitrary change level command
-        self.timeoutid = Some( eventloop.timeout( self.id, Duration::from_millis(PRINT_TIMEOUT_MS) ).unwrap() );
-        return;
+        let report = self.benchmark.take().unwrap().report(self.id, false, None);
+        println!("Benchmark #{} finished, time: {:.1}ms ({:.0} commands/s, {} failed)",
+            report.id, report.duration_ms, report.commands_per_sec, report.failed);
+        for (command, summary) in &report.latency {
+            println!("  {}: p50 {}us, p95 {}us, p99 {}us, max {}us", command,
+                summary.p50_us, summary.p95_us, summary.p99_us, summary.max_us);
+        }
+        self.finished_benchmarks.push(report);
     }
 }
@@ -1,44 +1,63 @@
-use super::super::time;
-
-use std::io::{Read, Write};
-use std::sync::{Arc, RwLock};
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+use std::rc::Rc;
+use std::cell::RefCell;
 use std::io::stdin;
-use std::collections::HashMap;
-use std::ops::DerefMut;
 use std::time::Duration;
+use std::cmp;
+use std::ops::DerefMut;
+use std::collections::HashMap;
 use mio::tcp::TcpListener;
 use mio::{Token, Timeout, EventLoop, EventSet, PollOpt, Handler};
-use mqtt::async::{PersistenceType, Qos, AsyncClient, AsyncConnectOptions};
 
-use super::Printerpart;
+use super::{Printerpart, PartCell, Parts};
 use super::PrinterPartType;
+use super::handshake::Handshake;
+use super::Jobs;
+use super::{journal, get_new_job_id};
+use super::split;
+use super::split::SplitMode;
+use config::Config;
+use status;
+use metrics;
+use rustc_serialize::json;
+use events::{Event, Events, now_ms};
+use telemetry::Quantity;
+use remote::{RemoteRequest, RemoteReply};
+use benchmark;
+use benchmark::{BenchmarkSpec, BenchmarkReports};
+use maintenance;
+use maintenance::{Maintenance, PartKey};
+use estop;
+use estop::EmergencyStop;
+use messages::{Message, Request, Reply};
+use rest;
+use sim;
 use super::super::SERVER_TOKEN;
 use super::super::CLI_TOKEN;
-use super::super::PRINT_TIMEOUT_MS;
-
-use super::BenchWatchStopTime;
+use super::super::HEARTBEAT_TIMEOUT;
 
+//Owns all parts, the REST and remote threads reach them only through messages to the event loop
 pub struct Server {
     pub socket: TcpListener,
-    pub clients: Arc<RwLock<HashMap<Token, Arc<RwLock<Printerpart>>>>>,
+    pub clients: Parts,
+    pub handshakes: HashMap<Token, Handshake>, //Connected parts that have not completely registered yet
     pub tokencounter: usize,
     pub continuedelay: Option<Timeout>,
-    pub msgclient: AsyncClient
+    pub events: Events,
+    pub jobs: Jobs,
+    pub benchmarks: BenchmarkReports,
+    pub maintenance: Maintenance,
+    pub estop: Option<EmergencyStop>, //No jobs are accepted while it is set
+    pub lost_alarms: Vec<(Quantity, i64)>, //Of sensors that disconnected in alarm, with the time they did
+    pub config: Arc<Config>
 }
 
 impl Server {
-    fn check_mat_status(&self) -> bool {
-        let client = self.clients.read().unwrap();
-        for cell in client.values() {
-            let part = cell.read().unwrap();
-            if part.parttype != PrinterPartType::Material {
-                continue;
-            }
-            if part.matempty {
-                return false;
-            }
-        }
-        true
+    fn check_mat_status(&self, matid : i32) -> bool {
+        self.get_mat_src(matid).is_some()
     }
 
     fn accept_new_client(&mut self, eventloop : &mut EventLoop<Server>) {
@@ -54,83 +73,687 @@ impl Server {
        self.tokencounter += 1;
        let token = Token(self.tokencounter);
 
-       let mut clients = self.clients.write().unwrap();
-       clients.insert( token, Arc::new( RwLock::new( Printerpart::new(clientsocket, self.tokencounter) ) ) );
-       eventloop.register( & clients[&token].read().unwrap().socket, token,
-                           EventSet::readable(), PollOpt::edge() ).unwrap();
+       //The registration is read once it arrives, a silent client must not block the other parts
+       if let Err(e) = eventloop.register( &clientsocket, token, EventSet::readable() | EventSet::hup(), PollOpt::edge() ) {
+           println!("Cannot register new client: {}", e);
+           return;
+       }
+       self.handshakes.insert( token, Handshake::new(clientsocket) );
+    }
+
+    //Returns true once the part has registered, rejected parts are disconnected
+    fn continue_handshake(&mut self, eventloop : &mut EventLoop<Server>, token : Token) -> bool {
+       let result = match self.handshakes.get_mut(&token) {
+           Some(handshake) => handshake.read(),
+           None => return false
+       };
+       let registration = match result {
+           Ok(None) => return false,
+           Ok(Some(registration)) => registration,
+           Err(e) => {
+               println!("Rejecting new client: {}", e);
+               self.drop_handshake(eventloop, token);
+               return false;
+           }
+       };
+       if sim::reserved_serial(registration.serial) && !self.config.simulation() {
+           println!("Part with serial {} uses the range reserved for simulated parts, disconnecting", registration.serial);
+           self.drop_handshake(eventloop, token);
+           return false;
+       }
+       let handshake = self.handshakes.remove(&token).unwrap();
+
+       let mut part = Printerpart::new(handshake.socket, token.as_usize(), registration);
+       part.volume = self.config.build_volume;
+       part.max_retries = self.config.max_retries;
+       part.window = cmp::min(part.window, self.config.pipeline_depth);
+       part.purge_amount = self.config.purge_amount;
+       part.purge_command = self.config.purge_command;
+       part.toolchange_confirm = self.config.toolchange_confirm;
+       part.halted = self.estop.is_some();
+       if part.parttype == PrinterPartType::Sensor {
+           part.sensor_limits = self.config.sensor_limits.clone();
+       }
+
+       let event = Event::part_connected(part.id, &format!("{:?}", part.parttype).to_lowercase());
+       part.events.push(event);
+       if part.parttype == PrinterPartType::Printhead && self.jobs.journal.has_interrupted(part.serial) {
+           println!("Printhead({}) has an interrupted job, enter 'r' to resume or 'd' to discard it", part.id);
+       }
+       self.clients.insert( token, Rc::new( RefCell::new( part ) ) );
+       self.check_environment(eventloop); //A new printhead holds too
+       true
+    }
+
+    fn drop_handshake(&mut self, eventloop : &mut EventLoop<Server>, token : Token) {
+        if let Some(handshake) = self.handshakes.remove(&token) {
+            let _ = eventloop.deregister(&handshake.socket);
+        }
+    }
+
+    fn disconnect(&mut self, eventloop : &mut EventLoop<Server>, token : Token) {
+        let cell = match self.clients.remove(&token) {
+            Some(cell) => cell,
+            None => return
+        };
+        let mut part = cell.borrow_mut();
+        println!("{:?}({}) disconnected", part.parttype, part.id);
+        let _ = eventloop.deregister(&part.socket);
+        if let Some(timeoutid) = part.timeoutid.take() {
+            eventloop.clear_timeout(&timeoutid);
+        }
+        if part.shard.is_some() {
+            //The split job cannot be finished without this shard, it fails as a whole
+            part.abort_job("printhead disconnected");
+            self.jobs.update(&mut part);
+        }
+        self.jobs.interrupt(&part);
+        if part.parttype == PrinterPartType::Sensor {
+            for quantity in part.telemetry.alarms.keys() {
+                println!("Sensor({}) disconnected in alarm, holding printheads until {} is back in range", part.id, quantity.name());
+                self.lost_alarms.push((*quantity, now_ms()));
+            }
+        }
+        self.maintenance.collect(&mut part, &self.config.service_intervals);
+        self.maintenance.checkpoint();
+
+        let mut events : Vec<Event> = part.events.drain(..).collect();
+        events.push( Event::part_disconnected(part.id, &format!("{:?}", part.parttype).to_lowercase(), part.job_id) );
+        for event in events {
+            self.events.publish(event);
+        }
+        self.coordinate_splits(eventloop);
+        self.check_environment(eventloop);
+        self.publish_status();
     }
 
-    fn get_free_printhead(self : &Self) -> Option<Arc<RwLock<Printerpart>>> {
-        let clients = self.clients.read().unwrap();
+    fn resume_jobs(self : &mut Self, eventloop : &mut EventLoop<Server>) {
+        let clients = self.clients.clone();
         for cell in clients.values() {
-            let part = cell.read().unwrap();
-            if part.parttype == PrinterPartType::Printhead
-                    && part.blueprint.is_none() {
-                return Some(cell.clone());
+            let (parttype, serial, idle) = {
+                let part = cell.borrow();
+                (part.parttype, part.serial, part.blueprint.is_none() && part.benchmark.is_none())
+            };
+            if parttype != PrinterPartType::Printhead || !idle {
+                continue;
+            }
+            let entry = match self.jobs.journal.take_interrupted(serial) {
+                Some(entry) => entry,
+                None => continue
+            };
+
+            let mat_src = self.get_mat_src(entry.matid); //Lookup before locking the printhead
+            let mut printhead = cell.borrow_mut();
+            if let Err(e) = printhead.resume_job(&entry) {
+                println!("Cannot resume job #{} '{}': {}, enter 'r' to retry or 'd' to discard it", entry.job_id, entry.title, e);
+                self.jobs.journal.restore_interrupted(entry);
+                continue;
+            }
+            println!("Resuming job #{} '{}' on printhead({}) at offset {}", entry.job_id, entry.title, printhead.id, entry.offset);
+            let event = Event::job_started(printhead.id, entry.job_id, &entry.title);
+            printhead.events.push(event);
+            match mat_src {
+                Some(mat_src) => {
+                    printhead.exec_instr( eventloop, Some(mat_src.borrow_mut().deref_mut()) );
+                },
+                None => {
+                    printhead.exec_instr( eventloop, None );
+                }
             }
+            self.jobs.update(&mut printhead);
+        }
+        if !self.jobs.journal.interrupted.is_empty() {
+            println!("{} interrupted job[s] waiting for their printhead to reconnect", self.jobs.journal.interrupted.len());
         }
-        None
     }
 
-    fn start_print(self : &mut Self, eventloop : &mut EventLoop<Server>) {
-        if !self.check_mat_status() {
-            println!("Job discarded: Please refill material containers first!");
+    fn get_free_printhead(self : &Self) -> Option<PartCell> {
+        status::free_printhead(&self.clients)
+    }
+
+    fn get_printhead(self : &Self, id : usize) -> Option<PartCell> {
+        match self.clients.get(&Token(id)) {
+            Some(cell) if cell.borrow().parttype == PrinterPartType::Printhead => Some(cell.clone()),
+            _ => None
+        }
+    }
+
+    //Sends the next command to a printhead that is idle in the middle of a job
+    fn continue_printhead(self : &mut Self, eventloop : &mut EventLoop<Server>, cell : &PartCell) {
+        let (matid, idle) = {
+            let part = cell.borrow();
+            (part.required_matid(), part.blueprint.is_some() && part.timeoutid.is_none() && !part.paused)
+        };
+        if !idle {
             return;
         }
+        let mat_src = self.get_mat_src(matid); //Lookup before locking the printhead
+        let mut printhead = cell.borrow_mut();
+        match mat_src {
+            Some(mat_src) => {
+                printhead.matwait = None;
+                printhead.exec_instr( eventloop, Some(mat_src.borrow_mut().deref_mut()) );
+            },
+            None => {
+                println!("Printhead({}): Pausing print until material {} is refilled", printhead.id, matid);
+                printhead.matwait = Some(matid);
+            }
+        }
+        self.jobs.update(&mut printhead);
+    }
 
+    fn start_print(self : &mut Self, eventloop : &mut EventLoop<Server>) {
         match self.get_free_printhead(){
             None => {
                 println!("Printhead[s] busy");
             },
             Some(printhead) => {
-                let mut printhead = printhead.write().unwrap();
+                let mut printhead = printhead.borrow_mut();
                 println!("Sending job to printhead({})", printhead.id);
-                printhead.load_blueprint();
+                if let Err(e) = printhead.load_blueprint() {
+                    println!("Job discarded: {}", e);
+                    return;
+                }
+                let event = Event::job_started(printhead.id, printhead.job_id.unwrap(), printhead.job_title.as_ref().unwrap());
+                printhead.events.push(event);
 
                 printhead.exec_instr( eventloop, None ); //First instruction cannot use a Material, since it could not possibly have selected one
+                self.jobs.update(&mut printhead);
             }
         }
     }
 
-    fn benchmark(self : &mut Self, eventloop : &mut EventLoop<Server>) {
-        match self.get_free_printhead(){
-            None => {
-                println!("Printhead[s] busy");
-                return;
+    fn start_split(self : &mut Self, eventloop : &mut EventLoop<Server>, bp : &[u8], title : &str, mode : SplitMode) -> Result<(usize, Vec<usize>), String> {
+        if let Some(volume) = self.config.build_volume {
+            try!( volume.check_blueprint(bp) );
+        }
+        let (job_id, printheads) = try!( split::start(&self.clients, bp, title, mode) );
+        for id in &printheads {
+            if let Some(cell) = self.get_printhead(*id) {
+                let mut printhead = cell.borrow_mut();
+                printhead.exec_instr( eventloop, None ); //First instruction cannot use a Material, since it could not possibly have selected one
+                self.jobs.update(&mut printhead);
+            }
+        }
+        Ok((job_id, printheads))
+    }
+
+    fn split_local(self : &mut Self, eventloop : &mut EventLoop<Server>, mode : &str) -> Result<(usize, Vec<usize>), String> {
+        let mode = try!( SplitMode::parse(mode) );
+        let mut bp = vec![0;0];
+        try!( File::open("modell.3dbp").and_then(|mut file| file.read_to_end(&mut bp))
+            .map_err(|e| format!("Cannot read modell.3dbp: {}", e)) );
+        self.start_split(eventloop, &bp, "local job", mode)
+    }
+
+    //Aborts the remaining shards of a failed split job and continues those waiting for the others' layers
+    fn coordinate_splits(self : &mut Self, eventloop : &mut EventLoop<Server>) {
+        let clients = self.clients.clone();
+        for cell in clients.values() {
+            let (failure, waiting) = {
+                let part = cell.borrow();
+                match part.shard {
+                    Some(ref shard) => (shard.failure(), part.layer_wait),
+                    None => continue
+                }
+            };
+            if let Some(reason) = failure {
+                let mut printhead = cell.borrow_mut();
+                printhead.abort_job(&format!("other shard failed: {}", reason));
+                self.jobs.update(&mut printhead);
+            }
+            else if waiting {
+                self.continue_printhead(eventloop, cell);
+            }
+        }
+    }
+
+    //Operator has loaded the new material, returns the printheads continuing their tool change
+    fn confirm_toolchange(self : &mut Self, eventloop : &mut EventLoop<Server>, printhead : Option<usize>) -> Result<Vec<usize>, String> {
+        let mut confirmed = Vec::new();
+        for cell in try!( self.job_targets(printhead) ) {
+            {
+                let mut part = cell.borrow_mut();
+                match part.toolchange {
+                    Some(ref mut toolchange) if !toolchange.confirmed => toolchange.confirmed = true,
+                    _ => continue
+                }
+                println!("Printhead({}): Tool change confirmed", part.id);
+                confirmed.push(part.id);
+            }
+            self.continue_printhead(eventloop, &cell);
+        }
+        Ok(confirmed)
+    }
+
+    //Returns the ids of the benchmarked printhead and of the benchmark
+    fn benchmark(self : &mut Self, eventloop : &mut EventLoop<Server>, spec : BenchmarkSpec, printhead : Option<usize>) -> Result<(usize, usize), String> {
+        let cell = try!( match printhead {
+            Some(id) => self.get_printhead(id).ok_or(format!("Unknown printhead {}", id)),
+            None => self.get_free_printhead().ok_or("no printhead".to_string())
+        } );
+        let mut printhead = cell.borrow_mut();
+        if printhead.blueprint.is_some() || printhead.timeoutid.is_some() || printhead.benchmark.is_some() {
+            return Err(format!("Printhead({}) busy", printhead.id));
+        }
+        if printhead.halted {
+            return Err("emergency stop active".to_string());
+        }
+        println!("Benchmarking printhead({}): {} commands, mix {:?}", printhead.id, spec.count, spec.mix);
+        let benchmark_id = printhead.start_benchmark(spec);
+        printhead.continue_benchmark(eventloop);
+        Ok((printhead.id, benchmark_id))
+    }
+
+    fn get_mat_src(self : &Self, required_mat_id : i32) -> Option<PartCell> {
+        let clients = &self.clients;
+        let mut substitute = None;
+        let substitute_id = self.config.mat_substitutes.get(&required_mat_id);
+        for cell in clients.values() {
+            let part = cell.borrow();
+            if part.parttype != PrinterPartType::Material || part.matempty || !part.healthy {
+                continue; //Empty or unresponsive containers only pause the printheads using them
+            }
+            if part.matid == required_mat_id {
+                return Some(cell.clone());
+            }
+            if Some(&part.matid) == substitute_id {
+                substitute = Some(cell.clone());
+            }
+        }
+        substitute
+    }
+
+    //Printheads a command applies to, all with a job if none is given
+    fn job_targets(self : &Self, printhead : Option<usize>) -> Result<Vec<PartCell>, String> {
+        match printhead {
+            Some(id) => self.get_printhead(id).map(|cell| vec![cell]).ok_or(format!("Unknown printhead {}", id)),
+            None => Ok( self.clients.values()
+                .filter(|cell| cell.borrow().blueprint.is_some())
+                .cloned()
+                .collect() )
+        }
+    }
+
+    //Starts a job on the given or a free printhead, a split job on all free printheads.
+    //Returns the job id and the printheads working on it.
+    fn submit(self : &mut Self, eventloop : &mut EventLoop<Server>, bp : Vec<u8>, title : &str, split : Option<&str>, printhead : Option<usize>) -> Result<(usize, Vec<usize>), String> {
+        if self.estop.is_some() {
+            return Err("emergency stop active".to_string());
+        }
+        if let Some(mode) = split {
+            let mode = try!( SplitMode::parse(mode) );
+            return self.start_split(eventloop, &bp, title, mode);
+        }
+        if let Some(volume) = self.config.build_volume {
+            try!( volume.check_blueprint(&bp) );
+        }
+        let cell = try!( match printhead {
+            Some(id) => self.get_printhead(id).ok_or(format!("Unknown printhead {}", id)),
+            None => self.get_free_printhead().ok_or("no printhead".to_string())
+        } );
+        let mut printhead = cell.borrow_mut();
+        if printhead.blueprint.is_some() || printhead.timeoutid.is_some() {
+            return Err(format!("Printhead({}) busy", printhead.id));
+        }
+
+        let job_id = get_new_job_id();
+        let spooled = try!( journal::spool_blueprint(job_id, &bp) );
+        if let Err(e) = printhead.start_job( job_id, title.to_string(), Box::new(spooled), bp.len() as u64 ) {
+            let _ = fs::remove_file(journal::spool_path(job_id));
+            return Err(e);
+        }
+        println!("Started printing job '{}' on printhead({})", title, printhead.id);
+        let event = Event::job_started(printhead.id, job_id, title);
+        printhead.events.push(event);
+        printhead.exec_instr( eventloop, None ); //First instruction cannot use a Material, since it could not possibly have selected one
+        self.jobs.update(&mut printhead);
+        Ok((job_id, vec![printhead.id]))
+    }
+
+    //Returns the printheads whose job was cancelled
+    fn cancel(self : &mut Self, printhead : Option<usize>) -> Result<Vec<usize>, String> {
+        let mut cancelled = Vec::new();
+        for cell in try!( self.job_targets(printhead) ) {
+            let mut printhead = cell.borrow_mut();
+            if printhead.blueprint.is_some() {
+                printhead.abort_job("cancelled");
+                cancelled.push(printhead.id);
+                self.jobs.update(&mut printhead);
+            }
+        }
+        Ok(cancelled)
+    }
+
+    //After a part was serviced, clears its service alerts
+    fn reset_maintenance(self : &mut Self, key : &PartKey) -> Result<(), String> {
+        try!( self.maintenance.reset(key) );
+        for cell in self.clients.values() {
+            let mut part = cell.borrow_mut();
+            if maintenance::part_key(&part) == *key {
+                part.service_due.clear();
+            }
+        }
+        self.publish_status();
+        Ok(())
+    }
+
+    fn remote_command(self : &mut Self, eventloop : &mut EventLoop<Server>, request : RemoteRequest) -> Result<RemoteReply, String> {
+        let cmd = request.cmd;
+        println!("Remote command '{}' [{}]", cmd.command, cmd.request_id);
+        let mut affected = Vec::new();
+        match &cmd.command[..] {
+            "start" => {
+                let bp = try!( request.blueprint );
+                let title = cmd.title.clone().unwrap_or("remote job".to_string());
+                let split = cmd.split.as_ref().map(|mode| &mode[..]);
+                let (job_id, printheads) = try!( self.submit(eventloop, bp, &title, split, cmd.printhead) );
+                return Ok( RemoteReply { job_id: Some(job_id), printheads: printheads, ..RemoteReply::ok(&cmd.request_id) } );
             },
-            Some(printhead) => {
-                let mut printhead = printhead.write().unwrap();
-                println!("Benchmarking printhead({})", printhead.id);
-                printhead.benchmarkcnt = 10000;
-                unsafe{BenchWatchStopTime = time::precise_time_ns();}
-                printhead.socket.write(&[1,57,5,0,0,0]).unwrap();
-                printhead.timeoutid = Some(eventloop.timeout(printhead.id, Duration::from_millis(PRINT_TIMEOUT_MS)).unwrap());
+            "pause" => {
+                for cell in try!( self.job_targets(cmd.printhead) ) {
+                    let mut printhead = cell.borrow_mut();
+                    if printhead.blueprint.is_some() && !printhead.paused {
+                        println!("Pausing printhead({})", printhead.id);
+                        printhead.paused = true;
+                        affected.push(printhead.id);
+                    }
+                }
+            },
+            "resume" => {
+                for cell in try!( self.job_targets(cmd.printhead) ) {
+                    if !cell.borrow().paused {
+                        continue;
+                    }
+                    println!("Resuming printhead({})", cell.borrow().id);
+                    cell.borrow_mut().paused = false;
+                    affected.push(cell.borrow().id);
+                    self.continue_printhead(eventloop, &cell);
+                }
+            },
+            "cancel" => {
+                affected = try!( self.cancel(cmd.printhead) );
+            },
+            "confirm" => { //Tool change
+                affected = try!( self.confirm_toolchange(eventloop, cmd.printhead) );
+            },
+            "estop" | "estop_reset" => {
+                let by = match cmd.operator {
+                    Some(ref operator) => format!("remote {} [{}]", operator, cmd.request_id),
+                    None => format!("remote [{}]", cmd.request_id)
+                };
+                if cmd.command == "estop" {
+                    self.emergency_stop(&by);
+                }
+                else {
+                    self.reset_emergency_stop(&by);
+                }
+            },
+            "benchmark" => {
+                let spec = try!( BenchmarkSpec::new(cmd.count, cmd.mix.as_ref()) );
+                let (printhead, benchmark_id) = try!( self.benchmark(eventloop, spec, cmd.printhead) );
+                return Ok( RemoteReply { benchmark_id: Some(benchmark_id), printheads: vec![printhead], ..RemoteReply::ok(&cmd.request_id) } );
+            },
+            "status" => {
+                return Ok( RemoteReply { status: Some(status::collect(&self.clients, &self.config, &self.estop)), ..RemoteReply::ok(&cmd.request_id) } );
+            },
+            other => return Err(format!("Unknown command '{}'", other))
+        }
+        Ok( RemoteReply { printheads: affected, ..RemoteReply::ok(&cmd.request_id) } )
+    }
+
+    fn handle_remote_command(self : &mut Self, eventloop : &mut EventLoop<Server>, request : RemoteRequest) {
+        let request_id = request.cmd.request_id.clone();
+        let reply = match self.remote_command(eventloop, request) {
+            Ok(reply) => reply,
+            Err(e) => {
+                println!("Remote command [{}] failed: {}", request_id, e);
+                RemoteReply::failed(&request_id, &e)
+            }
+        };
+        self.events.reply(&reply);
+        self.publish_status();
+    }
+
+    fn rest_request(self : &mut Self, eventloop : &mut EventLoop<Server>, request : Request) -> Reply {
+        match request {
+            Request::Status => Reply::Body( json::encode(&status::collect(&self.clients, &self.config, &self.estop)).unwrap() ),
+            Request::Parts(None) => Reply::Body( rest::parts::list(&self.clients) ),
+            Request::Parts(Some(id)) => Reply::found( rest::parts::get(&self.clients, id) ),
+            Request::Latency => Reply::Body( rest::latency::get(&self.clients) ),
+            Request::Metrics => Reply::Body( metrics::render(&self.clients) ),
+            Request::Benchmarks(None) => Reply::Body( rest::benchmarks::list(&self.clients, &self.benchmarks) ),
+            Request::Benchmarks(Some(id)) => Reply::found( rest::benchmarks::get(&self.clients, &self.benchmarks, id) ),
+            Request::Telemetry(None) => Reply::Body( rest::telemetry::latest(&self.clients) ),
+            Request::Telemetry(Some(quantity)) => Reply::Body( rest::telemetry::history(&self.clients, quantity) ),
+            Request::Maintenance(None) => Reply::Body( rest::maintenance::list(&self.maintenance) ),
+            Request::Maintenance(Some(key)) => Reply::found( rest::maintenance::get(&self.maintenance, &key) ),
+            Request::Jobs(query) => Reply::Body( rest::history::list(&self.jobs.history, &query) ),
+            Request::Job(job_id) => Reply::found( rest::history::get(&self.jobs.history, job_id) ),
+            Request::Bed(job_id, export) => match rest::bed_export::render(&self.jobs.finished, job_id, export, self.config.voxel_size) {
+                Ok(Some((output, mime))) => Reply::Export(output, mime),
+                Ok(None) => Reply::NotFound,
+                Err(e) => Reply::Unprocessable( rest::bed_export::error(&e) )
+            },
+            Request::Print { blueprint, title, split } => {
+                let split = split.as_ref().map(|mode| &mode[..]);
+                Reply::Body( rest::jobs::started(self.submit(eventloop, blueprint, &title, split, None)) )
+            },
+            Request::Cancel(printhead) => Reply::Body( rest::jobs::cancelled(self.cancel(printhead)) ),
+            Request::Benchmark { spec, printhead } => Reply::Body( rest::benchmarks::started(self.benchmark(eventloop, spec, printhead)) ),
+            Request::ResetMaintenance(key) => Reply::Body( rest::maintenance::reset_reply(self.reset_maintenance(&key)) ),
+            Request::EmergencyStop { reset, by } => {
+                let changed = if reset { self.reset_emergency_stop(&by) } else { self.emergency_stop(&by) };
+                Reply::Body( rest::estop::reply(changed, reset) )
+            }
+        }
+    }
+
+    fn publish_events(self : &mut Self) {
+        let mut published = false;
+        let clients = self.clients.clone();
+        for cell in clients.values() {
+            let (events, reports, samples) = {
+                let mut part = cell.borrow_mut();
+                self.maintenance.collect(&mut part, &self.config.service_intervals);
+                let events : Vec<Event> = part.events.drain(..).collect();
+                (events, part.finished_benchmarks.drain(..).collect::<Vec<_>>(), part.samples.drain(..).collect::<Vec<_>>())
+            };
+            for report in reports {
+                benchmark::store(&mut self.benchmarks, report);
+            }
+            for sample in samples {
+                self.events.telemetry(&sample);
             }
+            for event in events {
+                self.events.publish(event);
+                published = true;
+            }
+        }
+        if published {
+            self.publish_status();
         }
     }
 
-    fn get_mat_src(self : &Self, required_mat_id : i32) -> Option<Arc<RwLock<Printerpart>>> {
-        if self.check_mat_status() {
-            let clients = self.clients.read().unwrap();
-            for cell in clients.values() {
-                let part = cell.read().unwrap();
-                if part.parttype == PrinterPartType::Material
-                        && part.matid == required_mat_id {
-                    return Some(cell.clone());
+    //Sends the targets of heating jobs to the heaters, the jobs continue once the heater of their zone is close enough
+    fn coordinate_heating(self : &mut Self, eventloop : &mut EventLoop<Server>) {
+        let clients = self.clients.clone();
+        for cell in clients.values() {
+            let heating = match cell.borrow().heating {
+                Some(heating) => heating,
+                None => continue
+            };
+            let heater = clients.values().find(|heater| {
+                let heater = heater.borrow();
+                heater.parttype == PrinterPartType::Heater && heater.healthy && heater.heater_zone == Some(heating.zone)
+            }).cloned();
+            let heater = match heater {
+                Some(heater) => heater,
+                None => {
+                    //Waits for a heater of the zone to connect, the job fails if none does in time
+                    let mut printhead = cell.borrow_mut();
+                    match heating.missing_since {
+                        None => {
+                            println!("Printhead({}): No heater for {} connected", printhead.id, heating.zone.name());
+                            if let Some(job_id) = printhead.job_id {
+                                let event = Event::heater_missing(printhead.id, job_id, heating.zone.name());
+                                printhead.events.push(event);
+                            }
+                            printhead.heating.as_mut().unwrap().missing_since = Some(now_ms());
+                        },
+                        Some(since) if now_ms() - since >= self.config.heater_timeout_ms as i64 => {
+                            printhead.abort_job(&format!("no heater for {}", heating.zone.name()));
+                            self.jobs.update(&mut printhead);
+                        },
+                        Some(_) => {}
+                    }
+                    continue;
                 }
+            };
+            if heating.missing_since.is_some() {
+                cell.borrow_mut().heating.as_mut().unwrap().missing_since = None;
+            }
+            let reached = {
+                let mut heater = heater.borrow_mut();
+                if heater.heater_target != Some(heating.target) {
+                    println!("Heater({}): Target {} {}", heater.id, heating.zone.name(), heating.target);
+                    heater.set_heater_target(heating.target);
+                }
+                heater.telemetry.latest_of(heating.zone)
+                    .map(|reading| (reading.value - heating.target).abs() <= self.config.heater_tolerance)
+                    .unwrap_or(false)
+            };
+            if !reached {
+                continue;
+            }
+            {
+                let mut printhead = cell.borrow_mut();
+                printhead.heating_done();
+                self.jobs.update(&mut printhead); //Journals the temperature command as done
+            }
+            self.continue_printhead(eventloop, cell);
+        }
+    }
+
+    //Printheads hold while a sensor reads outside its limits, like they do for an empty container
+    fn check_environment(self : &mut Self, eventloop : &mut EventLoop<Server>) {
+        let clients = self.clients.clone();
+        //Alarms of disconnected sensors last until a healthy sensor reads the quantity within its limits again
+        self.lost_alarms.retain(|&(quantity, since)| {
+            let cleared = clients.values().any(|cell| {
+                let part = cell.borrow();
+                part.parttype == PrinterPartType::Sensor && part.healthy && !part.telemetry.alarms.contains_key(&quantity)
+                    && part.telemetry.latest_of(quantity).map_or(false, |reading| reading.timestamp >= since)
+            });
+            if cleared {
+                println!("{} is back in range, releasing the hold of the disconnected sensor", quantity.name());
+            }
+            !cleared
+        });
+        let alarm = !self.lost_alarms.is_empty() || clients.values().any(|cell| {
+            let part = cell.borrow();
+            part.parttype == PrinterPartType::Sensor && !part.telemetry.alarms.is_empty()
+        });
+        for cell in clients.values() {
+            {
+                let mut part = cell.borrow_mut();
+                if part.parttype != PrinterPartType::Printhead || part.env_hold == alarm {
+                    continue;
+                }
+                part.env_hold = alarm;
+                if alarm && part.blueprint.is_some() {
+                    println!("Printhead({}): Holding print until the environment is within limits", part.id);
+                }
+            }
+            if !alarm {
+                self.continue_printhead(eventloop, cell);
+            }
+        }
+    }
+
+    //Returns false if a stop was already active
+    fn emergency_stop(self : &mut Self, by : &str) -> bool {
+        let triggered = estop::trigger(&mut self.estop, by);
+        if triggered {
+            self.events.publish( Event::emergency_stop(Some(by), true) );
+            self.jobs.discard_interrupted(); //Jobs are not resumed after an emergency stop
+        }
+        else {
+            println!("Emergency stop already active");
+        }
+        self.enforce_emergency_stop();
+        triggered
+    }
+
+    //Returns false if no stop was active
+    fn reset_emergency_stop(self : &mut Self, by : &str) -> bool {
+        let reset = estop::reset(&mut self.estop, by);
+        if reset {
+            self.events.publish( Event::emergency_stop(None, false) );
+        }
+        else {
+            println!("No emergency stop to reset");
+        }
+        self.enforce_emergency_stop();
+        reset
+    }
+
+    //Stops every printhead and switches the heaters off while an emergency stop is active, lifts it once it was reset
+    fn enforce_emergency_stop(self : &mut Self) {
+        let active = self.estop.is_some();
+        let clients = self.clients.clone();
+        for cell in clients.values() {
+            let mut part = cell.borrow_mut();
+            match part.parttype {
+                PrinterPartType::Printhead if active && !part.halted => {
+                    println!("Printhead({}): Emergency stop", part.id);
+                    part.emergency_stop();
+                    self.jobs.update(&mut part);
+                },
+                PrinterPartType::Printhead if !active && part.halted => {
+                    part.halted = false;
+                },
+                PrinterPartType::Heater if active && part.heater_target != Some(0.0) => {
+                    println!("Heater({}): Switching off", part.id);
+                    part.set_heater_target(0.0);
+                },
+                _ => {}
             }
-            None
-        } else {
-            None //If production is paused until refill, don't supply MatContainer references
         }
+        self.publish_status();
+    }
+
+    //Pings the idle parts, health changes are published as events
+    fn heartbeat(self : &mut Self, eventloop : &mut EventLoop<Server>) {
+        let clients = self.clients.clone();
+        for cell in clients.values() {
+            cell.borrow_mut().heartbeat(self.config.heartbeat_missed);
+        }
+        //Clients that stay silent as long as it takes to mark a part unhealthy never register
+        let limit = now_ms() - (self.config.heartbeat_interval_ms * self.config.heartbeat_missed as u64) as i64;
+        let silent : Vec<Token> = self.handshakes.iter().filter(|&(_, handshake)| handshake.connected_at < limit)
+            .map(|(token, _)| *token).collect();
+        for token in silent {
+            println!("New client did not register in time, disconnecting");
+            self.drop_handshake(eventloop, token);
+        }
+        self.jobs.journal.flush(); //Progress of jobs that have stalled since the last write
+        self.maintenance.flush(); //Wear of parts that went idle since the last write
+        eventloop.timeout(HEARTBEAT_TIMEOUT, Duration::from_millis(self.config.heartbeat_interval_ms)).unwrap();
+    }
+
+    //Event stream clients get the new state after every change
+    fn publish_status(self : &mut Self) {
+        let status = status::collect(&self.clients, &self.config, &self.estop);
+        self.events.stream_status(&status);
     }
 }
 
 impl Handler for Server {
     type Timeout = usize;
-    type Message = Token;
+    type Message = Message;
 
-    fn ready(&mut self, eventloop: &mut EventLoop<Server>, token: Token, _: EventSet)
+    fn ready(&mut self, eventloop: &mut EventLoop<Server>, token: Token, events: EventSet)
     {
         match token {
             SERVER_TOKEN => {
@@ -139,14 +762,49 @@ impl Handler for Server {
             CLI_TOKEN => {
                 let mut input = String::new();
                 stdin().read_line(&mut input).unwrap();
-                match input.trim() {
+                let args : Vec<&str> = input.split_whitespace().collect();
+                match args.first().cloned().unwrap_or("") {
                     "p" => {
                         self.start_print(eventloop);
                     },
-                    "b" => {
-                        self.benchmark(eventloop);
+                    "s" => { //s [region|layers], splits the local blueprint across all free printheads
+                        if let Err(e) = self.split_local(eventloop, args.get(1).cloned().unwrap_or("region")) {
+                            println!("Split job not started: {}", e);
+                        }
+                    },
+                    "b" => { //b [count] [level=1,dot=5,line=2]
+                        let started = BenchmarkSpec::parse(&args[1..])
+                            .and_then(|spec| self.benchmark(eventloop, spec, None));
+                        if let Err(e) = started {
+                            println!("Benchmark not started: {}", e);
+                        }
                     }
+                    "t" => { //t [printhead], confirms pending tool changes
+                        let printhead = args.get(1).and_then(|id| id.parse().ok());
+                        match self.confirm_toolchange(eventloop, printhead) {
+                            Ok(ref confirmed) if confirmed.is_empty() => println!("No tool change to confirm"),
+                            Ok(_) => {},
+                            Err(e) => println!("{}", e)
+                        }
+                    },
+                    "e" => { //e [reset]
+                        if args.get(1) == Some(&"reset") {
+                            self.reset_emergency_stop("console");
+                        }
+                        else {
+                            self.emergency_stop("console");
+                        }
+                    },
+                    "r" => {
+                        self.resume_jobs(eventloop);
+                    },
+                    "d" => {
+                        self.jobs.discard_interrupted();
+                    },
                     "q" => {
+                        self.maintenance.checkpoint();
+                        self.jobs.journal.flush();
+                        self.events.go_offline();
                         eventloop.shutdown();
                     },
                     _ => {
@@ -155,80 +813,114 @@ impl Handler for Server {
                 }
             },
             token => {
-                let clients = self.clients.read().unwrap();
-                let client = clients.get(&token).unwrap();
-
-                let parttype = client.read().unwrap().parttype;
-
-                match parttype {
-                    PrinterPartType::Printhead => {
-                        let matid = client.read().unwrap().matid;
-                        match self.get_mat_src(matid) {
-                            Some(mat_src) => {
-                                client.write().unwrap().notify_printhead( eventloop, Some( mat_src.write().unwrap().deref_mut() ) );
-                            },
-                            None => {
-                                client.write().unwrap().notify_printhead( eventloop, None );
-                            }
-                        }
-                        if client.read().unwrap().blueprint.is_none() {
-                            self.msgclient.send(format!("{}",
-                                &client.read().unwrap().job_title.as_ref().unwrap_or(&"-".to_string())).as_bytes(),
-                                "printInfo", Qos::OnceAndOneOnly, false);
-                        }
-                    },
-                    PrinterPartType::Material  => { client.write().unwrap().notify_material(eventloop, &mut self.continuedelay);  }
+                if self.handshakes.contains_key(&token) {
+                    if events.is_hup() {
+                        println!("New client disconnected before registering");
+                        self.drop_handshake(eventloop, token);
+                        return;
+                    }
+                    if !self.continue_handshake(eventloop, token) {
+                        return;
+                    }
+                    //Anything sent right after the registration was not read yet
+                }
+                let mut sensor = false;
+                let connected = events.is_readable() && {
+                    let clients = &self.clients;
+                    let client = match clients.get(&token) {
+                        Some(client) => client.clone(),
+                        None => return //Already disconnected
+                    };
+
+                    let parttype = client.borrow().parttype;
+
+                    match parttype {
+                        PrinterPartType::Printhead => {
+                            let matid = client.borrow().required_matid();
+                            let connected = match self.get_mat_src(matid) {
+                                Some(mat_src) => {
+                                    client.borrow_mut().notify_printhead( eventloop, Some( mat_src.borrow_mut().deref_mut() ) )
+                                },
+                                None => {
+                                    client.borrow_mut().notify_printhead( eventloop, None )
+                                }
+                            };
+                            self.jobs.update(&mut client.borrow_mut());
+                            connected
+                        },
+                        PrinterPartType::Material  => { client.borrow_mut().notify_material(eventloop, &mut self.continuedelay) },
+                        PrinterPartType::Sensor => {
+                            sensor = true;
+                            client.borrow_mut().notify_sensor()
+                        },
+                        PrinterPartType::Heater => { client.borrow_mut().notify_sensor() }
+                    }
                 };
+                if sensor {
+                    self.check_environment(eventloop);
+                }
+                if !connected || events.is_hup() {
+                    self.disconnect(eventloop, token);
+                }
             }
         }
+        self.coordinate_splits(eventloop);
+        self.coordinate_heating(eventloop);
+        self.publish_events();
     }
     fn timeout(&mut self, eventloop: &mut EventLoop<Server>, timeout_token: usize) {
         match timeout_token {
             0 => { //Timeout id 0 is check for continue
-                if self.check_mat_status() {
-                    println!("All material containers refilled!");
-                    for cell in self.clients.read().unwrap().values() {
-                        let parttype = cell.read().unwrap().parttype;
-                        let has_bp    = cell.read().unwrap().blueprint.is_some();
-                        if parttype == PrinterPartType::Printhead && has_bp {
-                            println!("Continuing on printhead {}", cell.read().unwrap().id );
-                            let matid = cell.read().unwrap().matid;
-                            match self.get_mat_src(matid) {
-                                Some(mat_src) => {
-                                    cell.write().unwrap().exec_instr( eventloop, Some(mat_src.write().unwrap().deref_mut()) );
-                                },
-                                None => {
-                                    cell.write().unwrap().exec_instr( eventloop, None );
-                                }
-                            }
-                            if cell.read().unwrap().blueprint.is_none() {
-                                self.msgclient.send(format!("{}", &cell.read().unwrap().job_title.as_ref().unwrap()).as_bytes(),
-                                    "printInfo", Qos::OnceAndOneOnly, false);
-                            }
-                        }
+                let clients = self.clients.clone();
+                for cell in clients.values() {
+                    let (matwait, paused) = {
+                        let part = cell.borrow();
+                        (part.matwait, part.paused)
+                    };
+                    let matid = match matwait {
+                        Some(matid) => matid,
+                        None => continue //Only printheads paused for material need to be continued
+                    };
+                    if paused {
+                        continue; //Continued when resumed
                     }
+                    if !self.check_mat_status(matid) {
+                        println!("Printhead {} still missing material {}...", cell.borrow().id, matid);
+                        continue;
+                    }
+                    println!("Material {} refilled, continuing on printhead {}", matid, cell.borrow().id);
+                    self.continue_printhead(eventloop, cell);
                 }
-                else {
-                    println!("Still missing material...");
-                }
+            }
+            HEARTBEAT_TIMEOUT => {
+                self.heartbeat(eventloop);
             }
             _ => {
                 println!("Timeout while printing, aborting...");
-                let clients = self.clients.read().unwrap();
-                let mut connection = clients.get(&Token(timeout_token)).unwrap().write().unwrap();
-                connection.set_blueprint(None); //Abort print process
-                connection.abort_benchmark(); //Abort benchmark process
+                let clients = &self.clients;
+                if let Some(cell) = clients.get(&Token(timeout_token)) {
+                    let mut connection = cell.borrow_mut();
+                    connection.command_timeout();
+                    self.jobs.update(&mut connection);
+                }
             }
         };
+        self.coordinate_splits(eventloop);
+        self.coordinate_heating(eventloop);
+        self.publish_events();
     }
-    fn notify(&mut self, eventloop: &mut EventLoop<Server>, msg: Token) {
-        //external interface has loaded Blueprint into Printhead
-        //send first command and implement timeout etc.
-        let clients = self.clients.read().unwrap();
-        let printhead = clients.get(&msg).unwrap();
-        self.msgclient.send(format!("Started printing {}", &printhead.read().unwrap().job_title.as_ref().unwrap()).as_bytes(),
-            "printInfo", Qos::OnceAndOneOnly, false);
-        printhead.write().unwrap().exec_instr( eventloop, None );
-            //First instruction cannot use a Material, since it could not possibly have selected one
+    fn notify(&mut self, eventloop: &mut EventLoop<Server>, msg: Message) {
+        match msg {
+            Message::Remote(request) => {
+                self.handle_remote_command(eventloop, request);
+            },
+            Message::Rest(request, reply_to) => {
+                let reply = self.rest_request(eventloop, request);
+                reply_to.send(reply);
+            }
+        }
+        self.coordinate_splits(eventloop);
+        self.coordinate_heating(eventloop);
+        self.publish_events();
     }
 }
@@ -0,0 +1,472 @@
+use std::cmp;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::usize;
+
+use super::{MaterialUsage, Parts};
+use super::printerpart::add_usage;
+use super::{blueprint, journal, history, get_new_job_id};
+use super::blueprint::Command;
+use events::{Event, now_ms};
+use status;
+
+//A blueprint split across several printheads of the panel.
+//Layers are printed in order: a shard may only send a command of layer L once every other shard
+//has all its commands below L acknowledged, so within a layer the shards print side by side.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SplitMode {
+    Region, //Every layer is divided into bands along x, one per printhead
+    Layers  //Each printhead gets a contiguous range of layers
+}
+
+impl SplitMode {
+    pub fn parse(mode : &str) -> Result<SplitMode, String> {
+        match mode {
+            "region" => Ok(SplitMode::Region),
+            "layers" => Ok(SplitMode::Layers),
+            other => Err(format!("unknown split mode '{}'", other))
+        }
+    }
+}
+
+//Blueprint of one printhead, with the global layer index at the offset every layer starts at
+pub struct ShardPlan {
+    pub data: Vec<u8>,
+    pub layer_starts: Vec<(u64, usize)>
+}
+
+//Raw commands of one layer, the level command is None for commands before the first level
+struct Layer<'a> {
+    level: Option<&'a [u8]>,
+    commands: Vec<(&'a [u8], Command)>
+}
+
+fn layers(bp : &[u8]) -> Result<Vec<Layer>, String> {
+    let commands = try!(blueprint::parse(bp));
+    let mut layers = Vec::new();
+    for (index, &(offset, command)) in commands.iter().enumerate() {
+        let end = commands.get(index + 1).map(|&(next, _)| next).unwrap_or(bp.len());
+        let raw = &bp[offset .. end];
+        match command {
+            Command::Level { .. } => layers.push( Layer { level: Some(raw), commands: Vec::new() } ),
+            _ => {
+                if layers.is_empty() {
+                    layers.push( Layer { level: None, commands: Vec::new() } );
+                }
+                layers.last_mut().unwrap().commands.push((raw, command));
+            }
+        }
+    }
+    Ok(layers)
+}
+
+//Lowest and highest x a command prints at, None for commands without a position
+fn command_xs(command : &Command) -> Option<(i32, i32)> {
+    match *command {
+        Command::Dot { x, .. } => Some((x, x)),
+        Command::Line { x1, x2, .. } => Some((cmp::min(x1, x2), cmp::max(x1, x2))),
+        Command::Level { .. } | Command::Temperature { .. } => None
+    }
+}
+
+//What a shard prints of a layer: a command as it is or the part of a line within its band
+enum Piece {
+    Command(usize),
+    Clipped(Vec<u8>)
+}
+
+fn line_command(x1 : i32, y1 : i32, x2 : i32, y2 : i32) -> Vec<u8> {
+    let mut raw = vec![3];
+    for value in &[x1, y1, x2, y2] {
+        raw.extend_from_slice(&[*value as u8, (*value >> 8) as u8, (*value >> 16) as u8, (*value >> 24) as u8]);
+    }
+    raw
+}
+
+//Equal bands along x, the last one reaches up to the highest x
+struct Bands {
+    min_x: i64,
+    width: i64,
+    count: usize
+}
+
+impl Bands {
+    fn shard(&self, x : i32) -> usize {
+        cmp::min( ((x as i64 - self.min_x) / self.width) as usize, self.count - 1 )
+    }
+
+    fn start(&self, shard : usize) -> i64 {
+        self.min_x + shard as i64 * self.width
+    }
+
+    //Cuts a line at the band edges, so every head stays within its own band
+    fn clip(&self, x1 : i32, y1 : i32, x2 : i32, y2 : i32) -> Vec<(usize, Vec<u8>)> {
+        let (x1, y1, x2, y2) = if x1 <= x2 { (x1, y1, x2, y2) } else { (x2, y2, x1, y1) };
+        let y_at = |x : i64| -> i32 {
+            (y1 as i64 + (y2 as i64 - y1 as i64) * (x - x1 as i64) / (x2 as i64 - x1 as i64)) as i32
+        };
+        let (first, last) = (self.shard(x1), self.shard(x2));
+        (first .. last + 1).map(|shard| {
+            let from = if shard == first { x1 as i64 } else { self.start(shard) };
+            let to = if shard == last { x2 as i64 } else { self.start(shard + 1) - 1 };
+            (shard, line_command(from as i32, y_at(from), to as i32, y_at(to)))
+        }).collect()
+    }
+}
+
+//What every shard prints of each layer
+fn assign(layers : &[Layer], shards : usize, mode : SplitMode) -> Vec<Vec<Vec<Piece>>> {
+    let mut assigned : Vec<Vec<Vec<Piece>>> = (0 .. shards).map(|_| layers.iter().map(|_| Vec::new()).collect()).collect();
+    match mode {
+        SplitMode::Region => {
+            let xs : Vec<(i32, i32)> = layers.iter().flat_map(|layer| layer.commands.iter().filter_map(|&(_, ref command)| command_xs(command))).collect();
+            let min_x = xs.iter().map(|&(low, _)| low).min().unwrap_or(0) as i64;
+            let max_x = xs.iter().map(|&(_, high)| high).max().unwrap_or(0) as i64;
+            let bands = Bands {
+                min_x: min_x,
+                width: cmp::max( (max_x - min_x + 1 + shards as i64 - 1) / shards as i64, 1 ),
+                count: shards
+            };
+            for (layer_index, layer) in layers.iter().enumerate() {
+                for (command_index, &(_, ref command)) in layer.commands.iter().enumerate() {
+                    match *command {
+                        Command::Dot { x, .. } => assigned[bands.shard(x)][layer_index].push(Piece::Command(command_index)),
+                        Command::Line { x1, y1, x2, y2 } if bands.shard(x1) != bands.shard(x2) => {
+                            for (shard, raw) in bands.clip(x1, y1, x2, y2) {
+                                assigned[shard][layer_index].push(Piece::Clipped(raw));
+                            }
+                        },
+                        Command::Line { x1, .. } => assigned[bands.shard(x1)][layer_index].push(Piece::Command(command_index)),
+                        //Every head has to wait for the temperature
+                        Command::Level { .. } | Command::Temperature { .. } => for shard_commands in assigned.iter_mut() {
+                            shard_commands[layer_index].push(Piece::Command(command_index));
+                        }
+                    }
+                }
+            }
+        },
+        SplitMode::Layers => {
+            //Contiguous layer ranges with about the same number of commands
+            let total = layers.iter().fold(0, |total, layer| total + layer.commands.len());
+            let per_shard = cmp::max( (total + shards - 1) / shards, 1 );
+            let mut shard = 0;
+            let mut in_shard = 0;
+            for (layer_index, layer) in layers.iter().enumerate() {
+                if in_shard >= per_shard && shard + 1 < shards {
+                    shard += 1;
+                    in_shard = 0;
+                }
+                assigned[shard][layer_index] = (0 .. layer.commands.len()).map(Piece::Command).collect();
+                in_shard += layer.commands.len();
+            }
+        }
+    }
+    assigned
+}
+
+//Shards without anything to print are left out. In region mode every shard moves through every layer,
+//so all heads change levels together.
+pub fn plan(bp : &[u8], shards : usize, mode : SplitMode) -> Result<Vec<ShardPlan>, String> {
+    if shards == 0 {
+        return Err("no printhead".to_string());
+    }
+    let layers = try!(layers(bp));
+    let mut plans = Vec::new();
+    for layer_pieces in assign(&layers, shards, mode) {
+        let mut data = blueprint::MAGIC.to_vec();
+        let mut layer_starts = Vec::new();
+        let mut prints = false;
+        for (layer_index, pieces) in layer_pieces.iter().enumerate() {
+            let level = layers[layer_index].level;
+            if pieces.is_empty() && (mode == SplitMode::Layers || level.is_none()) {
+                continue;
+            }
+            layer_starts.push((data.len() as u64, layer_index));
+            if let Some(level) = level {
+                data.extend_from_slice(level);
+            }
+            for piece in pieces {
+                match *piece {
+                    Piece::Command(command_index) => {
+                        let &(raw, ref command) = &layers[layer_index].commands[command_index];
+                        prints = prints || command_xs(command).is_some();
+                        data.extend_from_slice(raw);
+                    },
+                    Piece::Clipped(ref raw) => {
+                        prints = true;
+                        data.extend_from_slice(raw);
+                    }
+                }
+            }
+        }
+        if prints {
+            plans.push( ShardPlan { data: data, layer_starts: layer_starts } );
+        }
+    }
+    if plans.is_empty() {
+        return Err("blueprint has nothing to print".to_string());
+    }
+    Ok(plans)
+}
+
+//Shared by the printheads of a split job
+pub struct SplitJob {
+    pub job_id: usize,
+    pub title: String,
+    blueprint_hash: String, //Of the whole blueprint
+    started_at: i64,
+    acked_layers: Vec<usize>, //Per shard the layer of the first unacknowledged command, MAX once finished
+    failure: Option<String>,
+    mat_usage: Vec<MaterialUsage>, //Of all finished shards
+    commands: usize //Acknowledged by all finished shards
+}
+
+pub type SharedSplit = Arc<Mutex<SplitJob>>;
+
+//A split job once all of its shards have finished, reported as one job
+pub struct SplitReport {
+    pub job_id: usize,
+    pub title: String,
+    pub blueprint_hash: String,
+    pub started_at: i64,
+    pub shards: usize,
+    pub failure: Option<String>,
+    pub materials: Vec<MaterialUsage>,
+    pub commands: usize
+}
+
+impl SplitJob {
+    pub fn new(job_id : usize, title : String, blueprint_hash : String, plans : &[ShardPlan]) -> SharedSplit {
+        Arc::new( Mutex::new( SplitJob {
+            job_id: job_id,
+            title: title,
+            blueprint_hash: blueprint_hash,
+            started_at: now_ms(),
+            acked_layers: plans.iter().map(|plan| plan.layer_starts[0].1).collect(),
+            failure: None,
+            mat_usage: Vec::new(),
+            commands: 0
+        } ) )
+    }
+}
+
+//The part of a split job a printhead is working on
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+    pub layer_starts: Vec<(u64, usize)>,
+    pub split: SharedSplit
+}
+
+impl Shard {
+    fn layer_at(&self, offset : u64) -> usize {
+        self.layer_starts.iter().take_while(|&&(start, _)| start <= offset).last()
+            .map(|&(_, layer)| layer).unwrap_or(self.layer_starts[0].1)
+    }
+
+    //Whether the command at the given offset may be sent
+    pub fn may_print(&self, offset : u64) -> bool {
+        let layer = self.layer_at(offset);
+        let split = self.split.lock().unwrap();
+        split.acked_layers.iter().enumerate().all(|(index, &acked)| index == self.index || acked >= layer)
+    }
+
+    pub fn acked(&self, offset : u64) {
+        let layer = self.layer_at(offset);
+        self.split.lock().unwrap().acked_layers[self.index] = layer;
+    }
+
+    //Why another shard failed, the others have to be aborted then
+    pub fn failure(&self) -> Option<String> {
+        self.split.lock().unwrap().failure.clone()
+    }
+
+    //Returns the report of the split job once all shards have finished
+    pub fn finished(&self, failure : Option<&str>, usage : &[MaterialUsage], commands : usize) -> Option<SplitReport> {
+        let mut split = self.split.lock().unwrap();
+        split.acked_layers[self.index] = usize::MAX;
+        if split.failure.is_none() {
+            split.failure = failure.map(|reason| format!("shard {}/{}: {}", self.index + 1, self.count, reason));
+        }
+        for entry in usage {
+            add_usage(&mut split.mat_usage, *entry);
+        }
+        split.commands += commands;
+        if !split.acked_layers.iter().all(|&acked| acked == usize::MAX) {
+            return None;
+        }
+        Some( SplitReport {
+            job_id: split.job_id,
+            title: split.title.clone(),
+            blueprint_hash: split.blueprint_hash.clone(),
+            started_at: split.started_at,
+            shards: self.count,
+            failure: split.failure.clone(),
+            materials: split.mat_usage.clone(),
+            commands: split.commands
+        } )
+    }
+}
+
+//Loads one shard into every free printhead, the first commands have to be sent by the event loop.
+//Returns the split job's id and the printheads working on it, if any shard cannot be loaded none is started.
+pub fn start(clients : &Parts, bp : &[u8], title : &str, mode : SplitMode) -> Result<(usize, Vec<usize>), String> {
+    let cells = status::free_printheads(clients);
+    let plans = try!( plan(bp, cells.len(), mode) );
+    let job_id = get_new_job_id();
+    let split = SplitJob::new(job_id, title.to_string(), history::blueprint_hash(bp), &plans);
+    let count = plans.len();
+    let mut loaded_cells = Vec::new();
+    for (index, (shard_plan, cell)) in plans.into_iter().zip(cells.iter()).enumerate() {
+        let shard_id = get_new_job_id();
+        let loaded = journal::spool_blueprint(shard_id, &shard_plan.data).and_then(|spooled| {
+            let shard_title = format!("{} [{}/{}]", title, index + 1, count);
+            cell.borrow_mut().start_job(shard_id, shard_title, Box::new(spooled), shard_plan.data.len() as u64)
+        });
+        if let Err(e) = loaded {
+            let _ = fs::remove_file(journal::spool_path(shard_id));
+            //Nothing has been sent yet, the loaded shards are dropped without a trace
+            for loaded_cell in loaded_cells {
+                let mut printhead = loaded_cell.borrow_mut();
+                let loaded_id = printhead.job_id.take();
+                printhead.shard = None;
+                printhead.abort_job(&format!("shard {}/{} cannot be loaded", index + 1, count));
+                printhead.job_failure = None;
+                if let Some(loaded_id) = loaded_id {
+                    let _ = fs::remove_file(journal::spool_path(loaded_id));
+                }
+            }
+            return Err(format!("shard {}/{}: {}", index + 1, count, e));
+        }
+        cell.borrow_mut().shard = Some( Shard { index: index, count: count, layer_starts: shard_plan.layer_starts, split: split.clone() } );
+        loaded_cells.push(cell.clone());
+    }
+    let printheads : Vec<usize> = loaded_cells.iter().map(|cell| cell.borrow().id).collect();
+    let mut first = loaded_cells[0].borrow_mut();
+    let event = Event::job_started(first.id, job_id, title);
+    first.events.push(event);
+    println!("Split job #{} '{}' across printhead[s] {:?}", job_id, title, printheads);
+    Ok((job_id, printheads))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use internals::blueprint;
+    use internals::blueprint::Command;
+
+    fn level(z : i32, matid : u8) -> Vec<u8> {
+        vec![1, z as u8, (z >> 8) as u8, (z >> 16) as u8, (z >> 24) as u8, matid]
+    }
+
+    fn dot(x : i32, y : i32) -> Vec<u8> {
+        let mut raw = vec![2];
+        raw.extend_from_slice(&line_command(x, y, 0, 0)[1 .. 9]);
+        raw
+    }
+
+    fn temperature(target : i32, zone : u8) -> Vec<u8> {
+        vec![5, target as u8, (target >> 8) as u8, (target >> 16) as u8, (target >> 24) as u8, zone]
+    }
+
+    fn bp(commands : &[Vec<u8>]) -> Vec<u8> {
+        let mut data = blueprint::MAGIC.to_vec();
+        for command in commands {
+            data.extend_from_slice(command);
+        }
+        data
+    }
+
+    fn commands(plan : &ShardPlan) -> Vec<Command> {
+        blueprint::parse(&plan.data).unwrap().into_iter().map(|(_, command)| command).collect()
+    }
+
+    fn shards(plans : &[ShardPlan], split : &SharedSplit) -> Vec<Shard> {
+        plans.iter().enumerate().map(|(index, plan)| Shard {
+            index: index,
+            count: plans.len(),
+            layer_starts: plan.layer_starts.clone(),
+            split: split.clone()
+        }).collect()
+    }
+
+    //Two layers with a dot on each side
+    fn two_sided() -> Vec<u8> {
+        bp(&[level(0, 0), dot(0, 0), dot(99, 0), level(1, 0), dot(0, 1), dot(99, 1)])
+    }
+
+    #[test]
+    fn region_assigns_by_band() {
+        let plans = plan(&two_sided(), 2, SplitMode::Region).unwrap();
+        assert_eq!(plans.len(), 2);
+        assert_eq!(commands(&plans[0]), vec![Command::Level { z: 0, matid: 0 }, Command::Dot { x: 0, y: 0 },
+                                             Command::Level { z: 1, matid: 0 }, Command::Dot { x: 0, y: 1 }]);
+        assert_eq!(commands(&plans[1]), vec![Command::Level { z: 0, matid: 0 }, Command::Dot { x: 99, y: 0 },
+                                             Command::Level { z: 1, matid: 0 }, Command::Dot { x: 99, y: 1 }]);
+    }
+
+    #[test]
+    fn region_clips_lines_at_band_edges() {
+        let plans = plan(&bp(&[level(0, 0), line_command(0, 0, 99, 99)]), 2, SplitMode::Region).unwrap();
+        assert_eq!(commands(&plans[0])[1], Command::Line { x1: 0, y1: 0, x2: 49, y2: 49 });
+        assert_eq!(commands(&plans[1])[1], Command::Line { x1: 50, y1: 50, x2: 99, y2: 99 });
+    }
+
+    #[test]
+    fn region_broadcasts_temperature_and_levels() {
+        let data = bp(&[temperature(600, 1), level(0, 0), dot(0, 0), level(1, 0), dot(99, 1)]);
+        let plans = plan(&data, 2, SplitMode::Region).unwrap();
+        for plan in &plans {
+            let commands = commands(plan);
+            assert_eq!(commands[0], Command::Temperature { target: 600, zone: 1 });
+            assert_eq!(commands.iter().filter(|command| match **command { Command::Level { .. } => true, _ => false }).count(), 2);
+        }
+    }
+
+    #[test]
+    fn layers_assigns_contiguous_ranges() {
+        let plans = plan(&two_sided(), 2, SplitMode::Layers).unwrap();
+        assert_eq!(plans[0].layer_starts.iter().map(|&(_, layer)| layer).collect::<Vec<usize>>(), vec![0]);
+        assert_eq!(plans[1].layer_starts.iter().map(|&(_, layer)| layer).collect::<Vec<usize>>(), vec![1]);
+    }
+
+    #[test]
+    fn layer_waits_for_other_shards() {
+        let plans = plan(&two_sided(), 2, SplitMode::Region).unwrap();
+        let split = SplitJob::new(1, "test".to_string(), String::new(), &plans);
+        let shards = shards(&plans, &split);
+        let second_layer = plans[0].layer_starts[1].0;
+        assert!(shards[0].may_print(plans[0].layer_starts[0].0));
+        assert!(!shards[0].may_print(second_layer));
+        shards[1].acked(plans[1].layer_starts[1].0); //First layer of the other shard done
+        assert!(shards[0].may_print(second_layer));
+    }
+
+    #[test]
+    fn disconnect_mid_layer_fails_split() {
+        let plans = plan(&two_sided(), 2, SplitMode::Region).unwrap();
+        let split = SplitJob::new(1, "test".to_string(), String::new(), &plans);
+        let shards = shards(&plans, &split);
+        shards[1].acked(plans[1].layer_starts[0].0 + 6); //Level of the first layer acknowledged
+        assert!(shards[1].finished(Some("printhead disconnected"), &[], 1).is_none());
+        //The remaining shard is neither blocked nor left running
+        assert!(shards[0].may_print(plans[0].layer_starts[1].0));
+        assert_eq!(shards[0].failure(), Some("shard 2/2: printhead disconnected".to_string()));
+        let report = shards[0].finished(Some("other shard failed"), &[], 0).unwrap();
+        assert_eq!(report.job_id, 1);
+        assert_eq!(report.failure, Some("shard 2/2: printhead disconnected".to_string()));
+    }
+
+    #[test]
+    fn finished_reports_once_all_shards_are_done() {
+        let plans = plan(&two_sided(), 2, SplitMode::Region).unwrap();
+        let split = SplitJob::new(1, "test".to_string(), String::new(), &plans);
+        let shards = shards(&plans, &split);
+        assert!(shards[0].finished(None, &[], 4).is_none());
+        let report = shards[1].finished(None, &[], 4).unwrap();
+        assert_eq!(report.title, "test");
+        assert_eq!(report.failure, None);
+        assert_eq!(report.shards, 2);
+        assert_eq!(report.commands, 8);
+    }
+}
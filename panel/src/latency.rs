@@ -0,0 +1,99 @@
+use std::cmp;
+use std::collections::HashMap;
+use internals::blueprint;
+
+//Upper bounds of the histogram buckets in microseconds, slower answers go into a last overflow bucket
+pub const BUCKETS_US : [u64; 16] = [50, 100, 200, 500, 1000, 2000, 5000, 10000, 20000, 50000,
+    100000, 200000, 500000, 1000000, 2000000, 5000000];
+
+//Round trip times from writing a command to the printhead until its answer is read
+#[derive(Debug, Clone, Copy)]
+pub struct Histogram {
+    pub counts: [u64; 17],
+    pub count: u64,
+    pub sum_us: u64,
+    pub max_us: u64
+}
+
+#[derive(RustcEncodable, Debug, Clone)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64
+}
+
+//Per command type, keyed by the blueprint command id
+pub type CommandLatencies = HashMap<u8, Histogram>;
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram { counts: [0; 17], count: 0, sum_us: 0, max_us: 0 }
+    }
+
+    pub fn record(&mut self, us : u64) {
+        let bucket = BUCKETS_US.iter().position(|&bound| us <= bound).unwrap_or(BUCKETS_US.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum_us += us;
+        self.max_us = cmp::max(self.max_us, us);
+    }
+
+    pub fn merge(&mut self, other : &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other;
+        }
+        self.count += other.count;
+        self.sum_us += other.sum_us;
+        self.max_us = cmp::max(self.max_us, other.max_us);
+    }
+
+    //Upper bound of the bucket the given share (0..1) of answers falls into
+    pub fn percentile(&self, share : f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = cmp::max( ((self.count as f64) * share).ceil() as u64, 1 );
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank {
+                return match BUCKETS_US.get(bucket) {
+                    Some(bound) => cmp::min(*bound, self.max_us),
+                    None => self.max_us //Overflow bucket
+                };
+            }
+        }
+        self.max_us
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            mean_us: if self.count > 0 { self.sum_us / self.count } else { 0 },
+            p50_us: self.percentile(0.50),
+            p95_us: self.percentile(0.95),
+            p99_us: self.percentile(0.99),
+            max_us: self.max_us
+        }
+    }
+}
+
+pub fn record(latencies : &mut CommandLatencies, commandid : u8, us : u64) {
+    latencies.entry(commandid).or_insert(Histogram::new()).record(us);
+}
+
+pub fn merge(into : &mut CommandLatencies, from : &CommandLatencies) {
+    for (commandid, histogram) in from {
+        into.entry(*commandid).or_insert(Histogram::new()).merge(histogram);
+    }
+}
+
+//Summaries by command name (level, dot, line)
+pub fn summarize(latencies : &CommandLatencies) -> HashMap<String, LatencySummary> {
+    latencies.iter()
+        .map(|(commandid, histogram)| (blueprint::command_name(*commandid).to_string(), histogram.summary()))
+        .collect()
+}
@@ -3,22 +3,39 @@ extern crate time;
 extern crate hyper;
 extern crate rustc_serialize;
 extern crate mqtt;
+extern crate crypto;
+extern crate openssl;
+extern crate rand;
 
 mod internals;
 mod rest;
+mod config;
+mod vbed;
+mod events;
+mod status;
+mod remote;
+mod latency;
+mod benchmark;
+mod metrics;
+mod sim;
+mod telemetry;
+mod maintenance;
+mod estop;
+mod messages;
 
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use mio::{EventLoop, Token, EventSet, PollOpt};
 use std::net::SocketAddr;
 use mio::tcp::TcpListener;
 use std::collections::HashMap;
 use std::thread;
-use mqtt::async::{PersistenceType, Qos, AsyncClient, AsyncConnectOptions};
+use std::time::Duration;
 
 const SERVER_TOKEN: Token = Token(0);
 const CLI_TOKEN: Token = Token(1);
 const PRINT_TIMEOUT_MS : u64 = 10000;
 const CONTINUE_DELAY_MS : u64 = 1000;
+const HEARTBEAT_TIMEOUT : usize = std::usize::MAX; //Timeout id of the heartbeat, part ids are used for command timeouts
 
 fn main() {
     let broker_addr = "127.0.0.1";
@@ -26,29 +43,47 @@ fn main() {
     println!("VS-Fab 3D Printer Panel - Ramiz Bahrami(736861), Adrian Müller(734922)");
     println!("Welcome! Your options are:");
     println!(" p - Print blueprint once");
-    println!(" b - Run throughput benchmark");
+    println!(" s - Split blueprint across all free printheads (s [region|layers])");
+    println!(" b - Run throughput benchmark (b [count] [level=1,dot=5,line=2])");
+    println!(" t - Confirm tool change after loading the material (t [printhead])");
+    println!(" e - Emergency stop of all printheads (e reset to accept jobs again)");
+    println!(" r - Resume interrupted jobs");
+    println!(" d - Discard interrupted jobs");
     println!(" q - Quit");
 
+    let config = Arc::new( config::load() );
+
     let mut eventloop = EventLoop::new().unwrap();
 
-    let internal_parts = Arc::new(RwLock::new(HashMap::new()));
+    let job_history = internals::history::load(config.history_limit);
+
+    let event_streams = rest::EventStreams::new();
 
-    let rparts = internal_parts.clone();
+    let rconfig = config.clone();
+    let rstreams = event_streams.clone();
     let eventloop_channel = eventloop.channel();
-    let _restthread = thread::spawn( move || rest::serve( rparts, eventloop_channel ) );
+    let _restthread = thread::spawn( move || rest::serve( eventloop_channel, rconfig, rstreams ) );
+
+    let remote_config = config.clone();
+    let remote_channel = eventloop.channel();
+    let _remotethread = thread::spawn( move || remote::listen( broker_addr, remote_config, remote_channel ) );
+
+    let events = events::Events::connect(broker_addr, &config, event_streams);
 
-    let connection_options = AsyncConnectOptions::new();
-    let mut msgclient = AsyncClient::new(broker_addr, "printer", PersistenceType::Nothing, None)
-        .expect("Cannot create MQTT client!");
-    msgclient.connect(&connection_options).expect("Cannot connect to MQTT broker!");
-    
     let address = "0.0.0.0:18000".parse::<SocketAddr>().unwrap();
     let mut server = internals::Server {
             socket: TcpListener::bind(&address).unwrap(),
             tokencounter : 2,
-            clients: internal_parts.clone(),
+            clients: HashMap::new(),
+            handshakes: HashMap::new(),
             continuedelay: None,
-            msgclient: msgclient
+            events: events,
+            jobs: internals::Jobs::new( internals::Journal::load(), job_history, config.clone() ),
+            benchmarks: HashMap::new(),
+            maintenance: maintenance::Maintenance::load(),
+            estop: estop::load(),
+            lost_alarms: Vec::new(),
+            config: config.clone()
     };
 
     eventloop.register(&server.socket,
@@ -62,6 +97,12 @@ fn main() {
                         EventSet::readable(),
                         PollOpt::level()).unwrap();
 
+    if config.heartbeat_interval_ms > 0 {
+        eventloop.timeout(HEARTBEAT_TIMEOUT, Duration::from_millis(config.heartbeat_interval_ms)).unwrap();
+    }
+
+    sim::start(&config);
+
     eventloop.run(&mut server).unwrap();
     println!("Job's done!");
 }
@@ -0,0 +1,249 @@
+use std::io::{Read, Write};
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::collections::BTreeMap;
+use rustc_serialize::json;
+use events::{Event, now_ms};
+use internals::Printerpart;
+use internals::journal::SPOOL_DIR;
+
+//Lifetime counters of every part that was ever connected, by part type and serial
+const MAINTENANCE_FILE : &'static str = "spool/maintenance.json";
+const SAVE_INTERVAL_MS : i64 = 10000; //Counters lost on a crash are at most this old, or one heartbeat interval once parts are idle
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Counter {
+    Commands,
+    LineLength,
+    Material,
+    Jobs,
+    Failures,
+    Hours
+}
+
+impl Counter {
+    pub fn parse(name : &str) -> Result<Counter, String> {
+        match name {
+            "commands" => Ok(Counter::Commands),
+            "line_length" => Ok(Counter::LineLength),
+            "material" => Ok(Counter::Material),
+            "jobs" => Ok(Counter::Jobs),
+            "failures" => Ok(Counter::Failures),
+            "hours" => Ok(Counter::Hours),
+            other => Err(format!("unknown counter '{}'", other))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Counter::Commands => "commands",
+            Counter::LineLength => "line_length",
+            Counter::Material => "material",
+            Counter::Jobs => "jobs",
+            Counter::Failures => "failures",
+            Counter::Hours => "hours"
+        }
+    }
+}
+
+//A service alert is raised once a counter reaches the limit since the last service
+#[derive(Debug, Clone, Copy)]
+pub struct ServiceInterval {
+    pub counter: Counter,
+    pub limit: f64
+}
+
+//Work a part has done since its counters were last collected
+#[derive(Debug, Clone, Copy)]
+pub struct Wear {
+    pub commands: u64,
+    pub line_length: f64, //Blueprint units
+    pub material: u64,    //Units drawn by a printhead or dispensed by a container
+    pub jobs: u64,
+    pub failures: u64,    //Jobs aborted or failed
+    pub since: i64        //Milliseconds since the epoch, for the time online
+}
+
+impl Wear {
+    pub fn new() -> Wear {
+        Wear { commands: 0, line_length: 0.0, material: 0, jobs: 0, failures: 0, since: now_ms() }
+    }
+
+    //Nothing but time online
+    fn idle(&self) -> bool {
+        self.commands == 0 && self.line_length == 0.0 && self.material == 0 && self.jobs == 0 && self.failures == 0
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, Copy)]
+pub struct Counts {
+    pub commands: u64,
+    pub line_length: f64,
+    pub material: u64,
+    pub jobs: u64,
+    pub failures: u64,
+    pub hours: f64 //Online
+}
+
+impl Counts {
+    fn new() -> Counts {
+        Counts { commands: 0, line_length: 0.0, material: 0, jobs: 0, failures: 0, hours: 0.0 }
+    }
+
+    fn add(&mut self, wear : &Wear, hours : f64) {
+        self.commands += wear.commands;
+        self.line_length += wear.line_length;
+        self.material += wear.material;
+        self.jobs += wear.jobs;
+        self.failures += wear.failures;
+        self.hours += hours;
+    }
+
+    fn get(&self, counter : Counter) -> f64 {
+        match counter {
+            Counter::Commands => self.commands as f64,
+            Counter::LineLength => self.line_length,
+            Counter::Material => self.material as f64,
+            Counter::Jobs => self.jobs as f64,
+            Counter::Failures => self.failures as f64,
+            Counter::Hours => self.hours
+        }
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
+pub struct ServiceRecord {
+    pub serial: u32,
+    pub part_type: String,
+    pub lifetime: Counts,
+    pub since_service: Counts,
+    pub last_service: Option<i64>, //Milliseconds since the epoch
+    pub due: Vec<String> //Counters that have reached their service interval
+}
+
+//Printheads and containers may share serials, records are kept apart by type
+pub type PartKey = (String, u32);
+
+pub fn part_key(part : &Printerpart) -> PartKey {
+    (format!("{:?}", part.parttype).to_lowercase(), part.serial)
+}
+
+pub struct Maintenance {
+    pub records: BTreeMap<PartKey, ServiceRecord>,
+    dirty: bool,
+    saved_at: i64
+}
+
+impl Maintenance {
+    pub fn load() -> Maintenance {
+        let mut maintenance = Maintenance { records: BTreeMap::new(), dirty: false, saved_at: now_ms() };
+        if ! Path::new(MAINTENANCE_FILE).exists() {
+            return maintenance;
+        }
+        let mut text = String::new();
+        let loaded = File::open(MAINTENANCE_FILE).and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| e.to_string())
+            .and_then(|_| json::decode::<Vec<ServiceRecord>>(&text).map_err(|e| e.to_string()));
+        let records = match loaded {
+            Ok(records) => records,
+            Err(e) => {
+                println!("Cannot load maintenance counters, starting without them: {}", e);
+                return maintenance;
+            }
+        };
+        for record in records {
+            if !record.due.is_empty() {
+                println!("{} serial {} is due for service: {}", record.part_type, record.serial, record.due.join(", "));
+            }
+            maintenance.records.insert((record.part_type.clone(), record.serial), record);
+        }
+        maintenance
+    }
+
+    fn save(&mut self) {
+        self.saved_at = now_ms();
+        self.dirty = false;
+        if let Err(e) = fs::create_dir_all(SPOOL_DIR) {
+            println!("Cannot create spool dir: {}", e);
+            return;
+        }
+        //Same as the job journal, a crash never leaves a half written file
+        let records : Vec<&ServiceRecord> = self.records.values().collect();
+        let tmp_path = format!("{}.tmp", MAINTENANCE_FILE);
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(json::encode(&records).unwrap().as_bytes()))
+            .and_then(|_| fs::rename(&tmp_path, MAINTENANCE_FILE));
+        if let Err(e) = result {
+            println!("Cannot write maintenance counters: {}", e);
+        }
+    }
+
+    //Writes the counters if anything has changed
+    pub fn flush(&mut self) {
+        if self.dirty {
+            self.save();
+        }
+    }
+
+    //Writes the counters including the time online, which alone does not mark them changed
+    pub fn checkpoint(&mut self) {
+        self.save();
+    }
+
+    //Adds the parts work to its counters, service alerts are queued as events of the part
+    pub fn collect(&mut self, part : &mut Printerpart, intervals : &[ServiceInterval]) {
+        let now = now_ms();
+        let wear = part.wear;
+        part.wear = Wear::new();
+        let hours = (now - wear.since) as f64 / 3600000.0;
+        let key = part_key(part);
+        let mut changed = !wear.idle() || !self.records.contains_key(&key);
+        {
+            let record = self.records.entry(key.clone()).or_insert(ServiceRecord {
+                serial: key.1,
+                part_type: key.0,
+                lifetime: Counts::new(),
+                since_service: Counts::new(),
+                last_service: None,
+                due: Vec::new()
+            });
+            record.lifetime.add(&wear, hours);
+            record.since_service.add(&wear, hours);
+
+            for interval in intervals {
+                let value = record.since_service.get(interval.counter);
+                let name = interval.counter.name();
+                if value < interval.limit || record.due.iter().any(|due| due == name) {
+                    continue;
+                }
+                println!("{:?}({}) serial {} is due for service: {} {} (interval {})",
+                    part.parttype, part.id, part.serial, name, value, interval.limit);
+                record.due.push(name.to_string());
+                part.events.push( Event::service_due(part.id, part.serial, name, value) );
+                changed = true;
+            }
+            part.service_due = record.due.clone();
+        }
+        if !changed {
+            return;
+        }
+        self.dirty = true;
+        if now - self.saved_at >= SAVE_INTERVAL_MS {
+            self.save();
+        }
+    }
+
+    //After maintenance, the lifetime counters are kept. The connected part with the serial has to be cleared by the caller.
+    pub fn reset(&mut self, key : &PartKey) -> Result<(), String> {
+        {
+            let record = try!( self.records.get_mut(key).ok_or(format!("Unknown {} serial {}", key.0, key.1)) );
+            println!("{} serial {} serviced, resetting its service counters", record.part_type, record.serial);
+            record.since_service = Counts::new();
+            record.last_service = Some(now_ms());
+            record.due.clear();
+        }
+        self.save();
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::mpsc;
+use hyper::{Control, Next};
+use hyper::mime::Mime;
+use remote::RemoteRequest;
+use benchmark::BenchmarkSpec;
+use telemetry::Quantity;
+use maintenance::PartKey;
+use internals::history::HistoryQuery;
+use rest::bed_export::BedExport;
+
+//Sent to the event loop, which alone owns the parts
+pub enum Message {
+    Remote(RemoteRequest), //Answered on the MQTT reply topic
+    Rest(Request, ReplySender)
+}
+
+//What the REST thread needs from the parts, answered in the order it arrives
+pub enum Request {
+    Status,
+    Parts(Option<usize>),
+    Latency,
+    Metrics,
+    Benchmarks(Option<usize>),
+    Telemetry(Option<Quantity>),
+    Maintenance(Option<PartKey>),
+    Jobs(HistoryQuery),
+    Job(usize),
+    Bed(usize, BedExport),
+    Print { blueprint: Vec<u8>, title: String, split: Option<String> },
+    Cancel(Option<usize>), //All jobs if no printhead is given
+    Benchmark { spec: BenchmarkSpec, printhead: Option<usize> },
+    ResetMaintenance(PartKey),
+    EmergencyStop { reset: bool, by: String }
+}
+
+pub enum Reply {
+    Body(String), //JSON, the metrics are plain text
+    Export(Vec<u8>, Mime), //Bed download in its own format
+    NotFound,
+    Unprocessable(String) //JSON error
+}
+
+impl Reply {
+    //None if the requested item does not exist
+    pub fn found(body : Option<String>) -> Reply {
+        match body {
+            Some(body) => Reply::Body(body),
+            None => Reply::NotFound
+        }
+    }
+}
+
+//The REST request waits without blocking its thread, sending the reply wakes it up
+pub struct ReplySender {
+    reply: mpsc::Sender<Reply>,
+    control: Control
+}
+
+impl ReplySender {
+    pub fn new(reply : mpsc::Sender<Reply>, control : Control) -> ReplySender {
+        ReplySender { reply: reply, control: control }
+    }
+
+    pub fn send(self, reply : Reply) {
+        //The REST request may have timed out and closed the channel
+        if self.reply.send(reply).is_ok() {
+            let _ = self.control.ready(Next::write());
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use internals::{PrinterPartType, PartCell, Parts};
+use internals::blueprint;
+use latency::BUCKETS_US;
+
+//Prometheus text format, served on GET /metrics
+static JOBS_ACCEPTED : AtomicUsize = ATOMIC_USIZE_INIT;
+static JOBS_COMPLETED : AtomicUsize = ATOMIC_USIZE_INIT;
+static JOBS_FAILED : AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub fn job_accepted() {
+    JOBS_ACCEPTED.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn job_finished(failed : bool) {
+    if failed {
+        JOBS_FAILED.fetch_add(1, Ordering::SeqCst);
+    }
+    else {
+        JOBS_COMPLETED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn header(out : &mut String, name : &str, kind : &str, help : &str) {
+    let _ = write!(out, "# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+}
+
+fn seconds(us : u64) -> f64 {
+    (us as f64) / 1_000_000.0
+}
+
+pub fn render(clients : &Parts) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "panel_jobs_accepted_total", "counter", "Jobs loaded into a printhead");
+    let _ = write!(out, "panel_jobs_accepted_total {}\n", JOBS_ACCEPTED.load(Ordering::SeqCst));
+    header(&mut out, "panel_jobs_completed_total", "counter", "Jobs printed completely");
+    let _ = write!(out, "panel_jobs_completed_total {}\n", JOBS_COMPLETED.load(Ordering::SeqCst));
+    header(&mut out, "panel_jobs_failed_total", "counter", "Jobs aborted or cancelled");
+    let _ = write!(out, "panel_jobs_failed_total {}\n", JOBS_FAILED.load(Ordering::SeqCst));
+
+    let mut parts : Vec<PartCell> = clients.values().cloned().collect();
+    parts.sort_by_key(|cell| cell.borrow().id);
+    let printheads : Vec<&PartCell> = parts.iter()
+        .filter(|cell| cell.borrow().parttype == PrinterPartType::Printhead).collect();
+    let containers : Vec<&PartCell> = parts.iter()
+        .filter(|cell| cell.borrow().parttype == PrinterPartType::Material).collect();
+    let sensors : Vec<&PartCell> = parts.iter()
+        .filter(|cell| cell.borrow().parttype == PrinterPartType::Sensor).collect();
+    let heaters = parts.iter().filter(|cell| cell.borrow().parttype == PrinterPartType::Heater).count();
+
+    header(&mut out, "panel_connected_parts", "gauge", "Parts connected to the panel");
+    let _ = write!(out, "panel_connected_parts{{type=\"printhead\"}} {}\n", printheads.len());
+    let _ = write!(out, "panel_connected_parts{{type=\"material\"}} {}\n", containers.len());
+    let _ = write!(out, "panel_connected_parts{{type=\"sensor\"}} {}\n", sensors.len());
+    let _ = write!(out, "panel_connected_parts{{type=\"heater\"}} {}\n", heaters);
+
+    header(&mut out, "panel_part_healthy", "gauge", "0 if a part has missed too many heartbeats");
+    for cell in &parts {
+        let part = cell.borrow();
+        let _ = write!(out, "panel_part_healthy{{part=\"{}\",serial=\"{}\"}} {}\n", part.id, part.serial, part.healthy as u8);
+    }
+
+    header(&mut out, "panel_part_commands_total", "counter", "Commands sent to a part and their outcome since it connected");
+    for cell in &parts {
+        let part = cell.borrow();
+        for &(result, count) in &[("sent", part.counters.sent), ("acked", part.counters.acked),
+                                  ("failed", part.counters.failed), ("timeout", part.counters.timeouts)] {
+            let _ = write!(out, "panel_part_commands_total{{part=\"{}\",serial=\"{}\",result=\"{}\"}} {}\n",
+                part.id, part.serial, result, count);
+        }
+    }
+
+    header(&mut out, "panel_commands_executed_total", "counter", "Commands answered by a printhead, by command type");
+    for cell in &printheads {
+        let part = cell.borrow();
+        for (commandid, histogram) in &part.latency {
+            let _ = write!(out, "panel_commands_executed_total{{printhead=\"{}\",command=\"{}\"}} {}\n",
+                part.id, blueprint::command_name(*commandid), histogram.count);
+        }
+    }
+
+    header(&mut out, "panel_command_latency_seconds", "histogram", "Round trip time from sending a command to the printhead's answer");
+    for cell in &printheads {
+        let part = cell.borrow();
+        for (commandid, histogram) in &part.latency {
+            let labels = format!("printhead=\"{}\",command=\"{}\"", part.id, blueprint::command_name(*commandid));
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS_US.iter().zip(histogram.counts.iter()) {
+                cumulative += *count;
+                let _ = write!(out, "panel_command_latency_seconds_bucket{{{},le=\"{}\"}} {}\n", labels, seconds(*bound), cumulative);
+            }
+            let _ = write!(out, "panel_command_latency_seconds_bucket{{{},le=\"+Inf\"}} {}\n", labels, histogram.count);
+            let _ = write!(out, "panel_command_latency_seconds_sum{{{}}} {}\n", labels, seconds(histogram.sum_us));
+            let _ = write!(out, "panel_command_latency_seconds_count{{{}}} {}\n", labels, histogram.count);
+        }
+    }
+
+    header(&mut out, "panel_material_used_units", "gauge", "Material drawn from a container since it was last refilled");
+    for cell in &containers {
+        let part = cell.borrow();
+        let _ = write!(out, "panel_material_used_units{{part=\"{}\",material=\"{}\"}} {}\n", part.id, part.matid, part.mat_used);
+    }
+    header(&mut out, "panel_material_empty", "gauge", "1 if a container reported that it is nearly empty");
+    for cell in &containers {
+        let part = cell.borrow();
+        let _ = write!(out, "panel_material_empty{{part=\"{}\",material=\"{}\"}} {}\n", part.id, part.matid, part.matempty as u8);
+    }
+
+    header(&mut out, "panel_sensor_reading", "gauge", "Latest reading of a sensor, temperatures in celsius, humidity in percent");
+    for cell in &sensors {
+        let part = cell.borrow();
+        for (quantity, reading) in part.telemetry.latest() {
+            let _ = write!(out, "panel_sensor_reading{{sensor=\"{}\",quantity=\"{}\"}} {}\n", part.id, quantity, reading.value);
+        }
+    }
+    header(&mut out, "panel_sensor_alarm", "gauge", "1 if a sensor reads outside its limits");
+    for cell in &sensors {
+        let part = cell.borrow();
+        let _ = write!(out, "panel_sensor_alarm{{sensor=\"{}\"}} {}\n", part.id, !part.telemetry.alarms.is_empty() as u8);
+    }
+
+    header(&mut out, "panel_printhead_busy", "gauge", "1 if a printhead is printing a job or running a benchmark");
+    for cell in &printheads {
+        let part = cell.borrow();
+        let busy = part.blueprint.is_some() || part.benchmark.is_some();
+        let _ = write!(out, "panel_printhead_busy{{printhead=\"{}\"}} {}\n", part.id, busy as u8);
+    }
+    out
+}
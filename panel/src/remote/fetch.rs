@@ -0,0 +1,84 @@
+use hyper;
+use hyper::{Decoder, Encoder, Next, Url};
+use hyper::client::{Client, Request, Response, DefaultTransport as HttpStream};
+use hyper::header::Connection;
+use std::io;
+use std::io::Read;
+use std::sync::mpsc;
+use std::time::Duration;
+
+//Downloads a blueprint for remote start commands
+struct BlueprintReq {
+    result_pipe: mpsc::Sender<Result<Vec<u8>, String>>,
+    buf : Vec<u8>,
+    read_pos : usize
+}
+
+fn read() -> Next {//Helper to generate a read-request with timeout
+    Next::read().timeout(Duration::from_millis(5000))
+}
+
+impl hyper::client::Handler<HttpStream> for BlueprintReq {
+    fn on_request(&mut self, req: &mut Request) -> Next {
+        req.headers_mut().set(Connection::close());
+        read()
+    }
+
+    fn on_request_writable(&mut self, _encoder: &mut Encoder<HttpStream>) -> Next {
+        //GET has no body, on_request already asked to read
+        let _ = self.result_pipe.send(Err("Blueprint download failed: unexpected request body".to_string()));
+        Next::end()
+    }
+
+    fn on_response(&mut self, res: Response) -> Next {
+        if !res.status().is_success() {
+            let _ = self.result_pipe.send(Err(format!("Blueprint download failed: {}", res.status())));
+            return Next::end();
+        }
+        read()
+    }
+
+    fn on_response_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+        if self.read_pos >= self.buf.len() {
+            let newsize = self.buf.len() + 2048;
+            self.buf.resize(newsize, 0); //If buffer is full, resize by 2KB
+        }
+        match transport.read(&mut self.buf[self.read_pos .. ]) {
+            Ok(0) => {
+                self.buf.truncate(self.read_pos);
+                let _ = self.result_pipe.send(Ok(self.buf.clone()));
+                Next::end()
+            }
+            Ok(n) => {
+                self.read_pos += n;
+                read()
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock => read(),
+                _ => {
+                    let _ = self.result_pipe.send(Err(format!("Blueprint download failed: {}", e)));
+                    Next::end()
+                }
+            }
+        }
+    }
+
+    fn on_error(&mut self, err: hyper::Error) -> Next {
+        let _ = self.result_pipe.send(Err(format!("Blueprint download failed: {}", err)));
+        Next::remove()
+    }
+}
+
+pub fn fetch(url : &str) -> Result<Vec<u8>, String> {
+    let url = try!( Url::parse(url).map_err(|e| format!("Invalid blueprint url: {}", e)) );
+    let client = try!( Client::new().map_err(|e| format!("Cannot instantiate new Client: {}", e)) );
+    let (tx, rx) = mpsc::channel();
+
+    let req = BlueprintReq { result_pipe: tx, buf: vec![0;0], read_pos: 0 };
+    let result = match client.request(url, req) {
+        Ok(_) => rx.recv().unwrap_or(Err("Blueprint download aborted".to_string())),
+        Err(_) => Err("Sending blueprint request failed".to_string())
+    };
+    client.close();
+    result
+}
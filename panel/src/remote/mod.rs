@@ -0,0 +1,102 @@
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::str::from_utf8;
+use mio;
+use mqtt::async::{PersistenceType, Qos, AsyncClient, AsyncConnectOptions};
+use rustc_serialize::json;
+use rustc_serialize::base64::FromBase64;
+use config::Config;
+use events::printer_topic;
+use status::Status;
+use messages::Message;
+
+mod fetch;
+
+//Received as JSON on fab/<fab>/printer/<id>/commands
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct RemoteCommand {
+    pub request_id: String,
+    pub command: String, //start, pause, resume, cancel, confirm (tool change), benchmark, status, estop or estop_reset
+    pub title: Option<String>,
+    pub blueprint: Option<String>, //Base64 encoded blueprint to start...
+    pub url: Option<String>,       //...or where to download it from
+    pub printhead: Option<usize>,  //Without it pause, resume and cancel affect all printheads
+    pub split: Option<String>,     //"region" or "layers" starts the blueprint on all free printheads
+    pub count: Option<u32>,        //Benchmark commands to send...
+    pub mix: Option<HashMap<String, u32>>, //...and their ratio, e.g. {"level": 1, "dot": 5}
+    pub operator: Option<String>   //Logged with emergency stops
+}
+
+//Published on fab/<fab>/printer/<id>/responses
+#[derive(RustcEncodable)]
+pub struct RemoteReply {
+    pub request_id: String,
+    pub success: bool,
+    pub reason: String,
+    pub job_id: Option<usize>,
+    pub benchmark_id: Option<usize>, //Report on GET /benchmarks/<id> when finished
+    pub printheads: Vec<usize>, //Printheads affected by the command
+    pub status: Option<Status>
+}
+
+impl RemoteReply {
+    pub fn ok(request_id : &str) -> RemoteReply {
+        RemoteReply {
+            request_id: request_id.to_string(),
+            success: true,
+            reason: String::new(),
+            job_id: None,
+            benchmark_id: None,
+            printheads: Vec::new(),
+            status: None
+        }
+    }
+
+    pub fn failed(request_id : &str, reason : &str) -> RemoteReply {
+        RemoteReply { success: false, reason: reason.to_string(), ..RemoteReply::ok(request_id) }
+    }
+}
+
+//A command whose blueprint (for start) has already been decoded or downloaded
+pub struct RemoteRequest {
+    pub cmd: RemoteCommand,
+    pub blueprint: Result<Vec<u8>, String>
+}
+
+fn load_blueprint(cmd : &RemoteCommand) -> Result<Vec<u8>, String> {
+    match (&cmd.blueprint, &cmd.url) {
+        (&Some(ref data), _) => data.from_base64().map_err(|e| format!("Invalid blueprint data: {}", e)),
+        (&None, &Some(ref url)) => fetch::fetch(url),
+        (&None, &None) => Err("No blueprint or url given".to_string())
+    }
+}
+
+//Receives commands on a separate MQTT connection and hands them to the eventloop
+pub fn listen(broker_addr : &str, config : Arc<Config>, evloop_send : mio::Sender<Message>) {
+    let topic = printer_topic(&config, "commands");
+    let connection_options = AsyncConnectOptions::new();
+    let mut client = AsyncClient::new(broker_addr, &format!("printer_{}_remote", config.printer_id), PersistenceType::Nothing, None)
+        .expect("Cannot create MQTT client!");
+    client.connect(&connection_options).expect("Cannot connect to MQTT broker!");
+    client.subscribe(&topic, Qos::OnceAndOneOnly).expect("Cannot subscribe to command topic!");
+
+    loop {
+        for message in client.messages(None) {
+            let cmd : Option<RemoteCommand> = message.payload.as_ref()
+                .and_then(|payload| from_utf8(&payload[..]).ok())
+                .and_then(|text| json::decode(text).ok());
+            let cmd = match cmd {
+                Some(cmd) => cmd,
+                None => {
+                    println!("Ignoring invalid remote command on {}", message.topic);
+                    continue;
+                }
+            };
+
+            let blueprint = if cmd.command == "start" { load_blueprint(&cmd) } else { Ok(Vec::new()) };
+            if let Err(e) = evloop_send.send( Message::Remote( RemoteRequest { cmd: cmd, blueprint: blueprint } ) ) {
+                println!("Cannot notify eventloop about remote command: {:?}", e);
+            }
+        }
+    }
+}
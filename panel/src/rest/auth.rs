@@ -0,0 +1,133 @@
+use hyper::header::{Headers, Authorization, Bearer};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rustc_serialize::json;
+use rustc_serialize::hex::FromHex;
+use std::str::from_utf8;
+use time;
+use config::{Config, Scope};
+
+//Signed requests older than this are rejected, so they cannot be replayed later
+const MAX_SIGNATURE_AGE_S : i64 = 300;
+
+pub enum Credentials {
+    Missing,
+    Bearer(String),
+    //Headers X-Key-Id, X-Timestamp (unix seconds) and X-Signature:
+    //hex HMAC-SHA256 of "<METHOD>\n<path>\n<timestamp>\n<body>"
+    Signed { key_id: String, timestamp: i64, signature: Vec<u8> }
+}
+
+pub enum AuthError {
+    Unauthorized(String), //401
+    Forbidden(String)     //403
+}
+
+#[derive(RustcEncodable)]
+struct AuthReply<'a> {
+    error: &'static str,
+    reason: &'a str
+}
+
+impl AuthError {
+    pub fn json(&self) -> String {
+        let reply = match *self {
+            AuthError::Unauthorized(ref reason) => AuthReply { error: "unauthorized", reason: reason },
+            AuthError::Forbidden(ref reason) => AuthReply { error: "forbidden", reason: reason }
+        };
+        json::encode(&reply).unwrap()
+    }
+}
+
+fn raw_header(headers : &Headers, name : &str) -> Option<String> {
+    headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| from_utf8(value).ok())
+        .map(|value| value.trim().to_string())
+}
+
+pub fn credentials(headers : &Headers) -> Credentials {
+    if let Some(&Authorization(Bearer { ref token })) = headers.get::<Authorization<Bearer>>() {
+        return Credentials::Bearer(token.clone());
+    }
+    let key_id = raw_header(headers, "X-Key-Id");
+    let timestamp = raw_header(headers, "X-Timestamp").and_then(|timestamp| timestamp.parse().ok());
+    let signature = raw_header(headers, "X-Signature").and_then(|signature| signature.from_hex().ok());
+    match (key_id, timestamp, signature) {
+        (Some(key_id), Some(timestamp), Some(signature)) =>
+            Credentials::Signed { key_id: key_id, timestamp: timestamp, signature: signature },
+        _ => Credentials::Missing
+    }
+}
+
+//Who sent the request, for logs. Tokens are secret, so only the key id is named
+pub fn identity(credentials : &Credentials) -> String {
+    match *credentials {
+        Credentials::Missing => "anonymous".to_string(),
+        Credentials::Bearer(_) => "token".to_string(),
+        Credentials::Signed { ref key_id, .. } => format!("key {}", key_id)
+    }
+}
+
+fn permits(scopes : &[Scope], required : Scope) -> bool {
+    scopes.contains(&required) || scopes.contains(&Scope::Control)
+}
+
+//Everything but the signature, so requests without valid credentials are rejected before their body is read
+pub fn precheck(config : &Config, credentials : &Credentials, required : Scope) -> Result<(), AuthError> {
+    verify(config, credentials, required, None)
+}
+
+//The body is only needed for signed requests
+pub fn check(config : &Config, credentials : &Credentials, required : Scope,
+        method : &str, path : &str, body : &[u8]) -> Result<(), AuthError> {
+    verify(config, credentials, required, Some((method, path, body)))
+}
+
+fn verify(config : &Config, credentials : &Credentials, required : Scope,
+        request : Option<(&str, &str, &[u8])>) -> Result<(), AuthError> {
+    if !config.auth_required() {
+        return Ok(());
+    }
+    let scopes = match *credentials {
+        Credentials::Missing => return Err(AuthError::Unauthorized("credentials missing".to_string())),
+        Credentials::Bearer(ref token) => {
+            //Every token is compared in constant time, a lookup would reveal how much of a guess was right
+            let mut matched = None;
+            for (candidate, scopes) in config.api_tokens.iter() {
+                if fixed_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                    matched = Some(scopes);
+                }
+            }
+            match matched {
+                Some(scopes) => scopes,
+                None => return Err(AuthError::Unauthorized("invalid token".to_string()))
+            }
+        },
+        Credentials::Signed { ref key_id, timestamp, ref signature } => {
+            let key = match config.hmac_keys.get(key_id) {
+                Some(key) => key,
+                None => return Err(AuthError::Unauthorized("unknown key".to_string()))
+            };
+            if (time::get_time().sec - timestamp).abs() > MAX_SIGNATURE_AGE_S {
+                return Err(AuthError::Unauthorized("signature expired".to_string()));
+            }
+            if let Some((method, path, body)) = request {
+                let mut hmac = Hmac::new(Sha256::new(), key.secret.as_bytes());
+                hmac.input(format!("{}\n{}\n{}\n", method, path, timestamp).as_bytes());
+                hmac.input(body);
+                if hmac.result() != MacResult::new(signature) { //Compared in constant time
+                    return Err(AuthError::Unauthorized("invalid signature".to_string()));
+                }
+            }
+            &key.scopes
+        }
+    };
+    if permits(scopes, required) {
+        Ok(())
+    } else {
+        Err(AuthError::Forbidden(format!("{:?} scope required", required).to_lowercase()))
+    }
+}
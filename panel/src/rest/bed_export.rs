@@ -0,0 +1,98 @@
+use hyper::mime;
+use hyper::mime::{Mime, TopLevel, SubLevel};
+use rustc_serialize::json;
+use internals::jobs::FinishedJobs;
+use vbed::Layer;
+
+//Downloads of the virtual bed of finished jobs:
+// /jobs/<id>/bed            all layers as JSON
+// /jobs/<id>/bed/<z>.svg    one layer as vector image
+// /jobs/<id>/bed/<z>.png    one layer as raster image
+// /jobs/<id>/voxels         voxel dump
+// /jobs/<id>/quality        result of the quality check
+#[derive(Clone, Copy)]
+pub enum BedExport {
+    Layers,
+    LayerSvg(i32),
+    LayerPng(i32),
+    Voxels,
+    Quality
+}
+
+#[derive(RustcEncodable)]
+struct BedLayers<'a> {
+    job_id: usize,
+    title: String,
+    printhead: usize,
+    layers: Vec<&'a Layer>
+}
+
+pub fn parse_path(path : &str) -> Option<(usize, BedExport)> {
+    let parts : Vec<&str> = path.trim_matches('/').split('/').collect();
+    if parts.len() < 3 || parts[0] != "jobs" {
+        return None;
+    }
+    let job_id = match parts[1].parse() {
+        Ok(job_id) => job_id,
+        Err(_) => return None
+    };
+    let export = match (parts.len(), parts[2]) {
+        (3, "bed") => BedExport::Layers,
+        (3, "voxels") => BedExport::Voxels,
+        (3, "quality") => BedExport::Quality,
+        (4, "bed") => {
+            let file = parts[3];
+            let z = match file[.. file.rfind('.').unwrap_or(file.len())].parse() {
+                Ok(z) => z,
+                Err(_) => return None
+            };
+            if file.ends_with(".svg") {
+                BedExport::LayerSvg(z)
+            } else if file.ends_with(".png") {
+                BedExport::LayerPng(z)
+            } else {
+                return None;
+            }
+        },
+        _ => return None
+    };
+    Some((job_id, export))
+}
+
+//Ok(None) if the job (or layer) is unknown, Err if the bed has too many voxels to export
+pub fn render(finished : &FinishedJobs, job_id : usize, export : BedExport, voxel_size : i32) -> Result<Option<(Vec<u8>, Mime)>, String> {
+    let job = match finished.get(&job_id) {
+        Some(job) => job,
+        None => return Ok(None)
+    };
+    Ok(match export {
+        BedExport::Layers => {
+            let layers = BedLayers {
+                job_id: job.job_id,
+                title: job.title.clone(),
+                printhead: job.printhead,
+                layers: job.bed.layers()
+            };
+            Some(( json::encode(&layers).unwrap().into_bytes(),
+                Mime(TopLevel::Application, SubLevel::Json, vec![(mime::Attr::Charset, mime::Value::Utf8)]) ))
+        },
+        BedExport::LayerSvg(z) => job.bed.layer_svg(z).map(|svg|
+            ( svg.into_bytes(), Mime(TopLevel::Image, SubLevel::Ext("svg+xml".to_string()), vec![]) )),
+        BedExport::LayerPng(z) => job.bed.layer_png(z).map(|png|
+            ( png, Mime(TopLevel::Image, SubLevel::Png, vec![]) )),
+        BedExport::Voxels => Some(( try!(job.bed.voxel_dump(voxel_size)).into_bytes(),
+            Mime(TopLevel::Text, SubLevel::Plain, vec![(mime::Attr::Charset, mime::Value::Utf8)]) )),
+        BedExport::Quality => job.quality.as_ref().map(|report|
+            ( json::encode(report).unwrap().into_bytes(),
+              Mime(TopLevel::Application, SubLevel::Json, vec![(mime::Attr::Charset, mime::Value::Utf8)]) ))
+    })
+}
+
+#[derive(RustcEncodable)]
+struct ExportError {
+    error: String
+}
+
+pub fn error(reason : &str) -> String {
+    json::encode(&ExportError { error: reason.to_string() }).unwrap()
+}
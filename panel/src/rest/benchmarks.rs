@@ -0,0 +1,69 @@
+use std::str::from_utf8;
+use rustc_serialize::json;
+use internals::Parts;
+use benchmark::{BenchmarkSpec, BenchmarkReq, BenchmarkReport, BenchmarkReports};
+use messages::Request;
+
+//Throughput benchmarks with a chosen command mix:
+// POST /benchmark      {"count": 1000, "mix": {"level": 1, "dot": 5, "line": 2}, "printhead": 3}, all optional
+// /benchmarks          reports of running and finished benchmarks
+// /benchmarks/<id>     one report
+#[derive(RustcEncodable)]
+struct BenchmarkStarted {
+    success: bool,
+    reason: String,
+    benchmark_id: Option<usize>,
+    printhead: Option<usize>
+}
+
+fn failed(reason : &str) -> String {
+    json::encode(&BenchmarkStarted { success: false, reason: reason.to_string(), benchmark_id: None, printhead: None }).unwrap()
+}
+
+pub fn parse_path(path : &str) -> Option<Option<usize>> {
+    let parts : Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (parts.len(), parts[0]) {
+        (1, "benchmarks") => Some(None),
+        (2, "benchmarks") => parts[1].parse().ok().map(Some),
+        _ => None
+    }
+}
+
+//Parsed in the REST thread, started by the event loop
+pub fn parse_request(body : &[u8]) -> Result<Request, String> {
+    let req : BenchmarkReq = try!( from_utf8(body).ok().and_then(|text| json::decode(text).ok()).ok_or("invalid request".to_string()) );
+    let spec = try!( BenchmarkSpec::new(req.count, req.mix.as_ref()) );
+    Ok( Request::Benchmark { spec: spec, printhead: req.printhead } )
+}
+
+//Reply with the ids of the benchmarked printhead and of the benchmark
+pub fn started(result : Result<(usize, usize), String>) -> String {
+    match result {
+        Ok((printhead, benchmark_id)) => json::encode(&BenchmarkStarted { success: true, reason: String::new(),
+            benchmark_id: Some(benchmark_id), printhead: Some(printhead) }).unwrap(),
+        Err(e) => failed(&e)
+    }
+}
+
+fn running(clients : &Parts) -> Vec<BenchmarkReport> {
+    clients.values().filter_map(|cell| {
+        let part = cell.borrow();
+        part.benchmark.as_ref().map(|run| run.report(part.id, true, None))
+    }).collect()
+}
+
+pub fn list(clients : &Parts, reports : &BenchmarkReports) -> String {
+    let mut all : Vec<BenchmarkReport> = reports.values().cloned().collect();
+    all.extend(running(clients));
+    all.sort_by_key(|report| report.id);
+    json::encode(&all).unwrap()
+}
+
+//None if there is no benchmark with that id
+pub fn get(clients : &Parts, reports : &BenchmarkReports, id : usize) -> Option<String> {
+    let report = match reports.get(&id) {
+        Some(report) => Some(report.clone()),
+        None => running(clients).into_iter().find(|report| report.id == id)
+    };
+    report.map(|report| json::encode(&report).unwrap())
+}
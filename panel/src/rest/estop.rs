@@ -0,0 +1,40 @@
+use std::str::from_utf8;
+use rustc_serialize::json;
+use super::auth;
+use super::auth::Credentials;
+
+//Emergency stop of all printheads, the body is optional:
+// POST /emergency_stop         {"operator": "alice"}
+// POST /emergency_stop/reset   jobs are accepted again
+#[derive(RustcDecodable)]
+struct StopReq {
+    operator: Option<String>
+}
+
+pub fn parse_path(path : &str) -> Option<bool> {
+    match path.trim_matches('/') {
+        "emergency_stop" => Some(false),
+        "emergency_stop/reset" => Some(true),
+        _ => None
+    }
+}
+
+//Who requested the stop, the printheads are stopped by the event loop
+pub fn requested_by(credentials : &Credentials, body : &[u8]) -> String {
+    let operator = from_utf8(body).ok()
+        .and_then(|text| json::decode::<StopReq>(text).ok())
+        .and_then(|req| req.operator);
+    match operator {
+        Some(operator) => format!("rest {} ({})", auth::identity(credentials), operator),
+        None => format!("rest {}", auth::identity(credentials))
+    }
+}
+
+//changed is false if the stop was already active or there was none to reset
+pub fn reply(changed : bool, reset : bool) -> String {
+    match (changed, reset) {
+        (true, _) => "{ \"success\": true, \"reason\": \"\"}".to_string(),
+        (false, false) => "{ \"success\": true, \"reason\": \"already active\"}".to_string(),
+        (false, true) => "{ \"success\": false, \"reason\": \"no emergency stop active\"}".to_string()
+    }
+}
@@ -0,0 +1,33 @@
+use hyper::{Control, Next};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+
+//Clients of GET /events, each gets every event as server-sent event
+pub struct EventStreams {
+    subscribers: Mutex<Vec<(mpsc::Sender<String>, Control)>>
+}
+
+pub type SharedEventStreams = Arc<EventStreams>;
+
+pub fn format(name : &str, data : &str) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+impl EventStreams {
+    pub fn new() -> SharedEventStreams {
+        Arc::new( EventStreams { subscribers: Mutex::new(Vec::new()) } )
+    }
+
+    pub fn subscribe(&self, control : Control) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push((tx, control));
+        rx
+    }
+
+    //Closed connections are dropped here
+    pub fn broadcast(&self, name : &str, data : &str) {
+        let message = format(name, data);
+        self.subscribers.lock().unwrap().retain(|&(ref tx, ref control)|
+            tx.send(message.clone()).is_ok() && control.ready(Next::write()).is_ok() );
+    }
+}
@@ -0,0 +1,35 @@
+use rustc_serialize::json;
+use internals::history::{History, HistoryQuery};
+
+//Finished jobs of this and earlier panel runs:
+// /jobs                                    all jobs, oldest first
+// /jobs?from=<ms>&to=<ms>&outcome=failed   finished in [from, to) with the outcome (done, cancelled, failed, interrupted or discarded)
+// /jobs/<id>                               one job
+pub enum HistoryPath {
+    List(HistoryQuery),
+    Get(usize)
+}
+
+//None for the bed exports below /jobs/<id>/ and invalid requests
+pub fn parse_path(path : &str) -> Option<HistoryPath> {
+    let (path, query) = match path.find('?') {
+        Some(pos) => (&path[.. pos], &path[pos + 1 ..]),
+        None => (path, "")
+    };
+    let parts : Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (parts.len(), parts[0]) {
+        (1, "jobs") => HistoryQuery::parse(query).ok().map(HistoryPath::List),
+        (2, "jobs") if query.is_empty() => parts[1].parse().ok().map(HistoryPath::Get),
+        _ => None
+    }
+}
+
+pub fn list(history : &History, query : &HistoryQuery) -> String {
+    let entries : Vec<_> = history.entries.iter().filter(|entry| query.matches(entry)).collect();
+    json::encode(&entries).unwrap()
+}
+
+//None if the job has not finished yet or never existed
+pub fn get(history : &History, job_id : usize) -> Option<String> {
+    history.entries.iter().rev().find(|entry| entry.job_id == job_id).map(|entry| json::encode(entry).unwrap())
+}
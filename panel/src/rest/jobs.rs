@@ -0,0 +1,64 @@
+use std::str::from_utf8;
+use rustc_serialize::json;
+use rustc_serialize::base64::FromBase64;
+use messages::Request;
+
+//Jobs are started and cancelled by the event loop:
+// POST /print    {"blueprint": "<base64>", "title": "...", "split": "region"}, split is optional
+// POST /cancel   {"printhead": 3}, the body is optional, without a printhead all jobs are cancelled
+#[derive(RustcDecodable)]
+struct PrintReq {
+    blueprint: String,
+    title: String,
+    split: Option<String> //"region" or "layers" prints on all free printheads
+}
+
+#[derive(RustcDecodable)]
+struct CancelReq {
+    printhead: Option<usize>
+}
+
+#[derive(RustcEncodable)]
+struct JobReply {
+    success: bool,
+    reason: String,
+    job_id: Option<usize>,
+    printheads: Vec<usize>
+}
+
+pub fn parse_print(body : &[u8]) -> Result<Request, String> {
+    let req : PrintReq = try!( from_utf8(body).ok().and_then(|text| json::decode(text).ok()).ok_or("invalid request".to_string()) );
+    let bp = try!( req.blueprint.from_base64().map_err(|e| format!("Invalid blueprint data: {}", e)) );
+    Ok( Request::Print { blueprint: bp, title: req.title, split: req.split } )
+}
+
+pub fn parse_cancel(body : &[u8]) -> Result<Request, String> {
+    if body.is_empty() {
+        return Ok( Request::Cancel(None) );
+    }
+    let req : CancelReq = try!( from_utf8(body).ok().and_then(|text| json::decode(text).ok()).ok_or("invalid request".to_string()) );
+    Ok( Request::Cancel(req.printhead) )
+}
+
+//Reply with the job id and the printheads working on it
+pub fn started(result : Result<(usize, Vec<usize>), String>) -> String {
+    let reply = match result {
+        Ok((job_id, printheads)) => JobReply { success: true, reason: String::new(), job_id: Some(job_id), printheads: printheads },
+        Err(e) => JobReply { success: false, reason: e, job_id: None, printheads: Vec::new() }
+    };
+    json::encode(&reply).unwrap()
+}
+
+//Reply with the printheads whose job was cancelled
+pub fn cancelled(result : Result<Vec<usize>, String>) -> String {
+    let reply = match result {
+        Ok(printheads) => JobReply { success: true, reason: String::new(), job_id: None, printheads: printheads },
+        Err(e) => JobReply { success: false, reason: e, job_id: None, printheads: Vec::new() }
+    };
+    json::encode(&reply).unwrap()
+}
+
+//Body of a request that could not be parsed
+pub fn invalid(reason : &str) -> String {
+    json::encode(&JobReply { success: false, reason: reason.to_string(), job_id: None, printheads: Vec::new() }).unwrap()
+}
@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+use rustc_serialize::json;
+use internals::{PrinterPartType, Parts};
+use latency;
+use latency::{CommandLatencies, LatencySummary};
+
+//Round trip times of the printhead commands since the printheads connected:
+// /latency   by command type, over all printheads and for each one
+#[derive(RustcEncodable)]
+struct PrintheadLatency {
+    printhead: usize,
+    serial: u32,
+    commands: HashMap<String, LatencySummary>
+}
+
+#[derive(RustcEncodable)]
+struct LatencyInfo {
+    commands: HashMap<String, LatencySummary>,
+    printheads: Vec<PrintheadLatency>
+}
+
+pub fn get(clients : &Parts) -> String {
+    let mut total = CommandLatencies::new();
+    let mut printheads = Vec::new();
+    for cell in clients.values() {
+        let part = cell.borrow();
+        if part.parttype != PrinterPartType::Printhead {
+            continue;
+        }
+        latency::merge(&mut total, &part.latency);
+        printheads.push( PrintheadLatency {
+            printhead: part.id,
+            serial: part.serial,
+            commands: latency::summarize(&part.latency)
+        } );
+    }
+    printheads.sort_by_key(|printhead| printhead.printhead);
+    json::encode(&LatencyInfo { commands: latency::summarize(&total), printheads: printheads }).unwrap()
+}
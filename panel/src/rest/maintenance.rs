@@ -0,0 +1,49 @@
+use rustc_serialize::json;
+use maintenance::{Maintenance, PartKey};
+
+//Lifetime and since-service counters of every part ever connected, by part type and serial:
+// /maintenance                                all parts
+// /maintenance/<type>/<serial>                one part, type is printhead, material, sensor or heater
+// POST /maintenance/<type>/<serial>/reset     after the part was serviced, clears its service alerts
+pub enum MaintenancePath {
+    Get(Option<PartKey>),
+    Reset(PartKey)
+}
+
+#[derive(RustcEncodable)]
+struct ResetReply {
+    success: bool,
+    reason: String
+}
+
+fn part_key(part_type : &str, serial : &str) -> Option<PartKey> {
+    serial.parse().ok().map(|serial| (part_type.to_string(), serial))
+}
+
+pub fn parse_path(path : &str) -> Option<MaintenancePath> {
+    let parts : Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (parts.len(), parts[0]) {
+        (1, "maintenance") => Some(MaintenancePath::Get(None)),
+        (3, "maintenance") => part_key(parts[1], parts[2]).map(|key| MaintenancePath::Get(Some(key))),
+        (4, "maintenance") if parts[3] == "reset" => part_key(parts[1], parts[2]).map(MaintenancePath::Reset),
+        _ => None
+    }
+}
+
+pub fn list(maintenance : &Maintenance) -> String {
+    let records : Vec<_> = maintenance.records.values().collect();
+    json::encode(&records).unwrap()
+}
+
+//None if the part was never connected
+pub fn get(maintenance : &Maintenance, key : &PartKey) -> Option<String> {
+    maintenance.records.get(key).map(|record| json::encode(record).unwrap())
+}
+
+pub fn reset_reply(result : Result<(), String>) -> String {
+    let reply = match result {
+        Ok(()) => ResetReply { success: true, reason: String::new() },
+        Err(e) => ResetReply { success: false, reason: e }
+    };
+    json::encode(&reply).unwrap()
+}
@@ -1,20 +1,46 @@
+use hyper::Control;
 use hyper::server::Server;
-use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+use std::sync::Arc;
 use mio;
-use mio::Token;
-use internals::Printerpart;
+use config::Config;
+use messages::Message;
 
 mod printer_rest;
+mod event_stream;
+mod auth;
+mod tls;
+//Rendered by the event loop, which owns the parts
+pub mod parts;
+pub mod latency;
+pub mod benchmarks;
+pub mod telemetry;
+pub mod maintenance;
+pub mod estop;
+pub mod jobs;
+pub mod history;
+pub mod bed_export;
+
+pub use self::event_stream::{EventStreams, SharedEventStreams};
 
 use self::printer_rest::PrinterRest;
 
-pub fn serve(internals : Arc<RwLock<HashMap<Token, Arc<RwLock<Printerpart>>>>>,
-        evloop_send : mio::Sender<Token>) {
-    let server = Server::http(&"0.0.0.0:18080".parse().unwrap()).unwrap();
+pub fn serve(evloop_send : mio::Sender<Message>, config : Arc<Config>, streams : SharedEventStreams) {
+    let addr = "0.0.0.0:18080".parse().unwrap();
     let evloop_send = Arc::new( evloop_send );
-    let (_, serverloop) = server.handle(|_| PrinterRest::new( internals.clone(), evloop_send.clone() ) ).unwrap();
+    let ssl = tls::server_context(&config);
+    let factory = |control : Control| PrinterRest::new( evloop_send.clone(), config.clone(),
+        streams.clone(), control );
 
-    serverloop.run();
+    match ssl {
+        Some(ssl) => {
+            println!("Serving REST API over https");
+            let (_, serverloop) = Server::https(&addr, ssl).unwrap().handle(factory).unwrap();
+            serverloop.run();
+        }
+        None => {
+            let (_, serverloop) = Server::http(&addr).unwrap().handle(factory).unwrap();
+            serverloop.run();
+        }
+    }
 }
 
@@ -0,0 +1,77 @@
+use mio::Token;
+use rustc_serialize::json;
+use internals::{Printerpart, PrinterPartType, PartCounters, Parts};
+
+//What is connected to port 18000:
+// /parts        all parts
+// /parts/<id>   one part, the id is its token
+#[derive(RustcEncodable)]
+struct PartInfo {
+    id: usize,
+    serial: u32,
+    part_type: String,
+    material: i32,
+    material_empty: Option<bool>, //Only known for material containers
+    material_used: Option<u64>,   //Units since the last refill
+    job_id: Option<usize>,
+    job_title: Option<String>,
+    paused: bool,
+    waiting_for_material: Option<i32>,
+    heating: Option<String>, //Zone a printhead waits for
+    heater_target: Option<f64>, //Set point of a heater
+    pipeline_window: Option<usize>, //Commands a printhead may have in flight
+    in_flight: Option<usize>,
+    connected_at: i64,
+    last_activity: i64,
+    healthy: bool,
+    heartbeats_missed: u32,
+    service_due: Vec<String>, //Counters that have reached their service interval, see /maintenance
+    commands: PartCounters
+}
+
+fn part_info(part : &Printerpart) -> PartInfo {
+    let container = part.parttype == PrinterPartType::Material;
+    let printhead = part.parttype == PrinterPartType::Printhead;
+    PartInfo {
+        id: part.id,
+        serial: part.serial,
+        part_type: format!("{:?}", part.parttype).to_lowercase(),
+        material: part.matid,
+        material_empty: if container { Some(part.matempty) } else { None },
+        material_used: if container { Some(part.mat_used) } else { None },
+        job_id: part.job_id,
+        job_title: if part.blueprint.is_some() { part.job_title.clone() } else { None },
+        paused: part.paused,
+        waiting_for_material: part.matwait,
+        heating: part.heating.map(|heating| heating.zone.name().to_string()),
+        heater_target: part.heater_target,
+        pipeline_window: if printhead { Some(part.window) } else { None },
+        in_flight: if printhead { Some(part.in_flight.len()) } else { None },
+        connected_at: part.connected_at,
+        last_activity: part.last_activity,
+        healthy: part.healthy,
+        heartbeats_missed: part.heartbeats_missed,
+        service_due: part.service_due.clone(),
+        commands: part.counters
+    }
+}
+
+pub fn parse_path(path : &str) -> Option<Option<usize>> {
+    let parts : Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (parts.len(), parts[0]) {
+        (1, "parts") => Some(None),
+        (2, "parts") => parts[1].parse().ok().map(Some),
+        _ => None
+    }
+}
+
+pub fn list(clients : &Parts) -> String {
+    let mut parts : Vec<PartInfo> = clients.values().map(|cell| part_info(&cell.borrow())).collect();
+    parts.sort_by_key(|part| part.id);
+    json::encode(&parts).unwrap()
+}
+
+//None if no part with that id is connected
+pub fn get(clients : &Parts, id : usize) -> Option<String> {
+    clients.get(&Token(id)).map(|cell| json::encode(&part_info(&cell.borrow())).unwrap())
+}
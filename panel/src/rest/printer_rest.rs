@@ -1,177 +1,376 @@
-use hyper::{Get, Post, StatusCode, RequestUri, Decoder, Encoder, Next};
-use hyper::header::ContentType;
-use hyper::net::HttpStream;
-use hyper::server::{Handler, Request, Response};
+use hyper;
+use hyper::{Get, Post, StatusCode, RequestUri, Decoder, Encoder, Next, Control};
+use hyper::header::{ContentType, ContentLength, CacheControl, CacheDirective};
+use hyper::net::Transport;
+use hyper::server::{Handler, Request as HttpRequest, Response};
 use hyper::mime;
-use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+use std::sync::Arc;
+use std::cmp;
 use std::io;
-use std::io::{Write, Read, Cursor};
+use std::io::{Write, Read};
+use std::sync::mpsc;
+use std::time::Duration;
 use mio;
-use mio::Token;
-use internals::{Printerpart, PrinterPartType};
 use rustc_serialize::json;
-use rustc_serialize::base64::FromBase64;
-use std::str::from_utf8;
-use std::borrow::Borrow;
+use internals::history::HistoryQuery;
+use config::Config;
+use messages::{Message, Request, Reply, ReplySender};
+use super::bed_export;
+use super::bed_export::BedExport;
+use super::event_stream;
+use super::parts;
+use super::benchmarks;
+use super::telemetry;
+use super::maintenance;
+use super::history;
+use super::history::HistoryPath;
+use super::maintenance::MaintenancePath;
+use super::estop;
+use super::jobs;
+use telemetry::Quantity;
+use maintenance::PartKey;
+use super::auth;
+use super::auth::{Credentials, AuthError};
+use config::Scope;
+use super::event_stream::SharedEventStreams;
 
-#[derive(RustcEncodable)]
-struct Status {
-    busy: bool,
-    matempty: bool,
-    current_job: String
-}
-
-#[derive(RustcDecodable)]
-struct PrintReq {
-    blueprint: String,
-    title: String
-}
+//A comment is sent on idle event streams, so clients can detect dead connections
+const KEEPALIVE_MS : u64 = 10000;
+//The event loop answers between two events, it only misses this while it is stuck
+const REPLY_TIMEOUT_MS : u64 = 5000;
 
 pub struct PrinterRest {
-    pub internals: Arc<RwLock<HashMap<Token, Arc<RwLock<Printerpart>>>>>,
-    evloop_send:   Arc<mio::Sender<Token>>,
+    evloop_send:   Arc<mio::Sender<Message>>,
+    config:        Arc<Config>,
+    streams:       SharedEventStreams,
+    control:       Control,
+    stream:        Option<mpsc::Receiver<String>>,
+    reply:         Option<mpsc::Receiver<Reply>>, //Until the event loop answered
+    status:        Option<StatusCode>, //Set before the response, if not 200
+    credentials:   Credentials,
+    method:        String,
+    path:          String,
     action:        Action,
     buf:           Vec<u8>,
-    read_pos:      usize
+    read_pos:      usize,
+    output:        Option<Vec<u8>>, //Prepared response for larger downloads
+    write_pos:     usize
+}
+
+#[derive(RustcEncodable)]
+struct ErrorReply {
+    error: String
 }
 
 enum Action {
     InvalidRequest,
     GetStatus,
-    Print
+    Print,
+    Cancel,
+    GetBed(usize, BedExport),
+    GetJobs(HistoryQuery),
+    GetJob(usize),
+    GetParts(Option<usize>),
+    GetLatency,
+    GetMetrics,
+    Benchmark,
+    GetBenchmarks(Option<usize>),
+    GetTelemetry(Option<Quantity>),
+    GetMaintenance(Option<PartKey>),
+    ResetMaintenance(PartKey),
+    EmergencyStop(bool), //Reset if true
+    Events,
+    Denied //401, 403 or 413, the JSON error is in the output
 }
 
 impl PrinterRest {
-    pub fn new(internals: Arc<RwLock<HashMap<Token, Arc<RwLock<Printerpart>>>>>,
-            evloop_send: Arc<mio::Sender<Token>>) -> Self{
+    pub fn new(evloop_send: Arc<mio::Sender<Message>>, config: Arc<Config>,
+            streams: SharedEventStreams, control: Control) -> Self{
         PrinterRest {
-            internals: internals,
             evloop_send: evloop_send,
+            config:    config,
+            streams:   streams,
+            control:   control,
+            stream:    None,
+            reply:     None,
+            status:    None,
+            credentials: Credentials::Missing,
+            method:    String::new(),
+            path:      String::new(),
             action:    Action::InvalidRequest,
             buf:       vec![0;0], //Start with empty read buffer, will be increased when used
-            read_pos:  0
+            read_pos:  0,
+            output:    None,
+            write_pos: 0
         }
     }
 
-    fn get_status(&mut self) -> String {
-        let status = Status {
-            busy: self.get_free_printhead().is_none(), //Printer is busy if no printhead is available (so it also works if there is no Printhead connected yet)
-            matempty: !self.check_mat_status(), 
-            current_job: self.get_job_title()
+    //The event loop wakes this request up once it has answered
+    fn ask(&mut self, request : Request) -> Result<(), String> {
+        let (reply_send, reply_recv) = mpsc::channel();
+        let reply_to = ReplySender::new(reply_send, self.control.clone());
+        try!( self.evloop_send.send( Message::Rest(request, reply_to) ).map_err(|_| "notify failed".to_string()) );
+        self.reply = Some(reply_recv);
+        Ok(())
+    }
+
+    //Called once the request is complete, the response waits for the event loop
+    fn dispatch(&mut self) -> Next {
+        if let Action::InvalidRequest = self.action {
+            return Next::write();
+        }
+        if let Err(e) = self.authorize() {
+            return self.deny(e);
+        }
+        let request = match self.request() {
+            Ok(request) => request,
+            Err(e) => {
+                self.status = Some(StatusCode::BadRequest);
+                self.output = Some( jobs::invalid(&e).into_bytes() );
+                return Next::write();
+            }
         };
-        json::encode(&status).unwrap()
+        if let Action::Events = self.action {
+            //Subscribed before asking for the current state, so no event in between is lost
+            self.stream = Some( self.streams.subscribe(self.control.clone()) );
+        }
+        match self.ask(request) {
+            Ok(()) => match self.action {
+                Action::Events => Next::write(), //The status is sent once it arrives
+                _ => Next::wait().timeout(Duration::from_millis(REPLY_TIMEOUT_MS))
+            },
+            Err(e) => {
+                self.unanswered(&e);
+                Next::write()
+            }
+        }
     }
 
-    fn start_print(&mut self) -> String {
-        let reqtext = from_utf8(&self.buf[0 .. self.read_pos]).unwrap();
-        //println!("{}",reqtext);
-        let req : PrintReq = json::decode(reqtext).unwrap();
-        let bp = req.blueprint.from_base64().unwrap();
+    fn deny(&mut self, e : AuthError) -> Next {
+        let status = match e {
+            AuthError::Unauthorized(_) => StatusCode::Unauthorized,
+            AuthError::Forbidden(_) => StatusCode::Forbidden
+        };
+        self.reject(status, e.json())
+    }
 
-        let printhead = self.get_free_printhead();
-        if printhead.is_none() {
-            return "{ \"success\": false, \"reason\": \"no printhead\" }".to_string();
-        }
+    fn reject(&mut self, status : StatusCode, output : String) -> Next {
+        self.status = Some(status);
+        self.output = Some( output.into_bytes() );
+        self.action = Action::Denied;
+        Next::write()
+    }
 
-        let printhead = printhead.unwrap();
-        {
-            let mut printhead = printhead.write().unwrap();
-            printhead.blueprint = Some( Box::new( Cursor::new(bp) ) );
-            printhead.job_title = Some( req.title.clone() );
+    fn too_large(&mut self) -> Next {
+        let error = ErrorReply { error: format!("request body larger than {} bytes", self.config.max_body_bytes) };
+        self.reject(StatusCode::PayloadTooLarge, json::encode(&error).unwrap())
+    }
 
-            let mut magic = [0;4];
-            printhead.blueprint.as_mut().unwrap().read_exact(&mut magic).unwrap();
-            for i in 0..4 {
-                if magic[i] != b"RBAM"[i] {
-                    return "{ \"success\": false, \"reason\": \"invalid blueprint\" }".to_string();
-                }
-            }
+    //Only the signature has to wait for the body, everything else is checked before reading it
+    fn read_body(&mut self, length : Option<u64>) -> Next {
+        if let Err(e) = auth::precheck(&self.config, &self.credentials, self.required_scope()) {
+            return self.deny(e);
         }
-
-        let printheadid = printhead.read().unwrap().id;
-        println!("Started printing job '{}' on printhead({})", &req.title, printheadid);
-        match self.evloop_send.send( Token( printheadid ) ) { //Continue 3d print in internal eventloop
-            Ok(_) => "{ \"success\": true, \"reason\": \"\"}".to_string(),
-            Err(msg) => format!("{{\"success\": false, \"reason\": \"notify failed: {:?}\" }}", msg)
+        match length {
+            Some(length) if length > self.config.max_body_bytes as u64 => self.too_large(),
+            _ => Next::read()
         }
     }
 
-    fn get_free_printhead(self : &Self) -> Option<Arc<RwLock<Printerpart>>> {
-        let clients = self.internals.read().unwrap();
-        for cell in clients.values() {
-            let part = cell.read().unwrap();
-            if part.parttype == PrinterPartType::Printhead
-                    && part.blueprint.is_none() {
-                return Some(cell.clone());
-            }
-        }
-        None
+    fn unanswered(&mut self, reason : &str) {
+        println!("REST request not answered by the event loop: {}", reason);
+        self.status = Some(StatusCode::ServiceUnavailable);
+        self.output = Some( json::encode(&ErrorReply { error: reason.to_string() }).unwrap().into_bytes() );
     }
 
-    fn get_job_title(self : &Self) -> String {
-        let clients = self.internals.read().unwrap();
-        let mut result = Vec::<String>::new();
-        for cell in clients.values() {
-            let part = cell.read().unwrap();
-            if part.parttype != PrinterPartType::Printhead ||
-                   part.job_title.is_none() {
-                continue;
-            }
-            result.push(part.job_title.as_ref().unwrap().clone());
+    //What the event loop has to answer, the bodies of commands are parsed here
+    fn request(&self) -> Result<Request, String> {
+        let body = &self.buf[0 .. self.read_pos];
+        match self.action {
+            Action::GetStatus | Action::Events => Ok(Request::Status),
+            Action::Print => jobs::parse_print(body),
+            Action::Cancel => jobs::parse_cancel(body),
+            Action::GetParts(id) => Ok(Request::Parts(id)),
+            Action::GetLatency => Ok(Request::Latency),
+            Action::GetMetrics => Ok(Request::Metrics),
+            Action::Benchmark => benchmarks::parse_request(body),
+            Action::GetBenchmarks(id) => Ok(Request::Benchmarks(id)),
+            Action::GetTelemetry(quantity) => Ok(Request::Telemetry(quantity)),
+            Action::GetMaintenance(ref key) => Ok(Request::Maintenance(key.clone())),
+            Action::GetJobs(ref query) => Ok(Request::Jobs(query.clone())),
+            Action::GetJob(job_id) => Ok(Request::Job(job_id)),
+            Action::GetBed(job_id, export) => Ok(Request::Bed(job_id, export)),
+            Action::ResetMaintenance(ref key) => Ok(Request::ResetMaintenance(key.clone())),
+            Action::EmergencyStop(reset) => Ok(Request::EmergencyStop { reset: reset, by: estop::requested_by(&self.credentials, body) }),
+            _ => Err("not handled by the event loop".to_string())
         }
-        result.join(", ")
     }
 
-    fn check_mat_status(&self) -> bool {
-        let clients = self.internals.read().unwrap();
-        for cell in clients.values() {
-            let part = cell.read().unwrap();
-            if part.parttype != PrinterPartType::Material {
-                continue;
+    //Sends all queued events, then waits for the next ones
+    fn write_events<T: Transport>(&mut self, transport: &mut Encoder<T>) -> Next {
+        let mut pending = self.output.take().unwrap_or(Vec::new());
+        //Start with the current state, everything after that are changes
+        if let Some(reply) = self.reply.take() {
+            match reply.try_recv() {
+                Ok(Reply::Body(status)) => pending.extend_from_slice(event_stream::format("status", &status).as_bytes()),
+                Err(mpsc::TryRecvError::Empty) => {
+                    self.reply = Some(reply);
+                    self.output = Some(pending);
+                    return Next::wait().timeout(Duration::from_millis(REPLY_TIMEOUT_MS));
+                },
+                _ => ()
             }
-            if part.matempty {
-                return false;
+        }
+        if let Some(ref stream) = self.stream {
+            while let Ok(message) = stream.try_recv() {
+                pending.extend_from_slice(message.as_bytes());
+            }
+        }
+        while self.write_pos < pending.len() {
+            match transport.write(&pending[self.write_pos ..]) {
+                Ok(n) => self.write_pos += n,
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => {
+                        self.output = Some(pending);
+                        return Next::write();
+                    },
+                    _ => {
+                        println!("write error {:?}", e);
+                        return Next::end();
+                    }
+                }
             }
         }
-        true
+        self.write_pos = 0;
+        Next::wait().timeout(Duration::from_millis(KEEPALIVE_MS))
+    }
+
+    fn required_scope(&self) -> Scope {
+        match self.action {
+            Action::Print | Action::Cancel | Action::Benchmark | Action::ResetMaintenance(..) | Action::EmergencyStop(..) => Scope::Control,
+            _ => Scope::Read
+        }
+    }
+
+    fn authorize(&self) -> Result<(), AuthError> {
+        auth::check(&self.config, &self.credentials, self.required_scope(),
+            &self.method, &self.path, &self.buf[0 .. self.read_pos])
     }
 }
 
-impl Handler<HttpStream> for PrinterRest {
-    fn on_request(&mut self, req: Request) -> Next{
+impl<T: Transport> Handler<T> for PrinterRest {
+    fn on_request(&mut self, req: HttpRequest) -> Next{
+        self.credentials = auth::credentials(req.headers());
+        self.method = req.method().to_string();
+        let length = req.headers().get::<ContentLength>().map(|&ContentLength(length)| length);
+        if let RequestUri::AbsolutePath(ref path) = *req.uri() {
+            self.path = path.clone(); //Part of signed requests
+        }
         match *req.uri() {
             RequestUri::AbsolutePath(ref path) =>
             match (req.method(), &path[..]) {
                 (&Get, "/") | (&Get, "/status") => {
                     self.action = Action::GetStatus;
-                    Next::write()
+                    self.dispatch()
                 },
+                //Requests with a body are answered once it is read completely, signatures cover all of it
                 (&Post, "/print") => {
                     self.action = Action::Print;
-                    Next::read_and_write()
+                    self.read_body(length)
+                },
+                (&Post, "/cancel") => {
+                    self.action = Action::Cancel;
+                    self.read_body(length)
+                },
+                (&Post, "/benchmark") => {
+                    self.action = Action::Benchmark;
+                    self.read_body(length)
                 },
-                _ => Next::write(), //InvalidRequest
+                (&Get, "/metrics") => {
+                    self.action = Action::GetMetrics;
+                    self.dispatch()
+                },
+                (&Get, "/latency") => {
+                    self.action = Action::GetLatency;
+                    self.dispatch()
+                },
+                (&Get, path) if path.starts_with("/benchmarks") => {
+                    if let Some(id) = benchmarks::parse_path(path) {
+                        self.action = Action::GetBenchmarks(id);
+                    }
+                    self.dispatch()
+                },
+                (&Get, path) if path.starts_with("/telemetry") => {
+                    if let Some(quantity) = telemetry::parse_path(path) {
+                        self.action = Action::GetTelemetry(quantity);
+                    }
+                    self.dispatch()
+                },
+                (&Get, path) if path.starts_with("/maintenance") => {
+                    if let Some(MaintenancePath::Get(key)) = maintenance::parse_path(path) {
+                        self.action = Action::GetMaintenance(key);
+                    }
+                    self.dispatch()
+                },
+                (&Post, path) if path.starts_with("/maintenance") => {
+                    if let Some(MaintenancePath::Reset(key)) = maintenance::parse_path(path) {
+                        self.action = Action::ResetMaintenance(key);
+                    }
+                    self.dispatch()
+                },
+                (&Post, path) if path.starts_with("/emergency_stop") => {
+                    match estop::parse_path(path) {
+                        Some(reset) => {
+                            self.action = Action::EmergencyStop(reset);
+                            self.read_body(length)
+                        },
+                        None => self.dispatch() //InvalidRequest
+                    }
+                },
+                (&Get, "/events") => {
+                    self.action = Action::Events;
+                    self.dispatch()
+                },
+                (&Get, path) if path.starts_with("/parts") => {
+                    if let Some(id) = parts::parse_path(path) {
+                        self.action = Action::GetParts(id);
+                    }
+                    self.dispatch()
+                },
+                (&Get, path) if path.starts_with("/jobs") => {
+                    if let Some(history_path) = history::parse_path(path) {
+                        self.action = match history_path {
+                            HistoryPath::List(query) => Action::GetJobs(query),
+                            HistoryPath::Get(job_id) => Action::GetJob(job_id)
+                        };
+                    }
+                    else if let Some((job_id, export)) = bed_export::parse_path(path) {
+                        self.action = Action::GetBed(job_id, export);
+                    }
+                    self.dispatch()
+                },
+                _ => self.dispatch(), //InvalidRequest
             },
-            _ => Next::write(), //InvalidRequest
+            _ => self.dispatch(), //InvalidRequest
         }
     }
 
-    fn on_request_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+    fn on_request_readable(&mut self, transport: &mut Decoder<T>) -> Next {
         if self.read_pos >= self.buf.len() {
-            let newsize = self.buf.len() + 2048;
+            //One byte more than allowed, so a body of exactly the maximum still reads to its end
+            let newsize = cmp::min(self.buf.len() + 2048, self.config.max_body_bytes + 1);
             self.buf.resize(newsize, 0); //If buffer is full, resize by 2KB
         }
         match self.action {
-            Action::Print => {
+            Action::Print | Action::Cancel | Action::Benchmark | Action::EmergencyStop(..) => {
                 match transport.read(&mut self.buf[self.read_pos .. ]) {
-                    Ok(0) => Next::write(),
+                    Ok(0) => self.dispatch(), //Body complete
                     Ok(n) => {
                         self.read_pos += n;
-                        Next::read_and_write()
+                        if self.read_pos > self.config.max_body_bytes { self.too_large() } else { Next::read() }
                     }
                     Err(e) => match e.kind() {
-                        io::ErrorKind::WouldBlock => Next::read_and_write(),
+                        io::ErrorKind::WouldBlock => Next::read(),
                         _ => {
                             println!("read error {:?}", e);
                             Next::end()
@@ -187,32 +386,90 @@ impl Handler<HttpStream> for PrinterRest {
 	    res.headers_mut().set( ContentType(
             mime::Mime( mime::TopLevel::Application, mime::SubLevel::Json,
                 vec![(mime::Attr::Charset, mime::Value::Utf8)] ) ) );
+        if let Some(reply) = self.reply.take() {
+            match self.action {
+                Action::Events => self.reply = Some(reply), //The status is the first event
+                _ => match reply.try_recv() {
+                    Ok(Reply::Body(output)) => self.output = Some( output.into_bytes() ),
+                    Ok(Reply::Export(output, mime)) => {
+                        res.headers_mut().set( ContentType(mime) );
+                        self.output = Some(output);
+                    },
+                    Ok(Reply::NotFound) => self.status = Some(StatusCode::NotFound),
+                    Ok(Reply::Unprocessable(e)) => {
+                        self.status = Some(StatusCode::UnprocessableEntity);
+                        self.output = Some( e.into_bytes() );
+                    },
+                    //Commands stay queued in the event loop, a client must not simply repeat them
+                    Err(_) => self.unanswered(&format!("no reply within {}ms, the request may still be applied", REPLY_TIMEOUT_MS))
+                }
+            }
+        }
+        if let Some(status) = self.status {
+            res.set_status(status);
+        }
         match self.action {
-            Action::InvalidRequest => {
-                res.set_status(StatusCode::BadRequest); //Generic 400 failure
-                Next::write()
+            Action::InvalidRequest => res.set_status(StatusCode::BadRequest), //Generic 400 failure
+            Action::GetMetrics if self.status.is_none() => {
+                res.headers_mut().set( ContentType( mime::Mime( mime::TopLevel::Text, mime::SubLevel::Plain,
+                    vec![(mime::Attr::Ext("version".to_string()), mime::Value::Ext("0.0.4".to_string()))] ) ) );
             },
-            _ => {
-                Next::write()
-            }
+            Action::Events if self.status.is_none() => {
+                res.headers_mut().set( ContentType( mime::Mime( mime::TopLevel::Text,
+                    mime::SubLevel::Ext("event-stream".to_string()), vec![(mime::Attr::Charset, mime::Value::Utf8)] ) ) );
+                res.headers_mut().set( CacheControl(vec![CacheDirective::NoCache]) );
+            },
+            _ => ()
         }
+        Next::write()
     }
 
-    fn on_response_writable(&mut self, transport: &mut Encoder<HttpStream>) -> Next {
+    fn on_response_writable(&mut self, transport: &mut Encoder<T>) -> Next {
         match self.action {
             Action::InvalidRequest => {
                 transport.write_all(b"{ \"error\": \"invalidrequest\" }").unwrap();
                 Next::end()
             },
-            Action::GetStatus => {
-                transport.write_all( self.get_status( ).as_bytes() ).unwrap();
-                Next::end()
-            }
-            Action::Print => {
-                transport.write_all( self.start_print( ).as_bytes() ).unwrap();
-                Next::end()
+            Action::Events if self.status.is_none() => self.write_events(transport),
+            Action::GetStatus | Action::Print | Action::Cancel | Action::Benchmark | Action::EmergencyStop(..) | Action::ResetMaintenance(..)
+                    | Action::GetBed(..) | Action::GetJobs(..) | Action::GetJob(..) | Action::GetParts(..) | Action::GetLatency | Action::GetMetrics
+                    | Action::GetBenchmarks(..) | Action::GetTelemetry(..) | Action::GetMaintenance(..) | Action::Events | Action::Denied => {
+                let output = match self.output {
+                    Some(ref output) => output,
+                    None => {
+                        transport.write_all(b"{ \"error\": \"notfound\" }").unwrap();
+                        return Next::end();
+                    }
+                };
+                match transport.write(&output[self.write_pos ..]) { //Might not fit into one write
+                    Ok(n) => {
+                        self.write_pos += n;
+                        if self.write_pos < output.len() { Next::write() } else { Next::end() }
+                    },
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::WouldBlock => Next::write(),
+                        _ => {
+                            println!("write error {:?}", e);
+                            Next::end()
+                        }
+                    }
+                }
             }
-            //_ => unimplemented!()
+        }
+    }
+
+    fn on_error(&mut self, err: hyper::Error) -> Next {
+        match (&self.action, err) {
+            (&Action::Events, hyper::Error::Timeout) => {
+                self.reply = None; //Stream without the current state rather than holding back the events
+                let mut pending = self.output.take().unwrap_or(Vec::new());
+                pending.extend_from_slice(b": keepalive\n\n");
+                self.output = Some(pending);
+                Next::write()
+            },
+            //The event loop did not answer in time, on_response tells the client
+            (_, hyper::Error::Timeout) if self.reply.is_some() => Next::write(),
+            _ => Next::remove()
         }
     }
 }
@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use rustc_serialize::json;
+use internals::{PrinterPartType, PartCell, Parts};
+use telemetry::{Quantity, Reading};
+
+//Readings of the connected sensors:
+// /telemetry              latest reading of every quantity and the alarms of each sensor
+// /telemetry/<quantity>   recent readings, e.g. /telemetry/chamber_temperature
+#[derive(RustcEncodable)]
+struct SensorInfo {
+    sensor: usize,
+    serial: u32,
+    latest: BTreeMap<String, Reading>,
+    alarms: Vec<&'static str> //Quantities outside their limits
+}
+
+#[derive(RustcEncodable)]
+struct SensorSeries {
+    sensor: usize,
+    serial: u32,
+    quantity: &'static str,
+    unit: &'static str,
+    readings: Vec<Reading>
+}
+
+pub fn parse_path(path : &str) -> Option<Option<Quantity>> {
+    let parts : Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (parts.len(), parts[0]) {
+        (1, "telemetry") => Some(None),
+        (2, "telemetry") => Quantity::parse(parts[1]).ok().map(Some),
+        _ => None
+    }
+}
+
+fn sensors(clients : &Parts) -> Vec<PartCell> {
+    let mut sensors : Vec<PartCell> = clients.values()
+        .filter(|cell| cell.borrow().parttype == PrinterPartType::Sensor)
+        .cloned()
+        .collect();
+    sensors.sort_by_key(|cell| cell.borrow().id);
+    sensors
+}
+
+pub fn latest(clients : &Parts) -> String {
+    let infos : Vec<SensorInfo> = sensors(clients).iter().map(|cell| {
+        let part = cell.borrow();
+        SensorInfo {
+            sensor: part.id,
+            serial: part.serial,
+            latest: part.telemetry.latest(),
+            alarms: part.telemetry.alarms.keys().map(|quantity| quantity.name()).collect()
+        }
+    }).collect();
+    json::encode(&infos).unwrap()
+}
+
+pub fn history(clients : &Parts, quantity : Quantity) -> String {
+    let series : Vec<SensorSeries> = sensors(clients).iter().filter_map(|cell| {
+        let part = cell.borrow();
+        part.telemetry.series.get(&quantity).map(|readings| SensorSeries {
+            sensor: part.id,
+            serial: part.serial,
+            quantity: quantity.name(),
+            unit: quantity.unit(),
+            readings: readings.iter().cloned().collect()
+        })
+    }).collect();
+    json::encode(&series).unwrap()
+}
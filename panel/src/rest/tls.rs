@@ -0,0 +1,26 @@
+use std::sync::Arc;
+use hyper::net::Openssl;
+use openssl::ssl::{SslContext, SslMethod, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::x509::X509FileType;
+use config::Config;
+
+//Context for the https server, None if the panel is configured for plain http
+pub fn server_context(config : &Config) -> Option<Openssl> {
+    let (cert, key) = match (config.tls_cert.as_ref(), config.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return None
+    };
+    let mut ctx = SslContext::new(SslMethod::Sslv23).expect("Cannot create TLS context!");
+    ctx.set_certificate_chain_file(cert, X509FileType::PEM)
+        .expect(&format!("Cannot load TLS certificate {}!", cert));
+    ctx.set_private_key_file(key, X509FileType::PEM)
+        .expect(&format!("Cannot load TLS key {}!", key));
+    ctx.check_private_key().expect("TLS key does not match the certificate!");
+
+    if let Some(ref ca) = config.tls_client_ca {
+        //Mutual TLS: the handshake fails for clients without a certificate of our CA
+        ctx.set_CA_file(ca).expect(&format!("Cannot load client CA {}!", ca));
+        ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+    }
+    Some( Openssl { context: Arc::new(ctx) } )
+}
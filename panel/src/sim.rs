@@ -0,0 +1,164 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use rand;
+use rand::Rng;
+use config::{Config, SimContainer};
+use internals::blueprint;
+
+//The command buffer of prnthead, so virtual and real pipelined printheads cannot drift apart
+#[path = "../../prnthead/src/pipeline.rs"]
+mod pipeline;
+
+//Virtual parts for the simulation mode, they connect to the panel like prnthead and mat do,
+//so everything behind the socket runs the same code as with real parts
+//Their serials come from the top of the range, real parts must not use it
+pub const SIM_SERIALS : u32 = 0x8000; //Per part kind
+const PRINTHEAD_SERIAL_BASE : u32 = 0xFFFF0000;
+const CONTAINER_SERIAL_BASE : u32 = PRINTHEAD_SERIAL_BASE + SIM_SERIALS;
+const NEARLY_EMPTY : u32 = 2; //Units left when a container reports it is nearly empty
+
+pub fn reserved_serial(serial : u32) -> bool {
+    serial >= PRINTHEAD_SERIAL_BASE
+}
+
+fn register(handshake : &[u8], serial : u32) -> TcpStream {
+    let mut stream = TcpStream::connect("127.0.0.1:18000").expect("Simulated part cannot connect to the panel!");
+    stream.write_all(handshake).unwrap();
+    stream.write_all(&[serial as u8, (serial >> 8) as u8, (serial >> 16) as u8, (serial >> 24) as u8]).unwrap();
+    stream
+}
+
+fn printhead(serial : u32, delay : Duration, failure_rate : f64) {
+    let mut stream = register(&[1], serial);
+    let mut rng = rand::thread_rng();
+    loop {
+        let mut cmd = [0];
+        if stream.read_exact(&mut cmd).is_err() {
+            return; //Panel is shutting down
+        }
+        if cmd[0] == blueprint::HEARTBEAT {
+            if stream.write_all(&[blueprint::HEARTBEAT]).is_err() {
+                return;
+            }
+            continue;
+        }
+        if cmd[0] == blueprint::STOP {
+            continue; //Nothing is moving between commands, not answered
+        }
+        let result = match blueprint::printhead_param_len(cmd[0]) {
+            Some(len) => {
+                let mut params = vec![0; len];
+                if stream.read_exact(&mut params).is_err() {
+                    return;
+                }
+                thread::sleep(delay);
+                if rng.gen::<f64>() < failure_rate { 255 } else { 1 }
+            },
+            //Known to blueprints but not to printheads, its parameters are skipped to stay in sync
+            None => match blueprint::param_len(cmd[0]) {
+                Some(len) => {
+                    let mut params = vec![0; len];
+                    if stream.read_exact(&mut params).is_err() {
+                        return;
+                    }
+                    255
+                },
+                None => {
+                    //Parameters of unknown commands cannot be skipped, the stream is lost
+                    let _ = stream.write_all(&[255]);
+                    return;
+                }
+            }
+        };
+        if stream.write_all(&[result]).is_err() {
+            return;
+        }
+    }
+}
+
+//Parameter bytes of everything a pipelined virtual printhead has to receive, unknown commands fail
+fn pipelined_param_len(commandid : u8) -> Option<usize> {
+    blueprint::printhead_param_len(commandid).or(blueprint::param_len(commandid))
+}
+
+//Reads commands into its buffer while a worker prints them, so the next command is already there
+fn pipelined_printhead(serial : u32, depth : u8, delay : Duration, failure_rate : f64) {
+    let handshake = [blueprint::EXTENDED_HANDSHAKE, blueprint::HANDSHAKE_VERSION, blueprint::KIND_PRINTHEAD, blueprint::CAPABILITY_PIPELINED];
+    let mut stream = register(&handshake, serial);
+    if stream.write_all(&[depth]).is_err() {
+        return;
+    }
+    pipeline::run(stream, pipelined_param_len, move |commandid, _, _| {
+        thread::sleep(delay);
+        blueprint::printhead_param_len(commandid).is_some() && rand::thread_rng().gen::<f64>() >= failure_rate
+    });
+}
+
+fn container(serial : u32, container : SimContainer, refill : Duration) {
+    let mut stream = register(&[2 + container.matid], serial);
+    let mut level = container.capacity;
+    let refilled = Arc::new(AtomicBool::new(false));
+    let mut empty = false;
+    loop {
+        let mut used = [0];
+        if stream.read_exact(&mut used).is_err() {
+            return;
+        }
+        if used[0] == blueprint::HEARTBEAT {
+            if stream.write_all(&[blueprint::HEARTBEAT]).is_err() {
+                return;
+            }
+            continue;
+        }
+        if refilled.swap(false, Ordering::SeqCst) {
+            level = container.capacity;
+            empty = false;
+        }
+        level = level.saturating_sub(used[0] as u32);
+        if level > NEARLY_EMPTY || empty {
+            continue;
+        }
+        if stream.write_all(&[255]).is_err() {
+            return;
+        }
+        empty = true;
+        //Someone refills it, meanwhile heartbeats are still answered
+        let mut refill_stream = match stream.try_clone() {
+            Ok(refill_stream) => refill_stream,
+            Err(_) => return
+        };
+        let refilled = refilled.clone();
+        thread::spawn( move || {
+            thread::sleep(refill);
+            refilled.store(true, Ordering::SeqCst);
+            let _ = refill_stream.write_all(&[1]);
+        } );
+    }
+}
+
+//Starts the configured virtual parts, the panel has to listen already
+pub fn start(config : &Config) {
+    if !config.simulation() {
+        return;
+    }
+    println!("Simulation mode: {} printhead[s], {} material container[s]", config.sim_printheads, config.sim_containers.len());
+    let delay = Duration::from_millis(config.sim_delay_ms);
+    let refill = Duration::from_millis(config.sim_refill_ms);
+    for index in 0 .. config.sim_printheads {
+        let failure_rate = config.sim_failure_rate;
+        let depth = config.sim_buffer_depth;
+        if depth > 1 {
+            thread::spawn( move || pipelined_printhead(PRINTHEAD_SERIAL_BASE + index, depth, delay, failure_rate) );
+        }
+        else {
+            thread::spawn( move || printhead(PRINTHEAD_SERIAL_BASE + index, delay, failure_rate) );
+        }
+    }
+    for (index, sim_container) in config.sim_containers.iter().enumerate() {
+        let sim_container = *sim_container;
+        thread::spawn( move || container(CONTAINER_SERIAL_BASE + index as u32, sim_container, refill) );
+    }
+}
@@ -0,0 +1,225 @@
+use internals::{PrinterPartType, PartCell, Parts};
+use internals::blueprint::BuildVolume;
+use config::Config;
+use estop::EmergencyStop;
+
+#[derive(RustcEncodable, Clone)]
+pub struct BlockedJob {
+    printhead: usize,
+    material: i32,
+    job: String
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct HeatingJob {
+    printhead: usize,
+    zone: &'static str,
+    target: f64,
+    current: Option<f64> //None until a heater of the zone reports
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct SensorAlarm {
+    sensor: usize,
+    quantity: &'static str,
+    value: f64
+}
+
+#[derive(RustcEncodable, Clone)]
+pub struct ServiceDue {
+    part: usize,
+    serial: u32,
+    counters: Vec<String> //Reached their service interval
+}
+
+//Printer status, served on REST /status and as reply to remote status commands
+#[derive(RustcEncodable, Clone)]
+pub struct Status {
+    busy: bool,
+    matempty: bool,
+    current_job: String,
+    empty_materials: Vec<i32>,
+    blocked_jobs: Vec<BlockedJob>,
+    paused_printheads: Vec<usize>,
+    unhealthy_parts: Vec<usize>, //Missed too many heartbeats
+    sensor_alarms: Vec<SensorAlarm>, //Printheads hold while there are any
+    heating: Vec<HeatingJob>, //Jobs waiting for their temperature
+    toolchanges: Vec<BlockedJob>, //Waiting for the operator to load the material
+    service_due: Vec<ServiceDue>,
+    emergency_stop: Option<EmergencyStop>, //No jobs are accepted until it is reset
+    volume: Option<BuildVolume>
+}
+
+pub fn collect(clients : &Parts, config : &Config, stop : &Option<EmergencyStop>) -> Status {
+    let empty_materials = get_empty_materials(clients);
+    Status {
+        busy: free_printhead(clients).is_none(), //Printer is busy if no printhead is available (so it also works if there is no Printhead connected yet)
+        matempty: !empty_materials.is_empty() && !check_mat_status(clients), //Only if no material at all is left
+        current_job: get_job_title(clients),
+        empty_materials: empty_materials,
+        blocked_jobs: get_blocked_jobs(clients),
+        paused_printheads: get_paused_printheads(clients),
+        unhealthy_parts: get_unhealthy_parts(clients),
+        sensor_alarms: get_sensor_alarms(clients),
+        heating: get_heating_jobs(clients),
+        toolchanges: get_pending_toolchanges(clients),
+        service_due: get_service_due(clients),
+        emergency_stop: stop.clone(),
+        volume: config.build_volume
+    }
+}
+
+//Idle printhead, one still waiting for the answer to a cancelled command is not free yet
+pub fn free_printhead(clients : &Parts) -> Option<PartCell> {
+    free_printheads(clients).into_iter().next()
+}
+
+//Sorted by id, so split jobs always assign their shards the same way
+pub fn free_printheads(clients : &Parts) -> Vec<PartCell> {
+    let mut free : Vec<PartCell> = clients.values().filter(|cell| {
+        let part = cell.borrow();
+        part.parttype == PrinterPartType::Printhead && part.healthy && !part.halted
+            && part.blueprint.is_none() && part.timeoutid.is_none() && part.benchmark.is_none()
+    }).cloned().collect();
+    free.sort_by_key(|cell| cell.borrow().id);
+    free
+}
+
+fn get_job_title(clients : &Parts) -> String {
+    let mut result = Vec::<String>::new();
+    for cell in clients.values() {
+        let part = cell.borrow();
+        if part.parttype != PrinterPartType::Printhead ||
+               part.job_title.is_none() {
+            continue;
+        }
+        let title = part.job_title.as_ref().unwrap();
+        result.push( match (&part.blueprint, &part.job_failure) {
+            (&Some(_), _) => title.clone(),
+            (&None, &Some(_)) => format!("Failed [last: {}]", title),
+            (&None, &None) => format!("Done [last: {}]", title)
+        } );
+    }
+    result.join(", ")
+}
+
+fn check_mat_status(clients : &Parts) -> bool { //true if any material container can still supply
+    for cell in clients.values() {
+        let part = cell.borrow();
+        if part.parttype == PrinterPartType::Material && !part.matempty && part.healthy {
+            return true;
+        }
+    }
+    false
+}
+
+fn get_empty_materials(clients : &Parts) -> Vec<i32> {
+    let mut result = Vec::new();
+    for cell in clients.values() {
+        let part = cell.borrow();
+        if part.parttype == PrinterPartType::Material && part.matempty {
+            result.push(part.matid);
+        }
+    }
+    result.sort();
+    result.dedup();
+    result
+}
+
+fn get_blocked_jobs(clients : &Parts) -> Vec<BlockedJob> {
+    let mut result = Vec::new();
+    for cell in clients.values() {
+        let part = cell.borrow();
+        if let Some(matid) = part.matwait {
+            result.push( BlockedJob {
+                printhead: part.id,
+                material: matid,
+                job: part.job_title.clone().unwrap_or("--".to_string())
+            } );
+        }
+    }
+    result
+}
+
+fn get_pending_toolchanges(clients : &Parts) -> Vec<BlockedJob> {
+    let mut result = Vec::new();
+    for cell in clients.values() {
+        let part = cell.borrow();
+        if let Some(ref toolchange) = part.toolchange {
+            if !toolchange.confirmed {
+                result.push( BlockedJob {
+                    printhead: part.id,
+                    material: toolchange.to,
+                    job: part.job_title.clone().unwrap_or("--".to_string())
+                } );
+            }
+        }
+    }
+    result
+}
+
+fn get_heating_jobs(clients : &Parts) -> Vec<HeatingJob> {
+    let mut result = Vec::new();
+    for cell in clients.values() {
+        let heating = match cell.borrow().heating {
+            Some(heating) => heating,
+            None => continue
+        };
+        let current = clients.values()
+            .map(|heater| heater.borrow())
+            .filter(|heater| heater.parttype == PrinterPartType::Heater && heater.heater_zone == Some(heating.zone))
+            .filter_map(|heater| heater.telemetry.latest_of(heating.zone))
+            .map(|reading| reading.value)
+            .next();
+        result.push( HeatingJob {
+            printhead: cell.borrow().id,
+            zone: heating.zone.name(),
+            target: heating.target,
+            current: current
+        } );
+    }
+    result.sort_by_key(|job| job.printhead);
+    result
+}
+
+fn get_sensor_alarms(clients : &Parts) -> Vec<SensorAlarm> {
+    let mut result = Vec::new();
+    for cell in clients.values() {
+        let part = cell.borrow();
+        for (quantity, value) in &part.telemetry.alarms {
+            result.push( SensorAlarm { sensor: part.id, quantity: quantity.name(), value: *value } );
+        }
+    }
+    result.sort_by_key(|alarm| alarm.sensor);
+    result
+}
+
+fn get_service_due(clients : &Parts) -> Vec<ServiceDue> {
+    let mut result : Vec<ServiceDue> = clients.values()
+        .map(|cell| cell.borrow())
+        .filter(|part| !part.service_due.is_empty())
+        .map(|part| ServiceDue { part: part.id, serial: part.serial, counters: part.service_due.clone() })
+        .collect();
+    result.sort_by_key(|due| due.part);
+    result
+}
+
+fn get_unhealthy_parts(clients : &Parts) -> Vec<usize> {
+    let mut result : Vec<usize> = clients.values()
+        .map(|cell| cell.borrow())
+        .filter(|part| !part.healthy)
+        .map(|part| part.id)
+        .collect();
+    result.sort();
+    result
+}
+
+fn get_paused_printheads(clients : &Parts) -> Vec<usize> {
+    let mut result : Vec<usize> = clients.values()
+        .map(|cell| cell.borrow())
+        .filter(|part| part.paused)
+        .map(|part| part.id)
+        .collect();
+    result.sort();
+    result
+}
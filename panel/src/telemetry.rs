@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, VecDeque};
+use events::now_ms;
+use internals::blueprint;
+
+//Sensor parts send records of the quantity id followed by the value in tenths (little endian i32).
+//Heartbeats are answered with a record of quantity 0.
+pub const RECORD_LEN : usize = 5;
+//Heater parts report their temperature the same way and take set points as SET_TARGET followed by the target in tenths
+pub const SET_TARGET : u8 = 1;
+const HISTORY_LEN : usize = 600; //Readings kept per sensor and quantity
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Quantity {
+    BedTemperature,
+    ChamberTemperature,
+    Humidity
+}
+
+impl Quantity {
+    //Heater zones are numbered like the temperatures
+    pub fn id(&self) -> u8 {
+        match *self {
+            Quantity::BedTemperature => 1,
+            Quantity::ChamberTemperature => 2,
+            Quantity::Humidity => 3
+        }
+    }
+
+    pub fn from_id(id : u8) -> Option<Quantity> {
+        match id {
+            1 => Some(Quantity::BedTemperature),
+            2 => Some(Quantity::ChamberTemperature),
+            3 => Some(Quantity::Humidity),
+            _ => None
+        }
+    }
+
+    pub fn parse(name : &str) -> Result<Quantity, String> {
+        match name {
+            "bed_temperature" => Ok(Quantity::BedTemperature),
+            "chamber_temperature" => Ok(Quantity::ChamberTemperature),
+            "humidity" => Ok(Quantity::Humidity),
+            other => Err(format!("unknown quantity '{}'", other))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Quantity::BedTemperature => "bed_temperature",
+            Quantity::ChamberTemperature => "chamber_temperature",
+            Quantity::Humidity => "humidity"
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match *self {
+            Quantity::BedTemperature | Quantity::ChamberTemperature => "celsius",
+            Quantity::Humidity => "percent"
+        }
+    }
+}
+
+//Printing is held while a reading is outside its limits
+#[derive(Debug, Clone, Copy)]
+pub struct SensorLimit {
+    pub quantity: Quantity,
+    pub min: f64,
+    pub max: f64
+}
+
+#[derive(RustcEncodable, Debug, Clone, Copy)]
+pub struct Reading {
+    pub timestamp: i64, //Milliseconds since the epoch
+    pub value: f64
+}
+
+//Published as JSON on fab/<fab>/printer/<id>/telemetry
+#[derive(RustcEncodable, Debug, Clone)]
+pub struct Sample {
+    pub part: usize,
+    pub quantity: &'static str,
+    pub unit: &'static str,
+    pub value: f64,
+    pub timestamp: i64
+}
+
+pub struct Telemetry {
+    pub series: BTreeMap<Quantity, VecDeque<Reading>>,
+    pub alarms: BTreeMap<Quantity, f64>, //Latest value of the quantities outside their limits
+    pending: Vec<u8> //Incomplete record
+}
+
+impl Telemetry {
+    pub fn new() -> Telemetry {
+        Telemetry { series: BTreeMap::new(), alarms: BTreeMap::new(), pending: Vec::new() }
+    }
+
+    //Splits the received bytes into records, returns the complete readings
+    pub fn receive(&mut self, data : &[u8]) -> Vec<(Quantity, Reading)> {
+        self.pending.extend_from_slice(data);
+        let complete = self.pending.len() - self.pending.len() % RECORD_LEN;
+        let readings : Vec<(Quantity, Reading)> = self.pending[.. complete].chunks(RECORD_LEN).filter_map(|record| {
+            let value = blueprint::read_i32(&record[1 ..]) as f64 / 10.0;
+            Quantity::from_id(record[0]).map(|quantity| (quantity, Reading { timestamp: now_ms(), value: value }))
+        }).collect();
+        self.pending.drain(.. complete);
+        readings
+    }
+
+    //Returns whether the quantity went out of (true) or back into (false) its limits
+    pub fn record(&mut self, quantity : Quantity, reading : Reading, limits : &[SensorLimit]) -> Option<bool> {
+        {
+            let series = self.series.entry(quantity).or_insert(VecDeque::new());
+            if series.len() >= HISTORY_LEN {
+                series.pop_front();
+            }
+            series.push_back(reading);
+        }
+        let outside = limits.iter().any(|limit| limit.quantity == quantity && (reading.value < limit.min || reading.value > limit.max));
+        let was_outside = self.alarms.contains_key(&quantity);
+        if outside {
+            self.alarms.insert(quantity, reading.value);
+        }
+        else {
+            self.alarms.remove(&quantity);
+        }
+        if outside != was_outside { Some(outside) } else { None }
+    }
+
+    pub fn latest_of(&self, quantity : Quantity) -> Option<Reading> {
+        self.series.get(&quantity).and_then(|series| series.back().cloned())
+    }
+
+    pub fn latest(&self) -> BTreeMap<String, Reading> {
+        self.series.iter()
+            .filter_map(|(quantity, series)| series.back().map(|reading| (quantity.name().to_string(), *reading)))
+            .collect()
+    }
+}
@@ -0,0 +1,223 @@
+mod png;
+pub mod quality;
+
+use std::cmp;
+use std::usize;
+use std::collections::BTreeMap;
+use internals::blueprint::Command;
+
+//Colors for material ids in SVG/PNG exports
+const PALETTE : [(u8, u8, u8); 6] = [(31, 119, 180), (214, 39, 40), (44, 160, 44), (255, 127, 14), (148, 103, 189), (23, 190, 207)];
+const PNG_MAX_SIZE : i64 = 512;
+const MAX_VOXELS : usize = 4000000; //Voxel exports and quality checks of larger beds are refused, a larger voxel_size helps
+
+#[derive(RustcEncodable, Clone, Copy, Debug)]
+pub struct Dot {
+    pub x: i32,
+    pub y: i32,
+    pub matid: i32
+}
+
+#[derive(RustcEncodable, Clone, Copy, Debug)]
+pub struct Line {
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+    pub matid: i32
+}
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Layer {
+    pub z: i32,
+    pub dots: Vec<Dot>,
+    pub lines: Vec<Line>
+}
+
+//Model of everything a printhead acknowledged to have printed
+#[derive(Clone, Debug)]
+pub struct VirtualBed {
+    layers: BTreeMap<i32, Layer>,
+    current_z: i32
+}
+
+fn color(matid : i32) -> (u8, u8, u8) {
+    PALETTE[(matid.abs() as usize) % PALETTE.len()]
+}
+
+//Index of the grid cell a coordinate lies in (rounding towards negative infinity), size has to be at least 1
+pub fn cell(coord : i32, size : i32) -> i32 {
+    let (coord, size) = (coord as i64, size as i64);
+    (if coord >= 0 { coord / size } else { (coord - size + 1) / size }) as i32
+}
+
+//Number of cells line_cells returns
+pub fn line_length(x1 : i32, y1 : i32, x2 : i32, y2 : i32) -> usize {
+    cmp::max((x2 as i64 - x1 as i64).abs(), (y2 as i64 - y1 as i64).abs()) as usize + 1
+}
+
+//Grid cells covered by a line (Bresenham)
+pub fn line_cells(x1 : i32, y1 : i32, x2 : i32, y2 : i32) -> Vec<(i32, i32)> {
+    let (x1, y1, x2, y2) = (x1 as i64, y1 as i64, x2 as i64, y2 as i64); //Differences of extreme coordinates overflow i32
+    let (dx, dy) = ((x2 - x1).abs(), -(y2 - y1).abs());
+    let (sx, sy) = (if x1 < x2 { 1 } else { -1 }, if y1 < y2 { 1 } else { -1 });
+    let (mut x, mut y, mut err) = (x1, y1, dx + dy);
+    let mut cells = Vec::new();
+    loop {
+        cells.push((x as i32, y as i32));
+        if x == x2 && y == y2 {
+            return cells;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+impl Layer {
+    //Cells (x, y, matid) of this layer at the given grid size, Err if there would be more than limit
+    fn cells(&self, size : i32, limit : usize) -> Result<Vec<(i32, i32, i32)>, String> {
+        let too_many = || format!("more than {} voxels at voxel size {}", limit, size);
+        if self.dots.len() > limit {
+            return Err(too_many());
+        }
+        let mut cells = Vec::new();
+        for dot in self.dots.iter() {
+            cells.push((cell(dot.x, size), cell(dot.y, size), dot.matid));
+        }
+        for line in self.lines.iter() {
+            let (x1, y1, x2, y2) = (cell(line.x1, size), cell(line.y1, size), cell(line.x2, size), cell(line.y2, size));
+            if line_length(x1, y1, x2, y2) > limit - cells.len() {
+                return Err(too_many());
+            }
+            for (x, y) in line_cells(x1, y1, x2, y2) {
+                cells.push((x, y, line.matid));
+            }
+        }
+        Ok(cells)
+    }
+
+    //(min_x, min_y, max_x, max_y) of everything in the layer
+    fn bounds(&self) -> (i32, i32, i32, i32) {
+        let mut xs : Vec<i32> = self.dots.iter().map(|d| d.x).collect();
+        let mut ys : Vec<i32> = self.dots.iter().map(|d| d.y).collect();
+        for line in self.lines.iter() {
+            xs.push(line.x1); xs.push(line.x2);
+            ys.push(line.y1); ys.push(line.y2);
+        }
+        (*xs.iter().min().unwrap_or(&0), *ys.iter().min().unwrap_or(&0),
+         *xs.iter().max().unwrap_or(&0), *ys.iter().max().unwrap_or(&0))
+    }
+}
+
+impl VirtualBed {
+    pub fn new() -> VirtualBed {
+        VirtualBed {
+            layers: BTreeMap::new(),
+            current_z: 0
+        }
+    }
+
+    //Has to be called for every command the printhead acknowledged
+    pub fn record(&mut self, command : &Command, matid : i32) {
+        if let Command::Level { z, .. } = *command {
+            self.current_z = z;
+            return;
+        }
+        let z = self.current_z;
+        let layer = self.layers.entry(z).or_insert(Layer { z: z, dots: Vec::new(), lines: Vec::new() });
+        match *command {
+            Command::Dot { x, y } => {
+                layer.dots.push(Dot { x: x, y: y, matid: matid });
+            },
+            Command::Line { x1, y1, x2, y2 } => {
+                layer.lines.push(Line { x1: x1, y1: y1, x2: x2, y2: y2, matid: matid });
+            },
+            Command::Level { .. } | Command::Temperature { .. } => {}
+        }
+    }
+
+    pub fn layers(&self) -> Vec<&Layer> {
+        self.layers.values().collect()
+    }
+
+    pub fn layer_svg(&self, z : i32) -> Option<String> {
+        let layer = match self.layers.get(&z) {
+            Some(layer) => layer,
+            None => return None
+        };
+        let (min_x, min_y, max_x, max_y) = layer.bounds();
+        let (min_x, min_y, max_x, max_y) = (min_x as i64, min_y as i64, max_x as i64, max_y as i64);
+        let stroke = cmp::max(1, cmp::max(max_x - min_x, max_y - min_y) / 200);
+
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+            min_x - stroke, min_y - stroke, max_x - min_x + 2 * stroke, max_y - min_y + 2 * stroke);
+        for line in layer.lines.iter() {
+            let (r, g, b) = color(line.matid);
+            svg.push_str(&format!("  <line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"rgb({},{},{})\" stroke-width=\"{}\" stroke-linecap=\"round\"/>\n",
+                line.x1, line.y1, line.x2, line.y2, r, g, b, stroke));
+        }
+        for dot in layer.dots.iter() {
+            let (r, g, b) = color(dot.matid);
+            svg.push_str(&format!("  <circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"rgb({},{},{})\"/>\n",
+                dot.x, dot.y, stroke, r, g, b));
+        }
+        svg.push_str("</svg>\n");
+        Some(svg)
+    }
+
+    //Raster image of the layer, scaled down to at most PNG_MAX_SIZE pixels per side
+    pub fn layer_png(&self, z : i32) -> Option<Vec<u8>> {
+        let layer = match self.layers.get(&z) {
+            Some(layer) => layer,
+            None => return None
+        };
+        let (min_x, min_y, max_x, max_y) = layer.bounds();
+        let extent = cmp::max(max_x as i64 - min_x as i64, max_y as i64 - min_y as i64) + 1;
+        let scale = ((extent + PNG_MAX_SIZE - 1) / PNG_MAX_SIZE) as i32;
+        let (origin_x, origin_y) = (cell(min_x, scale), cell(min_y, scale));
+        let width = (cell(max_x, scale) - origin_x + 1) as u32;
+        let height = (cell(max_y, scale) - origin_y + 1) as u32;
+
+        let mut rgb = vec![255; (width * height * 3) as usize];
+        //Every line is at most PNG_MAX_SIZE cells long at this scale
+        for (x, y, matid) in layer.cells(scale, usize::MAX).unwrap() {
+            let pos = (((y - origin_y) as u32 * width + (x - origin_x) as u32) * 3) as usize;
+            let (r, g, b) = color(matid);
+            rgb[pos] = r;
+            rgb[pos + 1] = g;
+            rgb[pos + 2] = b;
+        }
+        Some(png::encode_rgb(width, height, &rgb))
+    }
+
+    //Set voxels (x, y, z, matid) at the given voxel size, later commands overwrite earlier ones.
+    //Err if the bed has more than MAX_VOXELS at this size.
+    pub fn voxels(&self, size : i32) -> Result<BTreeMap<(i32, i32, i32), i32>, String> {
+        let mut voxels = BTreeMap::new();
+        let mut budget = MAX_VOXELS;
+        for layer in self.layers.values() {
+            let cells = try!( layer.cells(size, budget) );
+            budget -= cells.len();
+            for (x, y, matid) in cells {
+                voxels.insert((x, y, cell(layer.z, size)), matid);
+            }
+        }
+        Ok(voxels)
+    }
+
+    //Text dump of all voxels, one "x y z matid" per line
+    pub fn voxel_dump(&self, size : i32) -> Result<String, String> {
+        let mut dump = format!("# voxel size {}\n", size);
+        for (&(x, y, z), matid) in try!(self.voxels(size)).iter() {
+            dump.push_str(&format!("{} {} {} {}\n", x, y, z, matid));
+        }
+        Ok(dump)
+    }
+}
@@ -0,0 +1,65 @@
+//Minimal PNG encoder for RGB images, uses uncompressed deflate blocks so no zlib is needed
+
+fn crc32(data : &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data : &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn push_u32(out : &mut Vec<u8>, value : u32) {
+    out.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+fn push_chunk(out : &mut Vec<u8>, kind : &[u8], data : &[u8]) {
+    push_u32(out, data.len() as u32);
+    let mut chunk = kind.to_vec();
+    chunk.extend_from_slice(data);
+    out.extend_from_slice(&chunk);
+    push_u32(out, crc32(&chunk));
+}
+
+//rgb has to contain width * height * 3 bytes, row by row
+pub fn encode_rgb(width : u32, height : u32, rgb : &[u8]) -> Vec<u8> {
+    assert!(rgb.len() == (width * height * 3) as usize, "PNG image data has wrong size!");
+
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks((width * 3) as usize) {
+        raw.push(0); //Filter type none
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(65535).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(if blocks.peek().is_none() { 1 } else { 0 }); //Final flag, stored block
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        zlib.extend_from_slice(block);
+    }
+    push_u32(&mut zlib, adler32(&raw));
+
+    let mut header = Vec::new();
+    push_u32(&mut header, width);
+    push_u32(&mut header, height);
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); //8bit RGB, no interlacing
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    push_chunk(&mut png, b"IHDR", &header);
+    push_chunk(&mut png, b"IDAT", &zlib);
+    push_chunk(&mut png, b"IEND", &[]);
+    png
+}
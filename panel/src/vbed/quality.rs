@@ -0,0 +1,70 @@
+use internals::blueprint;
+use internals::blueprint::Command;
+use internals::JobStats;
+use super::VirtualBed;
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct QualityReport {
+    pub job_id: usize,
+    pub passed: bool,
+    pub deviation: f64, //Wrong voxels relative to the expected ones
+    pub expected_voxels: usize,
+    pub missing_voxels: usize,
+    pub extra_voxels: usize,
+    pub wrong_material_voxels: usize,
+    pub skipped_commands: usize,
+    pub retries: u32,
+    pub substitutions: u32
+}
+
+//Builds the bed the blueprint should have produced, starting at the given offset
+fn expected_bed(commands : &[(usize, Command)], from_offset : u64) -> VirtualBed {
+    let mut bed = VirtualBed::new();
+    let mut matid = 0;
+    for &(offset, command) in commands.iter() {
+        if let Command::Level { matid: level_mat, .. } = command {
+            matid = level_mat as i32;
+            bed.record(&command, matid); //Layer changes before the offset still define the position
+        } else if offset as u64 >= from_offset {
+            bed.record(&command, matid);
+        }
+    }
+    bed
+}
+
+//Compares what the printhead acknowledged with the blueprint that was submitted
+pub fn inspect(job_id : usize, bp : &[u8], printed : &VirtualBed, stats : &JobStats,
+        voxel_size : i32, max_deviation : f64) -> Result<QualityReport, String> {
+    let commands = try!(blueprint::parse(bp));
+    let expected = try!( expected_bed(&commands, stats.from_offset).voxels(voxel_size) );
+    let actual = try!( printed.voxels(voxel_size) );
+
+    let mut missing = 0;
+    let mut wrong_material = 0;
+    for (voxel, matid) in expected.iter() {
+        match actual.get(voxel) {
+            None => missing += 1,
+            Some(actual_mat) if actual_mat != matid => wrong_material += 1,
+            _ => {}
+        }
+    }
+    let extra = actual.keys().filter(|voxel| !expected.contains_key(voxel)).count();
+
+    //Temperatures are set by the panel, the printhead never acknowledges them
+    let to_print = commands.iter().filter(|&&(offset, command)| offset as u64 >= stats.from_offset && !command.panel_only()).count();
+    let skipped = to_print - ::std::cmp::min(to_print, stats.acked_commands);
+
+    let deviation = (missing + extra + wrong_material) as f64 / ::std::cmp::max(expected.len(), 1) as f64;
+    Ok(QualityReport {
+        job_id: job_id,
+        passed: deviation <= max_deviation && skipped == 0,
+        deviation: deviation,
+        expected_voxels: expected.len(),
+        missing_voxels: missing,
+        extra_voxels: extra,
+        wrong_material_voxels: wrong_material,
+        skipped_commands: skipped,
+        retries: stats.retries,
+        substitutions: stats.substitutions
+    })
+}
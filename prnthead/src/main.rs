@@ -1,16 +1,26 @@
-extern crate rand;
-
 use std::io::prelude::*;
+use std::io::Cursor;
 use std::net::TcpStream;
-use rand::distributions::*;
 
-fn execute_cmd(stream : &mut TcpStream, cmd : u8) -> Result<(), &'static str> {
+mod pipeline;
+
+//Parameter bytes of a command, needed to receive it before it is executed
+fn param_len(cmd : u8) -> Option<usize> {
+    match cmd {
+        1 => Some(5),
+        2 => Some(8),
+        3 => Some(16),
+        4 => Some(2),
+        _ => None
+    }
+}
+
+fn execute_cmd(stream : &mut dyn Read, cmd : u8) -> Result<(), &'static str> {
     match cmd  {
         1 => { //Matlevel
             let mut parambuf = [0;5]; // 4, 1 byte Parameter
-            match stream.read_exact(&mut parambuf) {
-                Err(_) => return Err("Cannot receive matlevel parameters!"),
-                Ok(_) => {}
+            if stream.read_exact(&mut parambuf).is_err() {
+                return Err("Cannot receive matlevel parameters!");
             }
             let zcoor : i32 = (parambuf[0] as i32) | ((parambuf[1] as i32) << 8) |
                 ((parambuf[2] as i32) << 16) | ((parambuf[3] as i32) << 24);
@@ -19,9 +29,8 @@ fn execute_cmd(stream : &mut TcpStream, cmd : u8) -> Result<(), &'static str> {
         }
         2 => { //Single dot
             let mut parambuf = [0;8]; // 2 * 4 byte Parameter
-            match stream.read_exact(&mut parambuf) {
-                Err(_) => return Err("Cannot receive dot parameters!"),
-                Ok(_) => {}
+            if stream.read_exact(&mut parambuf).is_err() {
+                return Err("Cannot receive dot parameters!");
             }
             let xcoor : i32 = (parambuf[0] as i32) | ((parambuf[1] as i32) << 8) |
                 ((parambuf[2] as i32) << 16) | ((parambuf[3] as i32) << 24);
@@ -31,9 +40,8 @@ fn execute_cmd(stream : &mut TcpStream, cmd : u8) -> Result<(), &'static str> {
         }
         3 => { //line
             let mut parambuf = [0;16]; // 4 * 4 byte Parameter
-            match stream.read_exact(&mut parambuf) {
-                Err(_) => return Err("Cannot receive line parameters!"),
-                Ok(_) => {}
+            if stream.read_exact(&mut parambuf).is_err() {
+                return Err("Cannot receive line parameters!");
             }
             let startx : i32 = (parambuf[0] as i32) | ((parambuf[1] as i32) << 8) |
                 ((parambuf[2] as i32) << 16) | ((parambuf[3] as i32) << 24);
@@ -46,21 +54,58 @@ fn execute_cmd(stream : &mut TcpStream, cmd : u8) -> Result<(), &'static str> {
             print!("Print line from ({}, {}) to ({}, {})",startx,starty,endx,endy);
             std::thread::sleep(std::time::Duration::from_millis(3000));
         }
+        4 => { //Purge & wipe after a tool change
+            let mut parambuf = [0;2]; // 1, 1 byte Parameter
+            if stream.read_exact(&mut parambuf).is_err() {
+                return Err("Cannot receive purge parameters!");
+            }
+            print!("Purging {} units of material:{}",parambuf[1],parambuf[0]);
+            std::thread::sleep(std::time::Duration::from_millis(1000));
+        }
         _ => {
             return Err("Unknown blueprint command received!");
         }
     }
-    return Ok(());
+    Ok(())
 }
 
 fn main() {
 
     let mut stream = TcpStream::connect("127.0.0.1:18000").unwrap();
 
-    let mut rng = rand::thread_rng();
-    let rndrange = Range::new(1, 100);
+    //Stable serial, so the panel can recognize this printhead after reconnecting
+    let serial : u32 = std::env::args().nth(1).map(|arg| arg.parse().expect("Serial must be numeric!")).unwrap_or(1);
+    println!("Printhead serial: {}", serial);
+    //With a buffer depth the panel keeps that many commands in flight
+    let depth : Option<u8> = std::env::args().nth(2).map(|arg| arg.parse().expect("Buffer depth must be 1-255!"));
+    if depth == Some(0) {
+        panic!("Buffer depth must be 1-255!");
+    }
 
-    let _ = stream.write(&[1]); //Register as printhead
+    let _ = match depth {
+        Some(_) => stream.write(&[0, 1, 1, 1]), //Register as pipelined printhead: extended handshake, version 1, kind printhead, pipelined
+        None => stream.write(&[1]) //Register as printhead
+    };
+    let _ = stream.write(&[serial as u8, (serial >> 8) as u8, (serial >> 16) as u8, (serial >> 24) as u8]);
+    if let Some(depth) = depth {
+        let _ = stream.write(&[depth]);
+        //Commands are received into a buffer while the last one is executed
+        pipeline::run(stream, param_len, |cmd, seq, params| {
+            print!("R #{}: ", seq);
+            match execute_cmd(&mut Cursor::new(params), cmd) {
+                Err(msg) => {
+                    println!(" - Err: {}", msg);
+                    false
+                },
+                Ok(_) => {
+                    println!(" - Done");
+                    true
+                }
+            }
+        });
+        println!("Connection closed, exiting");
+        return;
+    }
     loop {
         let mut cmd = [0];
         match stream.read_exact(&mut cmd) {
@@ -68,20 +113,25 @@ fn main() {
                 println!("Connection closed, exiting");
                 return;
             },
+            Ok(_) if cmd[0] == 0 => { //Heartbeat
+                stream.write_all(&[0]).unwrap();
+                continue;
+            },
+            Ok(_) if cmd[0] == 6 => { //Emergency stop, not answered
+                println!("EMERGENCY STOP");
+                continue;
+            },
             Ok(_) => {
                 print!("R: ");
             }
         };
-        match execute_cmd(&mut stream, cmd[0]) {
-            Err(msg) => {
-                println!(" - Err: {}", msg);
-                stream.write(&[255]).unwrap(); //Report failure
-                continue;
-            },
-            Ok(_) => {},
-        };
+        if let Err(msg) = execute_cmd(&mut stream, cmd[0]) {
+            println!(" - Err: {}", msg);
+            stream.write_all(&[255]).unwrap(); //Report failure
+            continue;
+        }
 
-        stream.write(&[1]).unwrap();
+        stream.write_all(&[1]).unwrap();
         println!(" - Done");
     }
 
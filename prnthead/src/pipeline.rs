@@ -0,0 +1,100 @@
+//Command buffer of pipelined printheads: commands are received while earlier ones are executed,
+//answers carry the sequence number of their command.
+//Shared by prnthead and the virtual printheads of the panel, so both behave the same.
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+
+const HEARTBEAT : u8 = 0;
+const STOP : u8 = 6;
+const FLUSH : u8 = 7;
+
+//Received and not yet executed
+enum Buffered {
+    Command(u8, u8, Vec<u8>), //Command id, sequence number and parameters
+    Heartbeat,
+    Flushed //Answered once everything before it is done
+}
+
+struct Buffer {
+    queue: VecDeque<Buffered>,
+    failed: bool //Commands are dropped until the panel flushes
+}
+
+//Returns once the connection is closed. param_len gives the parameter bytes of a command, None if it is unknown.
+//execute gets command id, sequence number and parameters and returns false if the command failed.
+pub fn run<F>(mut stream : TcpStream, param_len : fn(u8) -> Option<usize>, mut execute : F)
+        where F : FnMut(u8, u8, &[u8]) -> bool + Send + 'static {
+    let mut answers = match stream.try_clone() {
+        Ok(answers) => answers,
+        Err(_) => return
+    };
+    let buffer = Arc::new( (Mutex::new( Buffer { queue: VecDeque::new(), failed: false } ), Condvar::new()) );
+    let executor_buffer = buffer.clone();
+    thread::spawn( move || {
+        let (ref lock, ref ready) = *executor_buffer;
+        loop {
+            let next = {
+                let mut buffer = lock.lock().unwrap();
+                while buffer.queue.is_empty() {
+                    buffer = ready.wait(buffer).unwrap();
+                }
+                buffer.queue.pop_front().unwrap()
+            };
+            let answer = match next {
+                Buffered::Heartbeat => vec![HEARTBEAT],
+                Buffered::Flushed => vec![FLUSH],
+                Buffered::Command(cmd, seq, params) => {
+                    let ok = execute(cmd, seq, &params);
+                    if !ok {
+                        //Everything received before the next flush is dropped
+                        let mut guard = lock.lock().unwrap();
+                        let buffer = &mut *guard;
+                        let flushed_at = buffer.queue.iter().position(|buffered| matches!(*buffered, Buffered::Flushed)).unwrap_or(buffer.queue.len());
+                        buffer.failed = flushed_at == buffer.queue.len();
+                        let after_flush = buffer.queue.split_off(flushed_at);
+                        buffer.queue.retain(|buffered| !matches!(*buffered, Buffered::Command(..)));
+                        buffer.queue.extend(after_flush);
+                    }
+                    vec![if ok { 1 } else { 255 }, seq]
+                }
+            };
+            if answers.write_all(&answer).is_err() {
+                return;
+            }
+        }
+    } );
+
+    let (ref lock, ref ready) = *buffer;
+    loop {
+        let mut cmd = [0];
+        if stream.read_exact(&mut cmd).is_err() {
+            return;
+        }
+        let mut seq = [0];
+        let mut params = Vec::new();
+        if cmd[0] != HEARTBEAT && cmd[0] != STOP && cmd[0] != FLUSH {
+            match param_len(cmd[0]) {
+                Some(len) => params.resize(len, 0),
+                None => return //Parameters of unknown commands cannot be skipped, the stream is lost
+            }
+            if stream.read_exact(&mut seq).is_err() || stream.read_exact(&mut params).is_err() {
+                return;
+            }
+        }
+        let mut buffer = lock.lock().unwrap();
+        match cmd[0] {
+            HEARTBEAT => buffer.queue.push_front(Buffered::Heartbeat),
+            STOP | FLUSH => { //Emergency stop or flush, buffered commands are dropped
+                buffer.queue.retain(|buffered| matches!(*buffered, Buffered::Heartbeat));
+                buffer.queue.push_back(Buffered::Flushed);
+                buffer.failed = false;
+            },
+            _ if buffer.failed => continue,
+            commandid => buffer.queue.push_back(Buffered::Command(commandid, seq[0], params))
+        }
+        ready.notify_one();
+    }
+}
@@ -0,0 +1,80 @@
+extern crate rand;
+
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::io::stdin;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use rand::distributions::*;
+
+const INTERVAL_MS : u64 = 1000;
+
+//Quantity ids of the panel: bed temperature, chamber temperature, humidity
+const QUANTITIES : [(u8, &str); 3] = [(1, "bed"), (2, "chamber"), (3, "humidity")];
+
+//Record: quantity id, value in tenths as little endian i32
+fn send(stream : &Mutex<TcpStream>, quantity : u8, value : f64) -> bool {
+    let tenths = (value * 10.0).round() as i32;
+    let record = [quantity, tenths as u8, (tenths >> 8) as u8, (tenths >> 16) as u8, (tenths >> 24) as u8];
+    stream.lock().unwrap().write_all(&record).is_ok()
+}
+
+fn main() {
+    let stream = TcpStream::connect("127.0.0.1:18000").unwrap();
+    let serial : u32 = std::env::args().nth(1).map(|arg| arg.parse().expect("Serial must be numeric!")).unwrap_or(2000);
+    println!("Sensor serial: {}", serial);
+
+    let mut reader = stream.try_clone().unwrap();
+    let stream = Arc::new(Mutex::new(stream));
+    {
+        let mut stream = stream.lock().unwrap();
+        stream.write_all(&[0, 1, 2]).unwrap(); //Register as sensor: extended handshake, version 1, kind sensor
+        stream.write_all(&[serial as u8, (serial >> 8) as u8, (serial >> 16) as u8, (serial >> 24) as u8]).unwrap();
+    }
+
+    //Answer heartbeats with an empty record
+    let heartbeat_stream = stream.clone();
+    thread::spawn( move || {
+        let mut ping = [0];
+        while reader.read_exact(&mut ping).is_ok() {
+            if !send(&heartbeat_stream, 0, 0.0) {
+                break;
+            }
+        }
+        println!("Connection closed, exiting");
+        std::process::exit(0);
+    } );
+
+    //Base values can be changed on stdin, e.g. "chamber 40" to trigger an alarm
+    let base = Arc::new(Mutex::new([60.0, 25.0, 40.0]));
+    let input_base = base.clone();
+    thread::spawn( move || {
+        loop {
+            let mut input = String::new();
+            stdin().read_line(&mut input).unwrap();
+            let fields : Vec<&str> = input.split_whitespace().collect();
+            let index = QUANTITIES.iter().position(|&(_, name)| Some(&name) == fields.first());
+            match (index, fields.get(1).and_then(|value| value.parse::<f64>().ok())) {
+                (Some(index), Some(value)) => {
+                    input_base.lock().unwrap()[index] = value;
+                    println!("{} is now around {}", QUANTITIES[index].1, value);
+                },
+                _ => println!("Usage: bed|chamber|humidity <value>")
+            }
+        }
+    } );
+
+    let mut rng = rand::thread_rng();
+    let noise = Range::new(-0.5, 0.5);
+    loop {
+        let values = *base.lock().unwrap();
+        for (&(quantity, _), value) in QUANTITIES.iter().zip(values.iter()) {
+            if !send(&stream, quantity, value + noise.ind_sample(&mut rng)) {
+                println!("Connection closed, exiting");
+                return;
+            }
+        }
+        thread::sleep(Duration::from_millis(INTERVAL_MS));
+    }
+}